@@ -0,0 +1,395 @@
+use std::{borrow::Cow, io::Cursor};
+
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageError, ImageFormat, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+
+/// A file attached to a [Message](crate::Message), with the text that
+/// describes it to people who can't see it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+	pub name: String,
+	/// Alt text, read out in place of an image
+	#[serde(default)]
+	pub alt: Option<String>,
+	/// A caption shown to everyone, under the attachment
+	#[serde(default)]
+	pub caption: Option<String>,
+}
+
+/// Attachments with these extensions are images, and get thumbnails.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+
+/// The directory in the attachment directory that thumbnails are kept in.
+pub const THUMBNAIL_DIRECTORY: &str = "thumbnails";
+
+/// Thumbnails are shrunk to fit in a square this many pixels wide. Images that
+/// already fit keep their size.
+pub const THUMBNAIL_SIZE: u32 = 800;
+
+/// Whether an attachment is an image, going by its extension.
+pub fn is_image(name: &str) -> bool {
+	extension(name)
+		.map(|ext| IMAGE_EXTENSIONS.contains(&ext.as_str()))
+		.unwrap_or(false)
+}
+
+/// The name of an image attachment's thumbnail. JPEGs keep their name, and
+/// everything else becomes a PNG with `.png` on the end.
+pub fn thumbnail_name(name: &str) -> String {
+	if is_jpeg(name) {
+		name.to_owned()
+	} else {
+		format!("{}.png", name)
+	}
+}
+
+/// Make a thumbnail of an image attachment, in the format [thumbnail_name]
+/// says. Being drawn fresh, it has none of the original's metadata, so it's
+/// turned the way the original's EXIF orientation said to show it.
+pub fn make_thumbnail(name: &str, data: &[u8]) -> Result<Vec<u8>, ImageError> {
+	let orientation = strip_any(data)
+		.and_then(|(_, exif)| exif)
+		.and_then(exif_orientation)
+		.unwrap_or(1);
+
+	let image = orient(image::load_from_memory(data)?, orientation);
+	let image = if image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE {
+		image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+	} else {
+		image
+	};
+
+	let mut thumbnail = vec![];
+	if is_jpeg(name) {
+		let rgb = DynamicImage::ImageRgb8(image.into_rgb8());
+		rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut thumbnail, 85))?;
+	} else {
+		image.write_to(&mut Cursor::new(&mut thumbnail), ImageOutputFormat::Png)?;
+	}
+
+	Ok(thumbnail)
+}
+
+/// Remove the metadata that can say where a photo was taken, or by who, from
+/// a JPEG, PNG, WebP, or GIF. That's EXIF, XMP, and comments. The image itself
+/// is copied as it was, unless its EXIF said to show it rotated or flipped,
+/// then it's drawn again the right way up. Anything else, or anything we can't
+/// make sense of, is returned unchanged.
+///
+/// We can read WebP but not write it, so a rotated one loses its rotation.
+pub fn strip_metadata(data: &[u8]) -> Cow<'_, [u8]> {
+	let (stripped, exif) = match strip_any(data) {
+		Some(stripped) => stripped,
+		None => return Cow::Borrowed(data),
+	};
+
+	match exif.and_then(exif_orientation) {
+		Some(orientation) if orientation != 1 => match redraw_upright(data, orientation) {
+			Ok(upright) => Cow::Owned(upright),
+			Err(_) => Cow::Owned(stripped),
+		},
+		_ => Cow::Owned(stripped),
+	}
+}
+
+fn extension(name: &str) -> Option<String> {
+	name.rsplit_once('.')
+		.map(|(_, ext)| ext.to_ascii_lowercase())
+}
+
+fn is_jpeg(name: &str) -> bool {
+	matches!(extension(name).as_deref(), Some("jpg" | "jpeg"))
+}
+
+/// The image without its metadata, going by its magic number, and its EXIF
+/// if it had some.
+fn strip_any(data: &[u8]) -> Option<(Vec<u8>, Option<&[u8]>)> {
+	if data.starts_with(&[0xFF, 0xD8]) {
+		strip_jpeg(data)
+	} else if data.starts_with(PNG_SIGNATURE) {
+		strip_png(data)
+	} else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+		strip_webp(data)
+	} else if data.starts_with(b"GIF8") {
+		strip_gif(data).map(|gif| (gif, None))
+	} else {
+		None
+	}
+}
+
+/// Decode an image, turn it the way its EXIF orientation says, and encode it
+/// in the format it was in. Nothing of the original's metadata comes along.
+fn redraw_upright(data: &[u8], orientation: u16) -> Result<Vec<u8>, ImageError> {
+	let format = image::guess_format(data)?;
+	let image = orient(
+		image::load_from_memory_with_format(data, format)?,
+		orientation,
+	);
+
+	let output = match format {
+		ImageFormat::Jpeg => ImageOutputFormat::Jpeg(90),
+		format => ImageOutputFormat::from(format),
+	};
+
+	let mut redrawn = vec![];
+	image.write_to(&mut Cursor::new(&mut redrawn), output)?;
+	Ok(redrawn)
+}
+
+/// Turn an image the way an EXIF orientation says to show it.
+fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+	match orientation {
+		2 => image.fliph(),
+		3 => image.rotate180(),
+		4 => image.flipv(),
+		5 => image.rotate90().fliph(),
+		6 => image.rotate90(),
+		7 => image.rotate270().fliph(),
+		8 => image.rotate270(),
+		_ => image,
+	}
+}
+
+/// The orientation tag from EXIF, which is a TIFF header and its first
+/// directory of tags.
+fn exif_orientation(exif: &[u8]) -> Option<u16> {
+	// WebPs sometimes keep the JPEG segment's prefix
+	let exif = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+
+	let big_endian = match exif.get(..2)? {
+		b"MM" => true,
+		b"II" => false,
+		_ => return None,
+	};
+	let u16_at = |at: usize| {
+		let bytes = [*exif.get(at)?, *exif.get(at + 1)?];
+		Some(if big_endian {
+			u16::from_be_bytes(bytes)
+		} else {
+			u16::from_le_bytes(bytes)
+		})
+	};
+	let u32_at = |at: usize| {
+		let bytes: [u8; 4] = exif.get(at..at + 4)?.try_into().ok()?;
+		Some(if big_endian {
+			u32::from_be_bytes(bytes)
+		} else {
+			u32::from_le_bytes(bytes)
+		})
+	};
+
+	// Each tag is 12 bytes: its id, type, count, and then the value
+	let directory = u32_at(4)? as usize;
+	(0..u16_at(directory)? as usize)
+		.map(|n| directory + 2 + n * 12)
+		.find(|&tag| u16_at(tag) == Some(EXIF_ORIENTATION))
+		.and_then(|tag| u16_at(tag + 8))
+}
+
+const EXIF_ORIENTATION: u16 = 0x0112;
+
+/// What XMP in a JPEG APP1 segment starts with, for the packet and for the
+/// extension to it that holds the rest when it's too big for one segment.
+const JPEG_XMP: &[&[u8]] = &[
+	b"http://ns.adobe.com/xap/1.0/\0",
+	b"http://ns.adobe.com/xmp/extension/\0",
+];
+
+/// Copy a JPEG without its APP1 segments that hold EXIF or XMP, and without
+/// comments. Everything from the start of the scan on is image data and copied
+/// as-is.
+fn strip_jpeg(data: &[u8]) -> Option<(Vec<u8>, Option<&[u8]>)> {
+	let mut stripped = Vec::with_capacity(data.len());
+	stripped.extend_from_slice(&data[..2]);
+	let mut exif = None;
+
+	let mut idx = 2;
+	loop {
+		if *data.get(idx)? != 0xFF {
+			return None;
+		}
+
+		let marker = *data.get(idx + 1)?;
+		match marker {
+			// Padding before a marker
+			0xFF => {
+				idx += 1;
+				continue;
+			}
+			// Start of scan, the rest is the image
+			0xDA => {
+				stripped.extend_from_slice(&data[idx..]);
+				return Some((stripped, exif));
+			}
+			// End of image, or markers that don't have a length
+			0xD9 | 0x01 | 0xD0..=0xD7 => {
+				stripped.extend_from_slice(&data[idx..idx + 2]);
+				if marker == 0xD9 {
+					return Some((stripped, exif));
+				}
+				idx += 2;
+				continue;
+			}
+			_ => (),
+		}
+
+		let length = u16::from_be_bytes([*data.get(idx + 2)?, *data.get(idx + 3)?]) as usize;
+		if length < 2 {
+			return None;
+		}
+		let segment = data.get(idx..idx + 2 + length)?;
+		let payload = &segment[4..];
+
+		let metadata = match marker {
+			0xE1 if payload.starts_with(b"Exif\0\0") => {
+				exif = exif.or(Some(&payload[6..]));
+				true
+			}
+			0xE1 => JPEG_XMP.iter().any(|xmp| payload.starts_with(xmp)),
+			// Comment
+			0xFE => true,
+			_ => false,
+		};
+		if !metadata {
+			stripped.extend_from_slice(segment);
+		}
+
+		idx += segment.len();
+	}
+}
+
+const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Copy a PNG without its eXIf chunks, or its text chunks, which is where XMP
+/// and comments go.
+fn strip_png(data: &[u8]) -> Option<(Vec<u8>, Option<&[u8]>)> {
+	let mut stripped = Vec::with_capacity(data.len());
+	stripped.extend_from_slice(PNG_SIGNATURE);
+	let mut exif = None;
+
+	let mut idx = PNG_SIGNATURE.len();
+	while idx < data.len() {
+		let length = u32::from_be_bytes(data.get(idx..idx + 4)?.try_into().unwrap()) as usize;
+		// Length, type, data, and CRC
+		let chunk = data.get(idx..idx + 12 + length)?;
+
+		match &chunk[4..8] {
+			b"eXIf" => exif = exif.or(Some(&chunk[8..8 + length])),
+			b"tEXt" | b"zTXt" | b"iTXt" => (),
+			_ => stripped.extend_from_slice(chunk),
+		}
+
+		idx += chunk.len();
+	}
+
+	Some((stripped, exif))
+}
+
+/// Flags in a WebP's VP8X chunk saying it has EXIF and XMP chunks.
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Copy a WebP without its EXIF and XMP chunks, and clear the flags saying
+/// they're there.
+fn strip_webp(data: &[u8]) -> Option<(Vec<u8>, Option<&[u8]>)> {
+	// RIFF, its length, and WEBP
+	let mut stripped = data.get(..12)?.to_vec();
+	let mut exif = None;
+
+	let mut idx = 12;
+	while idx < data.len() {
+		let length = u32::from_le_bytes(data.get(idx + 4..idx + 8)?.try_into().unwrap()) as usize;
+		// Type, length, and data, padded to an even length. Some leave the
+		// padding off the last chunk.
+		let end = idx + 8 + length;
+		let chunk = data.get(idx..(end + length % 2).min(data.len().max(end)))?;
+
+		match &chunk[..4] {
+			b"EXIF" => exif = exif.or(Some(&chunk[8..8 + length])),
+			b"XMP " => (),
+			b"VP8X" => {
+				let flags = stripped.len() + 8;
+				stripped.extend_from_slice(chunk);
+				*stripped.get_mut(flags)? &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+			}
+			_ => stripped.extend_from_slice(chunk),
+		}
+
+		idx += chunk.len();
+	}
+
+	// The length in the header counts everything after it
+	let length = u32::try_from(stripped.len() - 8).ok()?;
+	stripped[4..8].copy_from_slice(&length.to_le_bytes());
+
+	Some((stripped, exif))
+}
+
+/// Copy a GIF without its comment extensions and XMP application extensions.
+/// GIFs don't have EXIF.
+fn strip_gif(data: &[u8]) -> Option<Vec<u8>> {
+	// The header and logical screen descriptor, then the global color table
+	let start = 13 + color_table_length(*data.get(10)?);
+	let mut stripped = data.get(..start)?.to_vec();
+
+	let mut idx = start;
+	loop {
+		match *data.get(idx)? {
+			// Trailer
+			0x3B => {
+				stripped.push(0x3B);
+				return Some(stripped);
+			}
+			// Image descriptor, a local color table, the LZW code size, and the
+			// image data
+			0x2C => {
+				let table = color_table_length(*data.get(idx + 9)?);
+				let end = sub_blocks_end(data, idx + 10 + table + 1)?;
+				stripped.extend_from_slice(&data[idx..end]);
+				idx = end;
+			}
+			// Extension, with its label and then its data
+			0x21 => {
+				let end = sub_blocks_end(data, idx + 2)?;
+				let extension = &data[idx..end];
+
+				let metadata = match extension[1] {
+					// Comment
+					0xFE => true,
+					// Application, named in its first block
+					0xFF => extension.get(3..14) == Some(&b"XMP DataXMP"[..]),
+					_ => false,
+				};
+				if !metadata {
+					stripped.extend_from_slice(extension);
+				}
+
+				idx = end;
+			}
+			_ => return None,
+		}
+	}
+}
+
+/// How many bytes the color table after a GIF descriptor takes, going by the
+/// descriptor's packed field.
+fn color_table_length(packed: u8) -> usize {
+	if packed & 0x80 == 0 {
+		0
+	} else {
+		3 << ((packed & 0x07) + 1)
+	}
+}
+
+/// Where the GIF sub-blocks starting at `idx` end, after the empty one that
+/// closes them.
+fn sub_blocks_end(data: &[u8], mut idx: usize) -> Option<usize> {
+	loop {
+		let size = *data.get(idx)? as usize;
+		idx += 1 + size;
+
+		if size == 0 {
+			return Some(idx);
+		}
+	}
+}
@@ -1,16 +1,24 @@
 use std::{
 	net::{IpAddr, Ipv4Addr},
 	path::PathBuf,
+	time::Duration,
 };
 
 use confindent::Confindent;
 use getopts::Options;
+use oodles::Renderer;
+
+use crate::throttle::LoginLimits;
 
 pub struct Config {
 	pub address: IpAddr,
 	pub port: u16,
 	pub credential_file: PathBuf,
 	pub data_directory: PathBuf,
+	/// How messages are rendered in oodles that don't choose for themselves
+	pub renderer: Renderer,
+	/// How many failed logins are allowed before having to wait
+	pub login_limits: LoginLimits,
 }
 
 impl Config {
@@ -34,6 +42,7 @@ impl Config {
 			opts.optopt("a", "address", "What IP address to serve on\nConfig Key: Address\nDefault: 127.0.0.1", "IPADDR");
 			opts.optopt("", "credentials", "File to find login information\nConfig Key: CredentialFile", "FILE");
 			opts.optopt("d", "data-directory", "Where data is to be kept\nConfig Key: DataDirectory", "PATH");
+			opts.optopt("", "renderer", "How to render messages, plain or markdown\nConfig Key: Renderer\nDefault: plain", "RENDERER");
 			opts
 		};
 
@@ -78,11 +87,39 @@ impl Config {
 			.map(|s| s.parse().expect("Failed to parse DataDirectory path"))
 			.expect("No Data Directory specified");
 
+		let renderer: Renderer = cli_or_conf("renderer", "Renderer")
+			.map(|s| s.parse().expect("Failed to parse Renderer"))
+			.unwrap_or_default();
+
+		// These are only in the config file, there's no need to change them on
+		// the command line.
+		let conf_number = |key: &str| -> Option<u32> {
+			conf.child_value(key).map(|s| {
+				s.parse()
+					.unwrap_or_else(|_| panic!("Failed to parse {}", key))
+			})
+		};
+
+		let defaults = LoginLimits::default();
+		let login_limits = LoginLimits {
+			free_attempts: conf_number("LoginFreeAttempts").unwrap_or(defaults.free_attempts),
+			backoff: conf_number("LoginBackoffSeconds")
+				.map(|secs| Duration::from_secs(secs.into()))
+				.unwrap_or(defaults.backoff),
+			lockout_attempts: conf_number("LoginLockoutAttempts")
+				.unwrap_or(defaults.lockout_attempts),
+			lockout: conf_number("LoginLockoutSeconds")
+				.map(|secs| Duration::from_secs(secs.into()))
+				.unwrap_or(defaults.lockout),
+		};
+
 		Self {
 			address,
 			port,
 			credential_file,
 			data_directory,
+			renderer,
+			login_limits,
 		}
 	}
 }
@@ -1,81 +1,1044 @@
 use std::{
+	borrow::Cow,
 	collections::HashMap,
+	ffi::OsStr,
+	fmt,
+	net::IpAddr,
 	path::{Path, PathBuf},
-	time::Duration,
+	time::{Duration, Instant},
 };
 
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use hyper::{header, Request};
-use oodles::{Message, Oodle};
+use image::ImageError;
+use oodles::{
+	filename_from_title, is_image, make_thumbnail, oodle_filename, sanitize_filename, slugify,
+	strip_metadata, thumbnail_name, valid_filename, valid_slug, Message, Oodle, OodleEditError,
+	OodleReadError, OodleSaveError, ParseMode, Renderer, Revision, SearchIndex, TagIndex,
+	REVISIONS_EXTENSION, THUMBNAIL_DIRECTORY,
+};
 use rand::{rngs::OsRng, Rng};
 use time::OffsetDateTime;
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::{
+	io::AsyncWriteExt,
+	sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{
+	throttle::{LoginLimits, LoginThrottle},
+	totp,
+};
 
 #[derive(Debug)]
 pub struct Oodles {
 	oodle_directory: PathBuf,
+	trash_directory: PathBuf,
+	attachment_directory: PathBuf,
+	/// Used for oodles that don't choose a renderer themselves
+	default_renderer: Renderer,
 	data: Vec<Oodle>,
+	/// Every oodle's title and messages, kept up to date with [Oodles::reindex]
+	index: SearchIndex,
+	/// The tags of every oodle and message, kept up to date like `index`
+	tag_index: TagIndex,
 }
 
 impl Oodles {
-	pub fn new<P: Into<PathBuf>>(data_dir: P) -> Self {
-		let mut oodle_directory = data_dir.into();
-		oodle_directory.push("oodles");
+	pub fn new<P: Into<PathBuf>>(data_dir: P, default_renderer: Renderer) -> Self {
+		let data_dir = data_dir.into();
 
 		Self {
-			oodle_directory,
+			oodle_directory: data_dir.join("oodles"),
+			trash_directory: data_dir.join("trash"),
+			attachment_directory: data_dir.join("attachments"),
+			default_renderer,
 			data: vec![],
+			index: SearchIndex::new(),
+			tag_index: TagIndex::new(),
 		}
 	}
 
+	/// Whether a file in the oodle or trash directory is an oodle, and not
+	/// something that lives beside them.
+	fn is_oodle_file(path: &Path) -> bool {
+		// Skip hidden files, like the temp file of an interrupted save
+		let hidden = path
+			.file_name()
+			.map(|name| name.to_string_lossy().starts_with('.'))
+			.unwrap_or(true);
+		let revisions = path
+			.extension()
+			.map(|ext| ext == REVISIONS_EXTENSION)
+			.unwrap_or(false);
+
+		!hidden && !revisions && path.is_file()
+	}
+
 	pub async fn load_oodles(&mut self) {
-		for entry in std::fs::read_dir(&self.oodle_directory).unwrap() {
-			let oodle = Oodle::read(entry.unwrap().path()).await.unwrap();
-			self.data.push(oodle);
+		// Sorted, so oodles without a slug are given the same one every time
+		let mut paths: Vec<PathBuf> = std::fs::read_dir(&self.oodle_directory)
+			.unwrap()
+			.map(|entry| entry.unwrap().path())
+			.filter(|path| Self::is_oodle_file(path))
+			.collect();
+		paths.sort();
+
+		for path in paths {
+			match Oodle::read_with(&path, ParseMode::Lenient).await {
+				Ok((oodle, warnings)) => {
+					for warning in warnings {
+						eprintln!("{}: {}", path.to_string_lossy(), warning);
+					}
+
+					self.data.push(oodle)
+				}
+				Err(e) => eprintln!("Skipping {}: {}", path.to_string_lossy(), e),
+			}
+		}
+
+		// Oodles from before slugs get one made from their title. It's written
+		// with the next change to the oodle, loading never rewrites a file.
+		for idx in 0..self.data.len() {
+			if self.data[idx].slug().is_none() {
+				let slug = self.unique_slug(&self.data[idx].name);
+				self.data[idx].set_slug(slug);
+			}
+		}
+
+		for oodle in &self.data {
+			self.index.index_oodle(oodle);
+			self.tag_index.index_oodle(oodle);
 		}
 	}
 
-	pub async fn new_oodle<T: Into<String>, F: Into<String>>(
+	/// Create an oodle and save it. If no filename is given, one is made from
+	/// the title. Returns the filename the oodle was saved as.
+	pub async fn new_oodle<T: Into<String>>(
 		&mut self,
 		title: T,
-		filename: F,
+		filename: Option<&str>,
 		message: Message,
-	) {
-		let mut oodle_path = self.oodle_directory.clone();
-		oodle_path.push(filename.into());
+	) -> Result<String, OodlesError> {
+		let title = title.into();
+		Self::check_title(&title)?;
 
-		let oodle = Oodle::new(title, oodle_path, message);
-		oodle.save().await.unwrap();
+		let filename = match filename {
+			Some(filename) => self.check_new_filename(filename)?,
+			None => filename_from_title(&title, |f| self.filename_taken(f)),
+		};
+
+		let slug = self.unique_slug(&title);
+		let mut oodle = Oodle::new(title, self.oodle_directory.join(&filename), message);
+		oodle.set_slug(slug);
+		oodle.save().await?;
 
 		self.data.push(oodle);
+		self.reindex(&filename);
+		Ok(filename)
+	}
+
+	/// The title, slug, tags, and date of every oodle that isn't archived. If
+	/// a tag is given, only the oodles with it are included.
+	pub async fn oodle_metedata(
+		&self,
+		tag: Option<&str>,
+	) -> Vec<(String, String, Vec<String>, Option<OffsetDateTime>)> {
+		self.data
+			.iter()
+			.filter(|oodle| !oodle.is_archived())
+			.map(|oodle| (oodle, oodle.tags()))
+			.filter(|(_, tags)| tag.map(|tag| tags.iter().any(|t| t == tag)).unwrap_or(true))
+			.map(|(oodle, tags)| {
+				let slug = oodle.slug().unwrap_or_default().to_owned();
+				(oodle.name.to_owned(), slug, tags, oodle.date())
+			})
+			.collect()
 	}
 
-	pub async fn oodle_metedata(&self) -> Vec<(String, Option<OffsetDateTime>)> {
+	/// The title, slug, and filename of every archived oodle.
+	pub fn archived(&self) -> Vec<(String, String, String)> {
 		self.data
 			.iter()
-			.map(|oodle| (oodle.name.to_owned(), oodle.date()))
+			.filter(|oodle| oodle.is_archived())
+			.map(|oodle| {
+				let slug = oodle.slug().unwrap_or_default().to_owned();
+				let file = oodle.file.file_name().unwrap().to_string_lossy();
+				(oodle.name.to_owned(), slug, file.into_owned())
+			})
 			.collect()
 	}
 
+	/// The filename of every oodle in the trash.
+	pub fn trashed(&self) -> Vec<String> {
+		let entries = match std::fs::read_dir(&self.trash_directory) {
+			Ok(entries) => entries,
+			Err(_) => return vec![],
+		};
+
+		let mut files: Vec<String> = entries
+			.filter_map(|entry| entry.ok().map(|e| e.path()))
+			.filter(|path| Self::is_oodle_file(path))
+			.map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+			.collect();
+		files.sort();
+		files
+	}
+
+	/// How messages are rendered in oodles that don't choose for themselves.
+	pub fn default_renderer(&self) -> Renderer {
+		self.default_renderer
+	}
+
+	/// Choose how an oodle's messages are rendered, or go back to the default
+	/// if `renderer` is None.
+	pub async fn set_renderer(
+		&mut self,
+		filename: &str,
+		renderer: Option<Renderer>,
+	) -> Result<(), OodlesError> {
+		let idx = self.position_by_file(filename)?;
+		let oodle = &mut self.data[idx];
+		let old = oodle.headers.clone();
+
+		oodle.set_renderer(renderer);
+		if let Err(e) = oodle.save().await {
+			oodle.headers = old;
+			return Err(e.into());
+		}
+
+		Ok(())
+	}
+
+	/// Search the titles and messages of every oodle that isn't archived, see
+	/// [SearchIndex::search]. Each oodle is returned with the message that
+	/// matched, or None if it was the title, best match first.
+	pub fn search(&self, query: &str) -> Vec<(&Oodle, Option<&Message>)> {
+		self.index
+			.search(query)
+			.into_iter()
+			.filter_map(|hit| {
+				let oodle = self
+					.oodle_by_file(&hit.document.filename)
+					.filter(|oodle| !oodle.is_archived())?;
+				match hit.document.message {
+					None => Some((oodle, None)),
+					Some(id) => Some((oodle, Some(oodle.message(id)?))),
+				}
+			})
+			.collect()
+	}
+
+	/// Every tag on an oodle or message, in order, and how many have it.
+	/// Archived oodles and their messages aren't counted.
+	pub fn tags(&self) -> Vec<(String, usize)> {
+		self.tag_index
+			.tags()
+			.map(|(tag, _)| (tag.to_owned(), self.tagged(tag).len()))
+			.filter(|(_, count)| *count > 0)
+			.collect()
+	}
+
+	/// The oodles and messages with a tag, leaving out archived oodles. Each
+	/// oodle is returned with the message that has it, or None if the oodle
+	/// itself does.
+	pub fn tagged(&self, tag: &str) -> Vec<(&Oodle, Option<&Message>)> {
+		self.tag_index
+			.tagged(tag)
+			.filter_map(|document| {
+				let oodle = self
+					.oodle_by_file(&document.filename)
+					.filter(|oodle| !oodle.is_archived())?;
+				match document.message {
+					None => Some((oodle, None)),
+					Some(id) => Some((oodle, Some(oodle.message(id)?))),
+				}
+			})
+			.collect()
+	}
+
+	/// Replace an oodle's tags. See [Oodle::set_tags].
+	pub async fn set_tags(&mut self, filename: &str, tags: &[String]) -> Result<(), OodlesError> {
+		let idx = self.position_by_file(filename)?;
+		let oodle = &mut self.data[idx];
+		let old = oodle.headers.clone();
+
+		oodle.set_tags(tags);
+		if let Err(e) = oodle.save().await {
+			oodle.headers = old;
+			return Err(e.into());
+		}
+
+		self.reindex(filename);
+		Ok(())
+	}
+
+	/// Bring the search and tag indexes up to date with an oodle after it's
+	/// changed. If there's no oodle with that filename any more, it's taken
+	/// out.
+	pub fn reindex(&mut self, filename: &str) {
+		match self.position_by_file(filename) {
+			Ok(idx) => {
+				self.index.index_oodle(&self.data[idx]);
+				self.tag_index.index_oodle(&self.data[idx]);
+			}
+			Err(_) => {
+				self.index.remove_oodle(filename);
+				self.tag_index.remove_oodle(filename);
+			}
+		}
+	}
+
+	pub fn oodle_by_slug(&self, slug: &str) -> Option<&Oodle> {
+		self.data.iter().find(|o| o.slug() == Some(slug))
+	}
+
+	/// The oodle that used to have this slug, so links to it can be sent on.
+	pub fn oodle_by_old_slug(&self, slug: &str) -> Option<&Oodle> {
+		self.data
+			.iter()
+			.find(|o| o.old_slugs().any(|old| old == slug))
+	}
+
+	/// Find an oodle by its title. Links to oodles used to have the title in
+	/// them, so this is kept to send those to the slug.
 	pub fn get_oodle_by_name<S: AsRef<str>>(&self, name: S) -> Option<&Oodle> {
 		self.data
 			.iter()
 			.find(|&o| o.name.to_lowercase() == name.as_ref().to_lowercase())
 	}
 
-	pub fn oodle_by_file<P: Into<PathBuf>>(&self, file: P) -> Option<&Oodle> {
-		let file = file.into();
+	pub fn oodle_by_file<S: AsRef<str>>(&self, file: S) -> Option<&Oodle> {
+		let idx = self.position_by_file(file).ok()?;
+		Some(&self.data[idx])
+	}
+
+	pub fn oodle_by_file_mut<S: AsRef<str>>(&mut self, file: S) -> Option<&mut Oodle> {
+		let idx = self.position_by_file(file).ok()?;
+		Some(&mut self.data[idx])
+	}
+
+	/// Change an oodle's title, slug, filename, or any of them. The oodle's
+	/// revisions are renamed along with it, and its old slug keeps working.
+	/// Returns the oodle's filename afterwards.
+	///
+	/// If saving the new title or slug fails after the file was moved, it's
+	/// moved back, so on an error the oodle is still where it was.
+	pub async fn rename_oodle(
+		&mut self,
+		filename: &str,
+		title: Option<String>,
+		slug: Option<&str>,
+		new_filename: Option<&str>,
+	) -> Result<String, OodlesError> {
+		let idx = self.position_by_file(filename)?;
+		let old_filename = filename;
+		let mut filename = filename.to_owned();
+
+		if let Some(title) = &title {
+			Self::check_title(title)?;
+		}
+
+		if let Some(slug) = slug {
+			if !valid_slug(slug) {
+				return Err(OodlesError::BadSlug(slug.to_owned()));
+			}
+
+			let taken = self
+				.data
+				.iter()
+				.enumerate()
+				.any(|(other, o)| other != idx && o.has_slug(slug));
+			if taken {
+				return Err(OodlesError::SlugTaken(slug.to_owned()));
+			}
+		}
+
+		let mut moved_from = None;
+		if let Some(new_filename) = new_filename {
+			let new_filename = oodle_filename(new_filename)
+				.ok_or_else(|| OodlesError::BadFilename(new_filename.to_owned()))?;
+
+			if new_filename != filename {
+				let new_filename = self.check_new_filename(&new_filename)?;
+				let to = self.oodle_directory.join(&new_filename);
+
+				let from = self.data[idx].file.clone();
+				Self::move_oodle(&mut self.data[idx], to).await?;
+				moved_from = Some(from);
+				filename = new_filename;
+			}
+		}
+
+		let retitled = title.is_some() || slug.is_some();
+		if retitled {
+			let oodle = &mut self.data[idx];
+			let old = (oodle.name.clone(), oodle.headers.clone());
+
+			if let Some(title) = title {
+				oodle.name = title;
+			}
+			if let Some(slug) = slug.filter(|&s| oodle.slug() != Some(s)) {
+				oodle.set_slug(slug);
+			}
+
+			if let Err(e) = oodle.save().await {
+				oodle.name = old.0;
+				oodle.headers = old.1;
+
+				// If this fails too there's not much else we can do
+				if let Some(from) = moved_from {
+					if let Err(back) = Self::move_oodle(oodle, from).await {
+						eprintln!(
+							"Failed to move {:?} back after renaming failed: {}",
+							oodle.file, back
+						);
+					}
+				}
+				return Err(e.into());
+			}
+		}
+
+		if moved_from.is_some() {
+			self.reindex(old_filename);
+		}
+		if moved_from.is_some() || retitled {
+			self.reindex(&filename);
+		}
+
+		Ok(filename)
+	}
+
+	/// Move an oodle into the trash, out of the index and away from its URL.
+	/// It can be brought back with [Oodles::restore_oodle].
+	pub async fn trash_oodle(&mut self, filename: &str) -> Result<(), OodlesError> {
+		if !valid_filename(filename) {
+			return Err(OodlesError::BadFilename(filename.to_owned()));
+		}
+
+		let idx = self.position_by_file(filename)?;
+
+		// Named for the oodle we found, not whatever we were asked for
+		let name = self.data[idx].file.file_name().unwrap_or_default();
+		let to = self.trash_directory.join(name);
+		if to.exists() {
+			return Err(OodlesError::AlreadyExists(filename.to_owned()));
+		}
+
+		Self::move_oodle(&mut self.data[idx], to).await?;
+		self.data.remove(idx);
+		self.reindex(filename);
+
+		Ok(())
+	}
+
+	/// Bring an oodle back out of the trash.
+	pub async fn restore_oodle(&mut self, filename: &str) -> Result<(), OodlesError> {
+		if !valid_filename(filename) {
+			return Err(OodlesError::BadFilename(filename.to_owned()));
+		}
+
+		let from = self.trash_directory.join(filename);
+		if !Self::is_oodle_file(&from) {
+			return Err(OodlesError::NoSuchOodle(filename.to_owned()));
+		} else if self.filename_taken(filename) {
+			return Err(OodlesError::AlreadyExists(filename.to_owned()));
+		}
+
+		let to = self.oodle_directory.join(filename);
+
+		let (mut oodle, _) = Oodle::read_with(&from, ParseMode::Lenient).await?;
+		Self::move_oodle(&mut oodle, to).await?;
+
+		// Something else may have taken the slug while this was in the trash
+		let slug_taken = match oodle.slug() {
+			Some(slug) => self.slug_taken(slug),
+			None => true,
+		};
+
+		if slug_taken {
+			oodle.set_slug(self.unique_slug(&oodle.name));
+
+			if let Err(e) = oodle.save().await {
+				eprintln!(
+					"Failed to save slug for {}: {}",
+					oodle.file.to_string_lossy(),
+					e
+				);
+			}
+		}
+
+		self.data.push(oodle);
+		self.reindex(filename);
+		Ok(())
+	}
+
+	/// Archive an oodle, or unarchive it if `when` is None. Archived oodles are
+	/// left out of the index but can still be visited.
+	pub async fn set_archived(
+		&mut self,
+		filename: &str,
+		when: Option<OffsetDateTime>,
+	) -> Result<(), OodlesError> {
+		let idx = self.position_by_file(filename)?;
+		let oodle = &mut self.data[idx];
+		let old = oodle.headers.clone();
+
+		oodle.set_archived(when);
+		if let Err(e) = oodle.save().await {
+			oodle.headers = old;
+			return Err(e.into());
+		}
+
+		Ok(())
+	}
+
+	/// Move an oodle's file, and its revisions if it has any, to `to`.
+	async fn move_oodle(oodle: &mut Oodle, to: PathBuf) -> Result<(), OodlesError> {
+		let revisions = oodle.revisions_path();
+
+		tokio::fs::rename(&oodle.file, &to).await?;
+		let from = std::mem::replace(&mut oodle.file, to);
+
+		match tokio::fs::rename(&revisions, oodle.revisions_path()).await {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+				// Put the oodle back with its revisions
+				tokio::fs::rename(&oodle.file, &from).await?;
+				oodle.file = from;
+				Err(e.into())
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// Whether any oodle is, or was, found at this slug.
+	fn slug_taken(&self, slug: &str) -> bool {
+		self.data.iter().any(|o| o.has_slug(slug))
+	}
+
+	/// A slug made from the title that no other oodle is, or was, found at.
+	fn unique_slug(&self, title: &str) -> String {
+		let slug = slugify(title);
+
+		let mut unique = slug.clone();
+		let mut count = 2;
+		while self.slug_taken(&unique) {
+			unique = format!("{}-{}", slug, count);
+			count += 1;
+		}
+
+		unique
+	}
+
+	/// Whether an oodle already has this filename, or a file is in the way of one.
+	fn filename_taken(&self, filename: &str) -> bool {
+		self.oodle_directory.join(filename).exists() || self.oodle_by_file(filename).is_some()
+	}
+
+	/// Make sure a filename is fit for a new oodle and isn't in use, adding the
+	/// extension if it's missing.
+	fn check_new_filename(&self, filename: &str) -> Result<String, OodlesError> {
+		let filename = oodle_filename(filename)
+			.ok_or_else(|| OodlesError::BadFilename(filename.to_owned()))?;
+
+		if self.filename_taken(&filename) {
+			Err(OodlesError::AlreadyExists(filename))
+		} else {
+			Ok(filename)
+		}
+	}
+
+	/// Titles are written on a line of their own between `-=` and `=-`, so
+	/// they can't have a newline, or a `=-` that would end the line early.
+	fn check_title(title: &str) -> Result<(), OodlesError> {
+		if title.contains(['\n', '\r']) || title.contains("=-") {
+			Err(OodlesError::BadTitle(title.to_owned()))
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Where the oodle with this filename is in [Oodles::data]. Anything that
+	/// isn't just a filename, like a path, is refused rather than matched on
+	/// its last part.
+	fn position_by_file<S: AsRef<str>>(&self, file: S) -> Result<usize, OodlesError> {
+		let file = file.as_ref();
+		if !valid_filename(file) {
+			return Err(OodlesError::BadFilename(file.to_owned()));
+		}
+
 		self.data
 			.iter()
-			.find(|o| o.file.file_name().unwrap() == file.file_name().unwrap())
+			.position(|o| o.file.file_name() == Some(OsStr::new(file)))
+			.ok_or_else(|| OodlesError::NoSuchOodle(file.to_owned()))
 	}
 
-	pub fn oodle_by_file_mut<P: Into<PathBuf>>(&mut self, file: P) -> Option<&mut Oodle> {
-		let file = file.into();
-		self.data.iter_mut().find(|o| {
-			println!("{}", o.file.file_name().unwrap().to_string_lossy());
-			o.file.file_name().unwrap() == file.file_name().unwrap()
-		})
+	/// Split the oodle at the message `at` into a new oodle, with every message
+	/// from `at` onward. See [Oodle::split_off].
+	///
+	/// The new oodle is written before the old one is cut short, so if we fail
+	/// partway through nothing is lost. The moved messages' revisions go with
+	/// them, so a message appended to the old oodle later, which gets one of
+	/// their ids, doesn't get their history too. Returns the new oodle's
+	/// filename.
+	pub async fn split_oodle<T: Into<String>>(
+		&mut self,
+		filename: &str,
+		at: usize,
+		title: T,
+		new_filename: &str,
+	) -> Result<String, OodlesError> {
+		let idx = self.position_by_file(filename)?;
+
+		let title = title.into();
+		Self::check_title(&title)?;
+
+		let new_filename = self.check_new_filename(new_filename)?;
+		let new_path = self.oodle_directory.join(&new_filename);
+
+		let mut source = self.data[idx].clone();
+		let (mut split, moved) = source
+			.split_off(at, title, new_path)
+			.ok_or(OodlesError::CannotSplit(at))?;
+
+		split.set_slug(self.unique_slug(&split.name));
+
+		let original_revisions = self.data[idx].all_revisions().await?;
+		let (kept, taken): (Vec<Revision>, Vec<Revision>) = original_revisions
+			.iter()
+			.cloned()
+			.partition(|rev| source.message(rev.message_id).is_some());
+
+		split.save().await?;
+
+		let mut revisions_written = false;
+		let result = async {
+			for (old, new) in moved {
+				let revisions = taken
+					.iter()
+					.filter(|rev| rev.message_id == old)
+					.cloned()
+					.collect();
+				split.add_revisions(new, revisions).await?;
+			}
+
+			if !taken.is_empty() {
+				revisions_written = true;
+				source.save_revisions(&kept).await?;
+			}
+			source.save().await?;
+			Ok::<_, OodlesError>(())
+		}
+		.await;
+
+		if let Err(e) = result {
+			// If these fail too there's not much else we can do
+			if revisions_written {
+				let _ = self.data[idx].save_revisions(&original_revisions).await;
+			}
+			let _ = tokio::fs::remove_file(&split.file).await;
+			let _ = tokio::fs::remove_file(split.revisions_path()).await;
+			return Err(e);
+		}
+
+		self.data[idx] = source;
+		self.data.push(split);
+
+		self.reindex(filename);
+		self.reindex(&new_filename);
+		Ok(new_filename)
+	}
+
+	/// Merge every message of one oodle into another, ordered by date, and
+	/// remove the one merged from. See [Oodle::merge].
+	///
+	/// Everything is worked out before anything is written. If writing the
+	/// merged oodle or its revisions fails, or the other can't be removed,
+	/// they're put back the way they were so nothing is lost or doubled.
+	pub async fn merge_oodles(&mut self, from: &str, into: &str) -> Result<(), OodlesError> {
+		let from_idx = self.position_by_file(from)?;
+		let into_idx = self.position_by_file(into)?;
+
+		if from_idx == into_idx {
+			return Err(OodlesError::SameOodle);
+		}
+
+		let source = self.data[from_idx].clone();
+		let source_revisions = source.all_revisions().await?;
+		let original_revisions = self.data[into_idx].all_revisions().await?;
+
+		let mut target = self.data[into_idx].clone();
+		let (ours, theirs) = target.merge(source.clone());
+
+		// Links to the merged oodle should find it in its new home
+		let slugs = source.slug().into_iter().chain(source.old_slugs());
+		target.add_old_slugs(slugs.map(<_>::to_owned).collect::<Vec<_>>());
+
+		// Revisions follow their messages. Deleted messages aren't merged, so
+		// theirs are dropped rather than left on an id another message now has.
+		let revisions: Vec<Revision> = original_revisions
+			.iter()
+			.cloned()
+			.map(|rev| (&ours, rev))
+			.chain(source_revisions.into_iter().map(|rev| (&theirs, rev)))
+			.filter_map(|(ids, rev)| {
+				let (_, new) = ids.iter().find(|(old, _)| *old == rev.message_id)?;
+				Some(Revision {
+					message_id: *new,
+					..rev
+				})
+			})
+			.collect();
+
+		let written = async {
+			target.save_revisions(&revisions).await?;
+			target.save().await?;
+			tokio::fs::remove_file(&source.file).await?;
+			Ok::<_, OodlesError>(())
+		}
+		.await;
+
+		if let Err(e) = written {
+			// If these fail too there's not much else we can do
+			let original = &self.data[into_idx];
+			let _ = original.save().await;
+			let _ = original.save_revisions(&original_revisions).await;
+			return Err(e);
+		}
+
+		// The messages are safe in their new oodle, so a revisions file left
+		// behind is only untidy
+		match tokio::fs::remove_file(source.revisions_path()).await {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+				eprintln!("Failed to remove the revisions of {}: {}", from, e)
+			}
+			_ => (),
+		}
+
+		self.data[into_idx] = target;
+		self.reindex(into);
+
+		self.data.remove(from_idx);
+		self.reindex(from);
+		Ok(())
+	}
+
+	/// Save a file and attach it to a message. The file is given a name that
+	/// won't clash with other attachments, which is returned.
+	///
+	/// Images have their EXIF metadata removed and get a thumbnail. If the
+	/// thumbnail can't be made the image is still attached, and shown full size.
+	pub async fn attach_file(
+		&mut self,
+		filename: &str,
+		id: usize,
+		original_name: &str,
+		data: &[u8],
+	) -> Result<String, OodlesError> {
+		let idx = self.position_by_file(filename)?;
+		match self.data[idx].message(id) {
+			Some(msg) if !msg.is_deleted() => (),
+			_ => return Err(OodlesError::NoSuchMessage(id)),
+		}
+
+		let name = format!(
+			"{:08x}-{}",
+			OsRng.gen::<u32>(),
+			sanitize_filename(original_name)
+		);
+		let path = self.attachment_directory.join(&name);
+
+		// Stripping can mean drawing the image again, so it's off this thread
+		let data = if is_image(&name) {
+			let owned = data.to_vec();
+			let stripped = tokio::task::spawn_blocking(move || strip_metadata(&owned).into_owned())
+				.await
+				.expect("metadata task panicked");
+			Cow::Owned(stripped)
+		} else {
+			Cow::Borrowed(data)
+		};
+
+		let mut file = tokio::fs::OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(&path)
+			.await?;
+		let written = async {
+			file.write_all(&data).await?;
+			file.sync_all().await
+		}
+		.await;
+
+		if let Err(e) = written {
+			let _ = tokio::fs::remove_file(&path).await;
+			return Err(e.into());
+		}
+
+		if is_image(&name) {
+			if let Err(e) = self.write_thumbnail(&name, data.into_owned()).await {
+				eprintln!("Failed to make a thumbnail for {}: {}", name, e);
+			}
+		}
+
+		let oodle = &mut self.data[idx];
+		let old = oodle.message(id).unwrap().headers.clone();
+
+		oodle.message_mut(id).unwrap().add_attachment(&name);
+		if let Err(e) = oodle.save().await {
+			oodle.message_mut(id).unwrap().headers = old;
+			let _ = tokio::fs::remove_file(&path).await;
+			return Err(e.into());
+		}
+
+		Ok(name)
+	}
+
+	/// Where an attachment is kept, if there is one by this name.
+	pub fn attachment_path(&self, name: &str) -> Option<PathBuf> {
+		if !valid_filename(name) {
+			return None;
+		}
+
+		Some(self.attachment_directory.join(name)).filter(|path| path.is_file())
+	}
+
+	/// Where a thumbnail is kept. If the thumbnail hasn't been made, this is
+	/// where its image is kept instead.
+	pub fn thumbnail_path(&self, name: &str) -> Option<PathBuf> {
+		if !valid_filename(name) {
+			return None;
+		}
+
+		let thumbnail = self.thumbnail_directory().join(name);
+		if thumbnail.is_file() {
+			return Some(thumbnail);
+		}
+
+		[Some(name), name.strip_suffix(".png")]
+			.into_iter()
+			.flatten()
+			.filter(|&original| is_image(original) && thumbnail_name(original) == name)
+			.find_map(|original| self.attachment_path(original))
+	}
+
+	/// Make thumbnails for every image attachment again, replacing the ones
+	/// there are. Returns how many were made.
+	pub async fn regenerate_thumbnails(&self) -> Result<usize, OodlesError> {
+		let mut made = 0;
+
+		let mut entries = tokio::fs::read_dir(&self.attachment_directory).await?;
+		while let Some(entry) = entries.next_entry().await? {
+			let path = entry.path();
+			let name = entry.file_name().to_string_lossy().into_owned();
+
+			if !path.is_file() || !is_image(&name) {
+				continue;
+			}
+
+			let result = match tokio::fs::read(&path).await {
+				Ok(data) => self.write_thumbnail(&name, data).await,
+				Err(e) => Err(e.into()),
+			};
+
+			match result {
+				Ok(()) => {
+					println!("Made a thumbnail for {}", name);
+					made += 1;
+				}
+				Err(e) => eprintln!("Failed to make a thumbnail for {}: {}", name, e),
+			}
+		}
+
+		Ok(made)
+	}
+
+	fn thumbnail_directory(&self) -> PathBuf {
+		self.attachment_directory.join(THUMBNAIL_DIRECTORY)
+	}
+
+	/// Make the thumbnail for an image attachment and save it.
+	async fn write_thumbnail(&self, name: &str, data: Vec<u8>) -> Result<(), OodlesError> {
+		let thumbnail = {
+			let name = name.to_owned();
+			tokio::task::spawn_blocking(move || make_thumbnail(&name, &data))
+				.await
+				.expect("thumbnail task panicked")?
+		};
+
+		let path = self.thumbnail_directory().join(thumbnail_name(name));
+		tokio::fs::write(path, thumbnail).await?;
+
+		Ok(())
+	}
+
+	/// Move a message from one oodle to another, placing it by date. Returns
+	/// the id it was given in the oodle it moved to.
+	///
+	/// The message is added to its new oodle before it's deleted from the old
+	/// one. If anything fails, both are put back the way they were, revisions
+	/// included, so the message is never lost or in two places.
+	pub async fn move_message(
+		&mut self,
+		from: &str,
+		id: usize,
+		to: &str,
+		now: OffsetDateTime,
+	) -> Result<usize, OodlesError> {
+		let from_idx = self.position_by_file(from)?;
+		let to_idx = self.position_by_file(to)?;
+
+		if from_idx == to_idx {
+			return Err(OodlesError::SameOodle);
+		}
+
+		let source = &self.data[from_idx];
+		let mut msg = source
+			.message(id)
+			.filter(|msg| !msg.is_deleted())
+			.ok_or(OodlesError::NoSuchMessage(id))?
+			.clone();
+		let source_revisions = source.all_revisions().await?;
+		let revisions = source.revisions(id).await?;
+
+		let original = self.data[to_idx].clone();
+		let original_revisions = original.all_revisions().await?;
+
+		// Ids don't travel between oodles, and neither does being edited
+		msg.id = 0;
+		msg.headers.remove(Message::EDITED_HEADER);
+
+		let moved = async {
+			let target = &mut self.data[to_idx];
+			let after = target
+				.messages
+				.iter()
+				.rev()
+				.find(|m| m.date <= msg.date)
+				.or_else(|| target.messages.first())
+				.map(|m| m.id);
+
+			let new_id = match after {
+				Some(after) => target.insert_message_after(after, msg).await?,
+				None => target.append_message(msg).await?,
+			};
+			target.add_revisions(new_id, revisions).await?;
+
+			self.data[from_idx].delete_message(id, now).await?;
+			Ok::<_, OodlesError>(new_id)
+		}
+		.await;
+
+		match moved {
+			Ok(new_id) => {
+				self.reindex(to);
+				self.reindex(from);
+				Ok(new_id)
+			}
+			Err(e) => {
+				// Deleting puts the message itself back if it fails, but may
+				// have kept it as a revision already. If putting things back
+				// fails too there's not much else we can do.
+				let _ = original.save().await;
+				let _ = original.save_revisions(&original_revisions).await;
+				let _ = self.data[from_idx].save_revisions(&source_revisions).await;
+
+				self.data[to_idx] = original;
+				self.reindex(to);
+				Err(e)
+			}
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum OodlesError {
+	/// There's no oodle with this filename
+	NoSuchOodle(String),
+	NoSuchMessage(usize),
+	/// There's already an oodle with this filename
+	AlreadyExists(String),
+	/// The filename has characters other than letters, digits, `-`, `_`, and
+	/// `.`, or starts with a `.`
+	BadFilename(String),
+	/// The slug isn't lowercase letters and digits separated by single dashes
+	BadSlug(String),
+	/// The title has a newline or a `=-`, see [Oodles::check_title]
+	BadTitle(String),
+	/// Another oodle is, or was, found at this slug
+	SlugTaken(String),
+	/// An oodle can't be split at its first message, or at one that doesn't exist
+	CannotSplit(usize),
+	/// The operation needs two different oodles
+	SameOodle,
+	Edit(OodleEditError),
+	/// An image attachment couldn't be read, or its thumbnail couldn't be made
+	Image(ImageError),
+	Io(std::io::Error),
+}
+
+impl fmt::Display for OodlesError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::NoSuchOodle(file) => write!(f, "no oodle with filename {}", file),
+			Self::NoSuchMessage(id) => write!(f, "no message with id {}", id),
+			Self::AlreadyExists(file) => {
+				write!(f, "an oodle with filename {} already exists", file)
+			}
+			Self::BadFilename(file) => write!(
+				f,
+				"{:?} isn't a valid filename. Use only letters, digits, '-', '_', and '.'",
+				file
+			),
+			Self::BadSlug(slug) => write!(
+				f,
+				"{:?} isn't a valid slug. Use lowercase letters and digits separated by '-'",
+				slug
+			),
+			Self::BadTitle(title) => write!(
+				f,
+				"{:?} isn't a valid title. It can't have a newline or '=-'",
+				title
+			),
+			Self::SlugTaken(slug) => write!(f, "another oodle is using the slug {}", slug),
+			Self::CannotSplit(id) => write!(f, "can't split at message {}", id),
+			Self::SameOodle => write!(f, "both oodles are the same"),
+			Self::Edit(e) => write!(f, "{}", e),
+			Self::Image(e) => write!(f, "{}", e),
+			Self::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl From<OodleEditError> for OodlesError {
+	fn from(e: OodleEditError) -> Self {
+		match e {
+			OodleEditError::NoSuchMessage(id) => Self::NoSuchMessage(id),
+			e => Self::Edit(e),
+		}
+	}
+}
+
+impl From<OodleSaveError> for OodlesError {
+	fn from(e: OodleSaveError) -> Self {
+		Self::Edit(e.into())
+	}
+}
+
+impl From<OodleReadError> for OodlesError {
+	fn from(e: OodleReadError) -> Self {
+		Self::Edit(e.into())
+	}
+}
+
+impl From<ImageError> for OodlesError {
+	fn from(e: ImageError) -> Self {
+		Self::Image(e)
+	}
+}
+
+impl From<std::io::Error> for OodlesError {
+	fn from(e: std::io::Error) -> Self {
+		Self::Io(e)
 	}
 }
 
@@ -84,23 +1047,54 @@ pub struct Database {
 	_data_directory: PathBuf,
 
 	users: RwLock<Users>,
+	/// The [generation](SessionSnapshot::generation) of the sessions last
+	/// written, so an older snapshot of them is never written over a newer one
+	sessions_written: Mutex<u64>,
+	/// Held while the credential file or the used steps file is written, so
+	/// two writes can't each undo the other's change
+	credentials_written: Mutex<()>,
 	oodles: RwLock<Oodles>,
+	login_throttle: RwLock<LoginThrottle>,
 }
 
 impl Database {
-	pub fn get<C: AsRef<Path>, D: Into<PathBuf>>(credentials: C, data_directory: D) -> Self {
+	pub fn get<C: AsRef<Path>, D: Into<PathBuf>>(
+		credentials: C,
+		data_directory: D,
+		renderer: Renderer,
+		login_limits: LoginLimits,
+	) -> Self {
 		let data_directory = data_directory.into();
+
+		let mut users = Users::load_file(credentials);
+		users.load_sessions(data_directory.join(Users::SESSION_FILE));
+		users.load_used_steps(data_directory.join(Users::USED_STEPS_FILE));
+
 		Database {
 			_data_directory: data_directory.clone(),
 
-			users: RwLock::new(Users::load_file(credentials)),
-			oodles: RwLock::new(Oodles::new(&data_directory)),
+			users: RwLock::new(users),
+			sessions_written: Mutex::new(0),
+			credentials_written: Mutex::new(()),
+			oodles: RwLock::new(Oodles::new(&data_directory, renderer)),
+			login_throttle: RwLock::new(LoginThrottle::new(login_limits)),
 		}
 	}
 
 	pub async fn create_directories(&self) {
-		if !self.oodles.read().await.oodle_directory.exists() {
-			std::fs::create_dir(&self.oodles.read().await.oodle_directory).unwrap()
+		let oodles = self.oodles.read().await;
+
+		let directories = [
+			&oodles.oodle_directory,
+			&oodles.trash_directory,
+			&oodles.attachment_directory,
+			&oodles.thumbnail_directory(),
+		];
+
+		for directory in directories {
+			if !directory.exists() {
+				std::fs::create_dir(directory).unwrap()
+			}
 		}
 	}
 
@@ -112,29 +1106,218 @@ impl Database {
 		self.oodles.write().await
 	}
 
+	/// Start a login attempt, if someone can try to log in yet after failing
+	/// before. If they can't, how long until they can. It counts as failed
+	/// until [Database::login_succeeded] or [Database::login_refunded] say
+	/// otherwise, see [LoginThrottle::attempt].
+	pub async fn start_login(
+		&self,
+		address: Option<IpAddr>,
+		username: &str,
+		now: Instant,
+	) -> Result<(), Duration> {
+		self.login_throttle
+			.write()
+			.await
+			.attempt(address, username, now)
+	}
+
+	/// Check a username and password. Failures are logged, they were counted
+	/// when the [attempt started](Database::start_login).
 	pub async fn verify_user_login<U: AsRef<str>, P: AsRef<str>>(
 		&self,
+		address: Option<IpAddr>,
 		username: U,
 		password: P,
 	) -> bool {
+		let username = username.as_ref();
 		let hash = {
 			let lock = self.users.read().await;
-			lock.users.get(username.as_ref()).map(String::to_owned)
+			lock.users.get(username).map(String::to_owned)
 		};
 
-		if let Some(hash) = hash {
-			let parsed_hash = PasswordHash::new(&hash).unwrap();
+		let verified = match hash {
+			Some(hash) => verify_hash(&hash, password.as_ref()),
+			None => false,
+		};
 
-			Argon2::default()
-				.verify_password(password.as_ref().as_bytes(), &parsed_hash)
-				.is_ok()
+		if !verified {
+			Self::log_failed_login(address, username);
+		}
+		verified
+	}
+
+	/// Someone finished logging in, so forget the times they got it wrong.
+	/// Getting the password right isn't enough if they still need a code,
+	/// or knowing the password would let you guess codes forever.
+	pub async fn login_succeeded(&self, address: Option<IpAddr>, username: &str) {
+		self.login_throttle
+			.write()
+			.await
+			.succeeded(address, username);
+	}
+
+	/// Someone got a step of logging in right without finishing, like their
+	/// password when they still need a code, so it's not counted as failed.
+	pub async fn login_refunded(&self, address: Option<IpAddr>, username: &str) {
+		self.login_throttle.write().await.refund(address, username);
+	}
+
+	fn log_failed_login(address: Option<IpAddr>, username: &str) {
+		eprintln!(
+			"Failed login for {:?} from {}",
+			username,
+			address
+				.map(|a| a.to_string())
+				.unwrap_or_else(|| "unknown".into())
+		);
+	}
+
+	/// Whether someone has to give a code after their password.
+	pub async fn has_second_factor(&self, username: &str) -> bool {
+		self.users
+			.read()
+			.await
+			.second_factors
+			.contains_key(username)
+	}
+
+	/// Remember that someone got their password right, so they can give their
+	/// code next. Returns the token that stands for them until they do.
+	pub async fn new_login_challenge(&self, username: &str) -> String {
+		self.users
+			.write()
+			.await
+			.new_challenge(username, Instant::now())
+	}
+
+	/// Who a login challenge is for, if it hasn't expired.
+	pub async fn login_challenge(&self, challenge: &str) -> Option<String> {
+		self.users
+			.read()
+			.await
+			.challenge_username(challenge, Instant::now())
+			.map(<_>::to_owned)
+	}
+
+	/// Check the code, or a recovery code, someone gave for a login
+	/// challenge. Returns who logged in if it was right, and the challenge is
+	/// used up. Failures are logged, and counted like a wrong password is when
+	/// the [attempt starts](Database::start_login).
+	pub async fn verify_login_code(
+		&self,
+		address: Option<IpAddr>,
+		challenge: &str,
+		code: &str,
+	) -> Option<String> {
+		let (username, recovery_codes) = {
+			let users = self.users.read().await;
+			let username = users.challenge_username(challenge, Instant::now())?;
+			let factor = users.second_factors.get(username)?;
+			(username.to_owned(), factor.recovery_codes.clone())
+		};
+
+		let verified = if totp::looks_like_code(code) {
+			let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+			self.use_code(&username, code, now).await
+		} else if Users::looks_like_recovery_code(code) {
+			// Hashing is slow, so it's done without holding the lock. Whoever
+			// takes the code out first gets to use it.
+			let code = Users::normalize_recovery_code(code);
+			match recovery_codes
+				.into_iter()
+				.find(|hash| verify_hash(hash, &code))
+			{
+				Some(hash) => self.use_recovery_code(&username, hash).await,
+				None => false,
+			}
 		} else {
 			false
+		};
+
+		if verified {
+			self.users.write().await.challenges.remove(challenge);
+			Some(username)
+		} else {
+			Self::log_failed_login(address, &username);
+			None
 		}
 	}
 
+	/// [Users::use_code], and save which step it was for, so it can't be used
+	/// again after a restart either.
+	async fn use_code(&self, username: &str, code: &str, unix_time: u64) -> bool {
+		let _writing = self.credentials_written.lock().await;
+
+		let (used, snapshot) = {
+			let mut users = self.users.write().await;
+			let used = users.use_code(username, code, unix_time);
+			(used, users.used_steps_snapshot())
+		};
+
+		if let (true, Some((path, contents))) = (used, snapshot) {
+			let written = tokio::task::spawn_blocking({
+				let path = path.clone();
+				move || Users::replace_file(&path, contents.as_bytes())
+			})
+			.await
+			.expect("used steps saving task panicked");
+
+			// It's used in memory either way, and we'd rather let them in
+			if let Err(e) = written {
+				eprintln!("Failed to save the codes used to {:?}: {}", path, e);
+			}
+		}
+
+		used
+	}
+
+	/// [Users::use_recovery_code], and take it out of the credential file too.
+	async fn use_recovery_code(&self, username: &str, hash: String) -> bool {
+		let _writing = self.credentials_written.lock().await;
+
+		let (used, path) = {
+			let mut users = self.users.write().await;
+			let used = users.use_recovery_code(username, &hash);
+			(used, users.credential_file.clone())
+		};
+
+		if used {
+			let username = username.to_owned();
+			let written = tokio::task::spawn_blocking({
+				let path = path.clone();
+				move || {
+					Users::update_second_factors(&path, |factors| {
+						if let Some(factor) = factors.get_mut(&username) {
+							factor.recovery_codes.retain(|code| *code != hash);
+						}
+					})
+				}
+			})
+			.await
+			.expect("credential saving task panicked");
+
+			// It's gone from memory either way, so it can't be used again until
+			// we restart, and we'd rather let them in than not.
+			if let Err(e) = written {
+				eprintln!(
+					"Failed to save {:?} so a used recovery code is taken out: {}",
+					path, e
+				);
+			}
+		}
+
+		used
+	}
+
 	pub async fn new_user_session<U: AsRef<str>>(&self, username: U) -> Session {
-		self.users.write().await.new_session(username).clone()
+		let mut users = self.users.write().await;
+		let session = users.new_session(username).clone();
+		let snapshot = users.session_snapshot();
+		drop(users);
+
+		self.save_sessions(snapshot).await;
+		session
 	}
 
 	//TODO: gen- this is gross
@@ -146,11 +1329,20 @@ impl Database {
 				.map(|s| (*s).to_owned());
 
 			if let Some(cookie) = cookie {
-				self.users
-					.read()
-					.await
-					.get_session(cookie)
-					.map(Session::to_owned)
+				let now = OffsetDateTime::now_utc();
+
+				// Nearly every request changes nothing, so only needs to read
+				if let Some(found) = self.users.read().await.peek_session(&cookie, now) {
+					return found.cloned();
+				}
+
+				let mut users = self.users.write().await;
+				let session = users.get_session(&cookie, now).cloned();
+				let snapshot = users.session_snapshot();
+				drop(users);
+
+				self.save_sessions(snapshot).await;
+				session
 			} else {
 				None
 			}
@@ -160,37 +1352,421 @@ impl Database {
 	}
 
 	pub async fn delete_session(&self, sid: String) -> bool {
-		self.users.write().await.delete_session(sid)
+		let mut users = self.users.write().await;
+		let deleted = users.delete_session(sid);
+		let snapshot = users.session_snapshot();
+		drop(users);
+
+		self.save_sessions(snapshot).await;
+		deleted
+	}
+
+	/// Every session someone is logged in with, newest first.
+	pub async fn user_sessions<U: AsRef<str>>(&self, username: U) -> Vec<Session> {
+		self.users.read().await.user_sessions(username.as_ref())
+	}
+
+	/// End one of someone's sessions, by its [id](Session::id). Returns false
+	/// if they don't have a session with it.
+	pub async fn revoke_session<U: AsRef<str>>(&self, username: U, id: &str) -> bool {
+		let mut users = self.users.write().await;
+		let revoked = users.revoke_session(username.as_ref(), id);
+		let snapshot = users.session_snapshot();
+		drop(users);
+
+		self.save_sessions(snapshot).await;
+		revoked
+	}
+
+	/// Write a snapshot of the sessions to their file, off the async threads
+	/// and without holding the lock on them. It's skipped if it's no newer
+	/// than what was written last.
+	async fn save_sessions(&self, snapshot: Option<SessionSnapshot>) {
+		let snapshot = match snapshot {
+			Some(snapshot) => snapshot,
+			None => return,
+		};
+
+		let mut written = self.sessions_written.lock().await;
+		if snapshot.generation <= *written {
+			return;
+		}
+
+		let generation = snapshot.generation;
+		let path = snapshot.path.clone();
+		let saved = tokio::task::spawn_blocking(move || {
+			Users::replace_file(&snapshot.path, snapshot.contents.as_bytes())
+		})
+		.await
+		.expect("session saving task panicked");
+
+		match saved {
+			Ok(()) => *written = generation,
+			Err(e) => eprintln!("Failed to save sessions to {:?}: {}", path, e),
+		}
 	}
 }
 
 #[derive(Clone, Debug)]
 pub struct Users {
 	users: HashMap<String, String>,
+	/// The codes of the users who log in with more than a password
+	second_factors: HashMap<String, SecondFactor>,
+	/// Where the users are kept, so used recovery codes can be taken out
+	credential_file: PathBuf,
+	/// Where the last step each person used a code for is kept
+	used_steps_file: Option<PathBuf>,
+	/// People who got their password right and still need to give a code, by
+	/// the token they were given
+	challenges: HashMap<String, Challenge>,
 	sessions: Vec<Session>,
+	/// Where sessions are kept so a restart doesn't log everyone out
+	session_file: Option<PathBuf>,
+	/// Counts every change to the sessions, see [SessionSnapshot::generation]
+	session_generation: u64,
+}
+
+/// The sessions as they were at one moment, to write to the session file
+/// after the lock on them is let go.
+#[derive(Clone, Debug)]
+pub struct SessionSnapshot {
+	path: PathBuf,
+	contents: String,
+	/// Higher for a later snapshot, if the sessions changed in between
+	pub generation: u64,
+}
+
+/// A secret to make login codes from, as in RFC 6238, and codes that can be
+/// used in their place if it's lost.
+#[derive(Clone, Debug)]
+struct SecondFactor {
+	secret: Vec<u8>,
+	/// Argon2 hashes of the recovery codes that haven't been used
+	recovery_codes: Vec<String>,
+	/// The last step a code was accepted for, so none can be used twice
+	used_step: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+struct Challenge {
+	username: String,
+	expires: Instant,
 }
 
 impl Users {
 	const BASE58: &'static [u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
-	const SESSION_ID_LENGTH: usize = 6;
+	/// About 187 bits, far too many to guess
+	const SESSION_ID_LENGTH: usize = 32;
+
+	/// The file in the data directory sessions are kept in.
+	pub const SESSION_FILE: &'static str = "sessions";
 
+	/// The file in the data directory the last step each person used a code
+	/// for is kept in, see [Users::load_used_steps].
+	pub const USED_STEPS_FILE: &'static str = "used-steps";
+
+	/// How long a session can go unused before it's saved again with when it
+	/// was last seen, so every request doesn't write the file.
+	const LAST_SEEN_GRANULARITY: Duration = Duration::from_secs(60 * 5);
+
+	/// How long someone has to give their code after their password.
+	const CHALLENGE_LIFETIME: Duration = Duration::from_secs(60 * 5);
+	const CHALLENGE_LENGTH: usize = 32;
+
+	/// How many recovery codes someone gets, and how long they are.
+	const RECOVERY_CODES: usize = 10;
+	const RECOVERY_CODE_LENGTH: usize = 10;
+
+	/// Read the credential file. Each line is a username and the Argon2 hash of
+	/// their password, then, if they have a second factor, its base32 secret
+	/// and the hashes of their recovery codes, all separated by spaces.
+	///
+	/// It's only read here, so changes to it, like from `oodles totp`, need a
+	/// restart to be seen.
 	pub fn load_file<C: AsRef<Path>>(credentials: C) -> Users {
-		let string = std::fs::read_to_string(credentials).unwrap();
+		let credential_file = credentials.as_ref().to_owned();
+		let (users, second_factors) = match Self::read_credentials(&credential_file) {
+			Ok(credentials) => credentials,
+			Err(e) => panic!("failed to read credential file: {}", e),
+		};
+
+		Users {
+			users,
+			second_factors,
+			credential_file,
+			used_steps_file: None,
+			challenges: HashMap::new(),
+			sessions: vec![],
+			session_file: None,
+			session_generation: 0,
+		}
+	}
+
+	/// The users, by the hash of their password, and their second factors, from
+	/// a credential file in the format [Users::load_file] describes.
+	#[allow(clippy::type_complexity)]
+	fn read_credentials(
+		path: &Path,
+	) -> std::io::Result<(HashMap<String, String>, HashMap<String, SecondFactor>)> {
+		let invalid = |message| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+		let string = std::fs::read_to_string(path)?;
 
 		let mut users = HashMap::new();
+		let mut second_factors = HashMap::new();
 		for line in string.lines() {
-			match line.split_once(" ") {
-				Some((username, hash)) => {
+			let mut fields = line.split(' ');
+			match (fields.next(), fields.next()) {
+				(Some(username), Some(hash)) if !username.is_empty() && !hash.is_empty() => {
 					users.insert(username.into(), hash.into());
+
+					if let Some(secret) = fields.next() {
+						let secret = totp::decode_secret(secret).ok_or_else(|| {
+							invalid("did not understand second factor secret in credential file")
+						})?;
+
+						second_factors.insert(
+							username.into(),
+							SecondFactor {
+								secret,
+								recovery_codes: fields.map(<_>::to_owned).collect(),
+								used_step: None,
+							},
+						);
+					}
 				}
-				_ => panic!("did not understand credential file format"),
+				_ => return Err(invalid("did not understand credential file format")),
 			}
 		}
 
-		Users {
-			users,
-			sessions: vec![],
+		Ok((users, second_factors))
+	}
+
+	/// Give someone a new second factor, replacing any they had, and save it
+	/// to the credential file. Returns the secret and the recovery codes, which
+	/// are only kept hashed, so this is the only time they can be seen.
+	///
+	/// A server that's already running won't know about it until it restarts,
+	/// see [Users::load_file].
+	pub fn enroll_second_factor(
+		&mut self,
+		username: &str,
+	) -> std::io::Result<(Vec<u8>, Vec<String>)> {
+		if !self.users.contains_key(username) {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				"there's no user with that name",
+			));
+		}
+
+		let secret = totp::generate_secret();
+		let codes: Vec<String> = (0..Self::RECOVERY_CODES)
+			.map(|_| {
+				let code = Self::random_base58(Self::RECOVERY_CODE_LENGTH);
+				let (first, second) = code.split_at(Self::RECOVERY_CODE_LENGTH / 2);
+				format!("{}-{}", first, second)
+			})
+			.collect();
+
+		let argon2 = Argon2::default();
+		let recovery_codes = codes
+			.iter()
+			.map(|code| {
+				let salt = SaltString::generate(&mut OsRng);
+				argon2
+					.hash_password(Self::normalize_recovery_code(code).as_bytes(), &salt)
+					.unwrap()
+					.to_string()
+			})
+			.collect();
+
+		let factor = SecondFactor {
+			secret: secret.clone(),
+			recovery_codes,
+			used_step: None,
+		};
+		Self::update_second_factors(&self.credential_file, |factors| {
+			factors.insert(username.to_owned(), factor.clone());
+		})?;
+		self.second_factors.insert(username.to_owned(), factor);
+
+		Ok((secret, codes))
+	}
+
+	/// Whether what someone typed could be a recovery code, so a guess that
+	/// can't be one isn't hashed against every one they have.
+	fn looks_like_recovery_code(code: &str) -> bool {
+		let code = Self::normalize_recovery_code(code);
+		code.len() == Self::RECOVERY_CODE_LENGTH && code.bytes().all(|b| Self::BASE58.contains(&b))
+	}
+
+	/// Recovery codes are shown with a dash in the middle, but it doesn't
+	/// matter if it's typed.
+	fn normalize_recovery_code(code: &str) -> String {
+		code.chars()
+			.filter(|c| *c != '-' && !c.is_whitespace())
+			.collect()
+	}
+
+	fn new_challenge(&mut self, username: &str, now: Instant) -> String {
+		self.challenges
+			.retain(|_, challenge| challenge.expires > now);
+
+		let token = Self::random_base58(Self::CHALLENGE_LENGTH);
+		self.challenges.insert(
+			token.clone(),
+			Challenge {
+				username: username.to_owned(),
+				expires: now + Self::CHALLENGE_LIFETIME,
+			},
+		);
+		token
+	}
+
+	fn challenge_username(&self, token: &str, now: Instant) -> Option<&str> {
+		self.challenges
+			.get(token)
+			.filter(|challenge| challenge.expires > now)
+			.map(|challenge| challenge.username.as_str())
+	}
+
+	/// Check a code from someone's authenticator, and don't accept it, or any
+	/// before it, again.
+	fn use_code(&mut self, username: &str, code: &str, unix_time: u64) -> bool {
+		let factor = match self.second_factors.get_mut(username) {
+			Some(factor) => factor,
+			None => return false,
+		};
+
+		match totp::verify(&factor.secret, code, unix_time, factor.used_step) {
+			Some(step) => {
+				factor.used_step = Some(step);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Take a recovery code, by its hash, out of someone's codes. Returns
+	/// false if it isn't there, like if it was just used. It's only taken out
+	/// in memory, see [Users::update_second_factors].
+	fn use_recovery_code(&mut self, username: &str, hash: &str) -> bool {
+		let factor = match self.second_factors.get_mut(username) {
+			Some(factor) => factor,
+			None => return false,
+		};
+
+		let before = factor.recovery_codes.len();
+		factor.recovery_codes.retain(|code| code != hash);
+		factor.recovery_codes.len() != before
+	}
+
+	/// Change the second factors in a credential file. It's read again first
+	/// and only the change is made to what's there, so anything changed since
+	/// we loaded it, like someone enrolling with `oodles totp`, isn't written
+	/// over with what we have in memory.
+	fn update_second_factors<F>(path: &Path, change: F) -> std::io::Result<()>
+	where
+		F: FnOnce(&mut HashMap<String, SecondFactor>),
+	{
+		let (users, mut second_factors) = Self::read_credentials(path)?;
+		change(&mut second_factors);
+
+		let contents = Self::credentials_contents(&users, &second_factors);
+		Self::replace_file(path, contents.as_bytes())
+	}
+
+	/// Users in the format [load_file](Users::load_file) reads, sorted by
+	/// username.
+	fn credentials_contents(
+		users: &HashMap<String, String>,
+		second_factors: &HashMap<String, SecondFactor>,
+	) -> String {
+		let mut usernames: Vec<&String> = users.keys().collect();
+		usernames.sort();
+
+		let mut contents = String::new();
+		for username in usernames {
+			contents.push_str(&format!("{} {}", username, users[username]));
+
+			if let Some(factor) = second_factors.get(username) {
+				contents.push(' ');
+				contents.push_str(&totp::encode_secret(&factor.secret));
+				for code in &factor.recovery_codes {
+					contents.push(' ');
+					contents.push_str(code);
+				}
+			}
+			contents.push('\n');
 		}
+
+		contents
+	}
+
+	/// Read the last step each person used a code for from a file, and keep
+	/// them there from now on, so a code can't be used again after a restart.
+	/// A missing file has none, and lines we don't understand are left out.
+	pub fn load_used_steps<P: Into<PathBuf>>(&mut self, path: P) {
+		let path = path.into();
+
+		match std::fs::read_to_string(&path) {
+			Ok(string) => {
+				for line in string.lines().filter(|line| !line.trim().is_empty()) {
+					let used = line
+						.split_once(' ')
+						.and_then(|(username, step)| Some((username, step.parse().ok()?)));
+
+					match used {
+						Some((username, step)) => {
+							if let Some(factor) = self.second_factors.get_mut(username) {
+								factor.used_step = Some(step);
+							}
+						}
+						None => eprintln!("Skipping a used step we don't understand in {:?}", path),
+					}
+				}
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+			Err(e) => eprintln!("Failed to read used steps from {:?}: {}", path, e),
+		}
+
+		self.used_steps_file = Some(path);
+	}
+
+	/// The [used steps file](Users::load_used_steps) and what to write to it,
+	/// a username and step on each line. None if there's no file.
+	fn used_steps_snapshot(&self) -> Option<(PathBuf, String)> {
+		let mut lines: Vec<String> = self
+			.second_factors
+			.iter()
+			.filter_map(|(username, factor)| Some(format!("{} {}\n", username, factor.used_step?)))
+			.collect();
+		lines.sort();
+
+		Some((self.used_steps_file.clone()?, lines.concat()))
+	}
+
+	/// Read the sessions kept in a file, and keep them there from now on. A
+	/// missing file has no sessions. Expired sessions, and lines we don't
+	/// understand, are left out.
+	pub fn load_sessions<P: Into<PathBuf>>(&mut self, path: P) {
+		let path = path.into();
+		let now = OffsetDateTime::now_utc();
+
+		match std::fs::read_to_string(&path) {
+			Ok(string) => {
+				for line in string.lines().filter(|line| !line.trim().is_empty()) {
+					match Session::from_line(line) {
+						Some(session) if !session.expired(now) => self.sessions.push(session),
+						Some(_) => (),
+						None => eprintln!("Skipping a session we don't understand in {:?}", path),
+					}
+				}
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+			Err(e) => eprintln!("Failed to read sessions from {:?}: {}", path, e),
+		}
+
+		self.session_file = Some(path);
 	}
 
 	pub fn new_session<U: AsRef<str>>(&mut self, username: U) -> &Session {
@@ -198,11 +1774,36 @@ impl Users {
 
 		let session = Session::new(cookie, username.as_ref().into());
 		self.sessions.push(session);
+		self.session_generation += 1;
 		self.sessions.last().unwrap()
 	}
 
-	pub fn get_session(&self, sid: String) -> Option<&Session> {
-		self.sessions.iter().find(|&s| s.cookie == sid)
+	/// The session with a cookie, if it hasn't expired, marked as seen now.
+	pub fn get_session(&mut self, sid: &str, now: OffsetDateTime) -> Option<&Session> {
+		self.expire_sessions(now);
+
+		let idx = self.sessions.iter().position(|s| s.cookie == sid)?;
+		if now - self.sessions[idx].last_seen >= Self::LAST_SEEN_GRANULARITY {
+			self.sessions[idx].last_seen = now;
+			self.session_generation += 1;
+		}
+
+		self.sessions.get(idx)
+	}
+
+	/// [Users::get_session] if it wouldn't change anything, so it can be
+	/// asked with only a read lock. None if something would change, either a
+	/// session has expired or this one is due to be marked as seen, and then
+	/// [Users::get_session] has to be asked instead.
+	pub fn peek_session(&self, sid: &str, now: OffsetDateTime) -> Option<Option<&Session>> {
+		if self.sessions.iter().any(|sesh| sesh.expired(now)) {
+			return None;
+		}
+
+		match self.sessions.iter().find(|sesh| sesh.cookie == sid) {
+			Some(sesh) if now - sesh.last_seen >= Self::LAST_SEEN_GRANULARITY => None,
+			found => Some(found),
+		}
 	}
 
 	pub fn delete_session(&mut self, sid: String) -> bool {
@@ -213,12 +1814,102 @@ impl Users {
 			.find(|(_, sesh)| sesh.cookie == sid);
 		if let Some((idx, _)) = op {
 			self.sessions.swap_remove(idx);
+			self.session_generation += 1;
 			true
 		} else {
 			false
 		}
 	}
 
+	/// Every session someone is logged in with, newest first.
+	pub fn user_sessions(&self, username: &str) -> Vec<Session> {
+		let mut sessions: Vec<Session> = self
+			.sessions
+			.iter()
+			.filter(|sesh| sesh.username == username)
+			.cloned()
+			.collect();
+
+		sessions.sort_by_key(|sesh| std::cmp::Reverse(sesh.created));
+		sessions
+	}
+
+	/// End one of someone's sessions by its [id](Session::id).
+	pub fn revoke_session(&mut self, username: &str, id: &str) -> bool {
+		let before = self.sessions.len();
+		self.sessions
+			.retain(|sesh| !(sesh.username == username && sesh.id == id));
+
+		let revoked = self.sessions.len() != before;
+		if revoked {
+			self.session_generation += 1;
+		}
+		revoked
+	}
+
+	/// Forget sessions that have expired.
+	fn expire_sessions(&mut self, now: OffsetDateTime) {
+		let before = self.sessions.len();
+		self.sessions.retain(|sesh| !sesh.expired(now));
+
+		if self.sessions.len() != before {
+			self.session_generation += 1;
+		}
+	}
+
+	/// Every session as it is now, to write to the [session
+	/// file](Users::load_sessions) with [Users::replace_file]. None if there's
+	/// no session file.
+	pub fn session_snapshot(&self) -> Option<SessionSnapshot> {
+		let contents = self
+			.sessions
+			.iter()
+			.map(|sesh| format!("{}\n", sesh.to_line()))
+			.collect();
+
+		Some(SessionSnapshot {
+			path: self.session_file.clone()?,
+			contents,
+			generation: self.session_generation,
+		})
+	}
+
+	/// Replace a file that only we can read, since it holds secrets. It's
+	/// written and synced beside it and then moved over it, so it's never left
+	/// half written, like [Oodle::save].
+	fn replace_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+		let temporary = path.with_extension("tmp");
+		Self::write_private(&temporary, contents)?;
+		std::fs::rename(&temporary, path)?;
+
+		#[cfg(unix)]
+		{
+			let directory = match path.parent() {
+				Some(parent) if !parent.as_os_str().is_empty() => parent,
+				_ => Path::new("."),
+			};
+			std::fs::File::open(directory)?.sync_all()?;
+		}
+
+		Ok(())
+	}
+
+	fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+		use std::io::Write;
+
+		let mut options = std::fs::OpenOptions::new();
+		options.write(true).create(true).truncate(true);
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::OpenOptionsExt;
+			options.mode(0o600);
+		}
+
+		let mut file = options.open(path)?;
+		file.write_all(contents)?;
+		file.sync_all()
+	}
+
 	fn random_base58(count: usize) -> String {
 		let mut ret = String::with_capacity(count);
 
@@ -232,30 +1923,131 @@ impl Users {
 	}
 }
 
+/// Whether a secret is the one an Argon2 hash was made from.
+fn verify_hash(hash: &str, secret: &str) -> bool {
+	PasswordHash::new(hash)
+		.map(|parsed| {
+			Argon2::default()
+				.verify_password(secret.as_bytes(), &parsed)
+				.is_ok()
+		})
+		.unwrap_or(false)
+}
+
 #[derive(Clone, Debug)]
 pub struct Session {
 	pub cookie: String,
+	/// Names the session where they're listed, without giving away the cookie
+	pub id: String,
 	pub username: String,
+	/// Sent with every write to prove it came from one of our pages, and not
+	/// from another site making the browser post for it.
+	pub csrf_token: String,
+	pub created: OffsetDateTime,
+	pub last_seen: OffsetDateTime,
 }
 
 impl Session {
+	const CSRF_TOKEN_LENGTH: usize = 32;
+	const ID_LENGTH: usize = 8;
+
+	/// How long a session lasts after logging in, however much it's used.
+	pub const LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+	/// How long a session lasts without being used.
+	pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60 * 24 * 2);
+
 	pub fn new(cookie: String, username: String) -> Self {
-		Self { cookie, username }
+		let now = OffsetDateTime::now_utc();
+
+		Self {
+			cookie,
+			id: Users::random_base58(Self::ID_LENGTH),
+			username,
+			csrf_token: Users::random_base58(Self::CSRF_TOKEN_LENGTH),
+			created: now,
+			last_seen: now,
+		}
+	}
+
+	/// Whether the session is past its [LIFETIME](Self::LIFETIME) or has
+	/// been idle for longer than the [IDLE_TIMEOUT](Self::IDLE_TIMEOUT).
+	pub fn expired(&self, now: OffsetDateTime) -> bool {
+		now >= self.created + Self::LIFETIME || now >= self.last_seen + Self::IDLE_TIMEOUT
+	}
+
+	/// A line of the session file: the cookie, id, CSRF token, when it was
+	/// created and last seen as Unix timestamps, and the username, separated
+	/// by spaces.
+	fn to_line(&self) -> String {
+		format!(
+			"{} {} {} {} {} {}",
+			self.cookie,
+			self.id,
+			self.csrf_token,
+			self.created.unix_timestamp(),
+			self.last_seen.unix_timestamp(),
+			self.username
+		)
+	}
+
+	fn from_line(line: &str) -> Option<Self> {
+		let mut fields = line.splitn(6, ' ');
+		let mut next = || fields.next().filter(|field| !field.is_empty());
+
+		let cookie = next()?.to_owned();
+		let id = next()?.to_owned();
+		let csrf_token = next()?.to_owned();
+		let created = OffsetDateTime::from_unix_timestamp(next()?.parse().ok()?).ok()?;
+		let last_seen = OffsetDateTime::from_unix_timestamp(next()?.parse().ok()?).ok()?;
+		let username = next()?.to_owned();
+
+		Some(Self {
+			cookie,
+			id,
+			username,
+			csrf_token,
+			created,
+			last_seen,
+		})
+	}
+
+	/// Whether a token sent with a request is this session's CSRF token. It's
+	/// compared in constant time so the token can't be guessed by timing.
+	pub fn check_csrf(&self, token: &str) -> bool {
+		let ours = self.csrf_token.as_bytes();
+		let theirs = token.as_bytes();
+
+		ours.len() == theirs.len()
+			&& ours
+				.iter()
+				.zip(theirs)
+				.fold(0, |diff, (a, b)| diff | (a ^ b))
+				== 0
 	}
 
 	pub fn get_set_cookie(&self) -> String {
-		mavourings::cookie::SetCookie::new("sid".into(), self.cookie.clone())
+		let cookie = mavourings::cookie::SetCookie::new("sid".into(), self.cookie.clone())
 			.secure(true)
 			.httponly(true)
-			.max_age(Some(Duration::from_secs(60 * 60 * 24 * 7)))
-			.as_string()
+			.max_age(Some(Self::LIFETIME))
+			.as_string();
+
+		Self::same_site(cookie)
 	}
 
 	pub fn get_clear_cookie(&self) -> String {
-		mavourings::cookie::SetCookie::new("sid".into(), self.cookie.clone())
+		let cookie = mavourings::cookie::SetCookie::new("sid".into(), self.cookie.clone())
 			.secure(true)
 			.httponly(true)
 			.max_age(Some(Duration::from_secs(0)))
-			.as_string()
+			.as_string();
+
+		Self::same_site(cookie)
+	}
+
+	/// Keep the browser from sending the cookie with posts from other sites.
+	/// Lax still sends it when following a link here, so you stay logged in.
+	fn same_site(cookie: String) -> String {
+		format!("{}; SameSite=Lax", cookie)
 	}
 }
@@ -0,0 +1,260 @@
+use std::fmt;
+
+/// An error encountered while parsing an [Oodle](crate::Oodle) or [Message](crate::Message).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OodleParseError {
+	/// The line the error occured on, starting from 1.
+	pub line: usize,
+	/// The column the error occured at, starting from 1.
+	pub column: usize,
+	pub kind: OodleParseErrorKind,
+	/// The text that could not be parsed.
+	pub text: String,
+}
+
+impl OodleParseError {
+	pub(crate) fn new<T: Into<String>>(
+		line: usize,
+		column: usize,
+		kind: OodleParseErrorKind,
+		text: T,
+	) -> Self {
+		Self {
+			line,
+			column,
+			kind,
+			text: text.into(),
+		}
+	}
+}
+
+impl fmt::Display for OodleParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"line {}, column {}: {} ({:?})",
+			self.line, self.column, self.kind, self.text
+		)
+	}
+}
+
+impl std::error::Error for OodleParseError {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OodleParseErrorKind {
+	/// The file does not start with a `-= Title =-` line
+	MissingTitle,
+	/// The title line is not closed, or is not followed by a blank line
+	MalformedTitle,
+	/// The dateline could not be parsed as a date
+	BadDateline,
+	/// The `(idx)` at the end of a dateline is not a number
+	BadIndex,
+	/// A message was terminated before it had a dateline
+	EmptyMessage,
+	/// A line in a message's headers is not `Key: value`
+	BadHeader,
+}
+
+impl fmt::Display for OodleParseErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::MissingTitle => write!(f, "missing title"),
+			Self::MalformedTitle => write!(f, "malformed title"),
+			Self::BadDateline => write!(f, "bad dateline"),
+			Self::BadIndex => write!(f, "bad message index"),
+			Self::EmptyMessage => write!(f, "empty message"),
+			Self::BadHeader => write!(f, "bad header"),
+		}
+	}
+}
+
+/// An error encountered while reading an [Oodle](crate::Oodle) from disk.
+#[derive(Debug)]
+pub enum OodleReadError {
+	Io(std::io::Error),
+	Parse(OodleParseError),
+}
+
+impl fmt::Display for OodleReadError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Io(e) => write!(f, "failed to read oodle: {}", e),
+			Self::Parse(e) => write!(f, "failed to parse oodle: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for OodleReadError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Io(e) => Some(e),
+			Self::Parse(e) => Some(e),
+		}
+	}
+}
+
+impl From<std::io::Error> for OodleReadError {
+	fn from(e: std::io::Error) -> Self {
+		Self::Io(e)
+	}
+}
+
+impl From<OodleParseError> for OodleReadError {
+	fn from(e: OodleParseError) -> Self {
+		Self::Parse(e)
+	}
+}
+
+/// A problem that was recovered from while parsing an [Oodle](crate::Oodle)
+/// leniently.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseWarning {
+	/// The line the problem was found on, starting from 1.
+	pub line: usize,
+	pub kind: ParseWarningKind,
+}
+
+impl ParseWarning {
+	pub(crate) fn new(line: usize, kind: ParseWarningKind) -> Self {
+		Self { line, kind }
+	}
+}
+
+impl fmt::Display for ParseWarning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.kind)
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseWarningKind {
+	/// The file started with a UTF-8 byte order mark, which was removed
+	ByteOrderMark,
+	/// The file has CRLF line endings. Only reported for the first line.
+	CarriageReturn,
+	/// The title or a message terminator had trailing whitespace
+	TrailingWhitespace,
+	/// The title was not followed by a blank line
+	MissingBlankLine,
+	/// A message could not be parsed and was left out
+	SkippedMessage(OodleParseError),
+}
+
+impl fmt::Display for ParseWarningKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::ByteOrderMark => write!(f, "removed byte order mark"),
+			Self::CarriageReturn => write!(f, "converted CRLF line endings"),
+			Self::TrailingWhitespace => write!(f, "removed trailing whitespace"),
+			Self::MissingBlankLine => write!(f, "no blank line after title"),
+			Self::SkippedMessage(e) => write!(f, "skipped message: {}", e),
+		}
+	}
+}
+
+/// An error encountered while saving an [Oodle](crate::Oodle) to disk, along
+/// with the step of the save that failed.
+#[derive(Debug)]
+pub struct OodleSaveError {
+	pub step: SaveStep,
+	pub source: std::io::Error,
+}
+
+impl OodleSaveError {
+	pub(crate) fn new(step: SaveStep, source: std::io::Error) -> Self {
+		Self { step, source }
+	}
+}
+
+impl fmt::Display for OodleSaveError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "failed to {}: {}", self.step, self.source)
+	}
+}
+
+impl std::error::Error for OodleSaveError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		Some(&self.source)
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SaveStep {
+	/// Making sure saving won't lose anything, see
+	/// [Oodle::is_incomplete](crate::Oodle::is_incomplete)
+	Check,
+	/// Creating the temporary file next to the oodle
+	CreateTemp,
+	/// Opening the oodle to append a message
+	Open,
+	/// Writing the oodle to the temporary file, or a message to the oodle
+	Write,
+	/// Flushing the written file to disk
+	SyncFile,
+	/// Moving the temporary file over the oodle
+	Rename,
+	/// Flushing the directory so the rename is durable
+	SyncDirectory,
+}
+
+impl fmt::Display for SaveStep {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Check => write!(f, "save incomplete oodle"),
+			Self::CreateTemp => write!(f, "create temporary file"),
+			Self::Open => write!(f, "open oodle"),
+			Self::Write => write!(f, "write file"),
+			Self::SyncFile => write!(f, "sync file"),
+			Self::Rename => write!(f, "rename temporary file"),
+			Self::SyncDirectory => write!(f, "sync directory"),
+		}
+	}
+}
+
+/// An error encountered while changing a message in an [Oodle](crate::Oodle).
+#[derive(Debug)]
+pub enum OodleEditError {
+	/// There's no message with this id
+	NoSuchMessage(usize),
+	/// The message doesn't have a revision with this number
+	NoSuchRevision(usize),
+	/// The message doesn't have an attachment by this name
+	NoSuchAttachment(String),
+	Read(OodleReadError),
+	Save(OodleSaveError),
+}
+
+impl fmt::Display for OodleEditError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::NoSuchMessage(id) => write!(f, "no message with id {}", id),
+			Self::NoSuchRevision(rev) => write!(f, "no revision {}", rev),
+			Self::NoSuchAttachment(name) => write!(f, "no attachment named {}", name),
+			Self::Read(e) => write!(f, "{}", e),
+			Self::Save(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl std::error::Error for OodleEditError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::NoSuchMessage(_) | Self::NoSuchRevision(_) | Self::NoSuchAttachment(_) => None,
+			Self::Read(e) => Some(e),
+			Self::Save(e) => Some(e),
+		}
+	}
+}
+
+impl From<OodleReadError> for OodleEditError {
+	fn from(e: OodleReadError) -> Self {
+		Self::Read(e)
+	}
+}
+
+impl From<OodleSaveError> for OodleEditError {
+	fn from(e: OodleSaveError) -> Self {
+		Self::Save(e)
+	}
+}
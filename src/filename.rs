@@ -0,0 +1,96 @@
+/// The extension every new oodle's file is given.
+pub const OODLE_EXTENSION: &str = "oodle";
+
+/// Whether a filename is safe to put in the oodle directory. It has to be
+/// made of ASCII letters, digits, `-`, `_`, and `.`, and can't start with a
+/// `.`, so it can't leave the directory or hide in it.
+pub fn valid_filename(filename: &str) -> bool {
+	!filename.is_empty()
+		&& !filename.starts_with('.')
+		&& filename
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Make a filename [valid](valid_filename) by replacing anything that isn't
+/// allowed with a `-`. The extension is kept, so `my screenshot.png` becomes
+/// `my-screenshot.png`.
+pub fn sanitize_filename(filename: &str) -> String {
+	let sanitized: String = filename
+		.chars()
+		.map(|c| {
+			if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+				c
+			} else {
+				'-'
+			}
+		})
+		.collect();
+
+	let sanitized = sanitized.trim_start_matches('.');
+	if sanitized.is_empty() {
+		String::from("file")
+	} else {
+		sanitized.to_owned()
+	}
+}
+
+/// Check a filename for a new oodle, adding the `.oodle` extension if it's
+/// missing. Returns None if the filename isn't [valid](valid_filename).
+pub fn oodle_filename(filename: &str) -> Option<String> {
+	let filename = filename.trim();
+	let filename = match filename.strip_suffix(OODLE_EXTENSION) {
+		Some(stem) if stem.ends_with('.') => filename.to_owned(),
+		_ => format!("{}.{}", filename, OODLE_EXTENSION),
+	};
+
+	let stem = &filename[..filename.len() - OODLE_EXTENSION.len() - 1];
+	if stem.is_empty() || !valid_filename(&filename) {
+		None
+	} else {
+		Some(filename)
+	}
+}
+
+/// Lowercase a title and replace every run of anything that isn't a letter or
+/// digit with a single `-`. A title with no letters or digits at all becomes
+/// `oodle`.
+pub fn slugify(title: &str) -> String {
+	let mut slug = String::with_capacity(title.len());
+
+	for c in title.chars() {
+		if c.is_ascii_alphanumeric() {
+			slug.push(c.to_ascii_lowercase());
+		} else if !slug.is_empty() && !slug.ends_with('-') {
+			slug.push('-');
+		}
+	}
+
+	let slug = slug.trim_end_matches('-');
+	if slug.is_empty() {
+		String::from("oodle")
+	} else {
+		slug.to_owned()
+	}
+}
+
+/// Whether this could have come from [slugify]: lowercase letters and digits
+/// in runs separated by single dashes.
+pub fn valid_slug(slug: &str) -> bool {
+	slugify(slug) == slug
+}
+
+/// A filename for an oodle made from its title, like `my-first-oodle.oodle`.
+/// If `taken` says that one's in use, a number is added until it isn't.
+pub fn filename_from_title<F: Fn(&str) -> bool>(title: &str, taken: F) -> String {
+	let slug = slugify(title);
+
+	let mut filename = format!("{}.{}", slug, OODLE_EXTENSION);
+	let mut count = 2;
+	while taken(&filename) {
+		filename = format!("{}-{}.{}", slug, count, OODLE_EXTENSION);
+		count += 1;
+	}
+
+	filename
+}
@@ -2,6 +2,7 @@ use std::str::FromStr;
 
 use hyper::StatusCode;
 use mavourings::query::Query;
+use oodles::{try_parse_tags, Attachment, Renderer};
 use serde::Deserialize;
 
 use crate::Request;
@@ -21,9 +22,28 @@ impl Login {
 	}
 }
 
+/// The second step of logging in, see [Database::verify_login_code](crate::database::Database::verify_login_code).
+pub struct LoginCode {
+	/// What the first step gave back for getting the password right
+	pub challenge: String,
+	/// From an authenticator app, or a recovery code
+	pub code: String,
+}
+
+impl LoginCode {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let challenge = query.get_first_value("challenge")?;
+		let code = query.get_first_value("code")?;
+
+		Ok(Self { challenge, code })
+	}
+}
+
 pub struct OodleCreate {
 	pub title: String,
-	pub filename: String,
+	/// None if the filename should be made from the title
+	pub filename: Option<String>,
 	pub content: String,
 }
 
@@ -31,7 +51,7 @@ impl OodleCreate {
 	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
 		let query = QueryWrapper::from_post_body(req).await?;
 		let title = query.get_first_value("title")?;
-		let filename = query.get_first_value("filename")?;
+		let filename = query.get_optional_value("filename");
 		let content = query.get_first_value("firstPost")?;
 
 		Ok(Self {
@@ -42,6 +62,138 @@ impl OodleCreate {
 	}
 }
 
+pub struct OodleSplit {
+	pub filename: String,
+	/// The id of the first message to go in the new oodle
+	pub at: usize,
+	pub title: String,
+	pub new_filename: String,
+}
+
+impl OodleSplit {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let filename = query.get_first_value("filename")?;
+		let at = query.parse_first_value("at")?;
+		let title = query.get_first_value("title")?;
+		let new_filename = query.get_first_value("newFilename")?;
+
+		Ok(Self {
+			filename,
+			at,
+			title,
+			new_filename,
+		})
+	}
+}
+
+pub struct OodleMerge {
+	pub from: String,
+	pub into: String,
+}
+
+impl OodleMerge {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let from = query.get_first_value("from")?;
+		let into = query.get_first_value("into")?;
+
+		Ok(Self { from, into })
+	}
+}
+
+pub struct OodleRename {
+	pub filename: String,
+	/// The new title, if it's changing
+	pub title: Option<String>,
+	/// The new slug, if it's changing
+	pub slug: Option<String>,
+	/// The new filename, if it's changing
+	pub new_filename: Option<String>,
+}
+
+impl OodleRename {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let filename = query.get_first_value("filename")?;
+		let title = query.get_optional_value("title");
+		let slug = query.get_optional_value("slug");
+		let new_filename = query.get_optional_value("newFilename");
+
+		Ok(Self {
+			filename,
+			title,
+			slug,
+			new_filename,
+		})
+	}
+}
+
+pub struct OodleRenderer {
+	pub filename: String,
+	/// None to go back to the default renderer
+	pub renderer: Option<Renderer>,
+}
+
+impl OodleRenderer {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let filename = query.get_first_value("filename")?;
+		let renderer = match query.get_optional_value("renderer") {
+			Some(renderer) => Some(renderer.parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+			None => None,
+		};
+
+		Ok(Self { filename, renderer })
+	}
+}
+
+pub struct OodleTags {
+	pub filename: String,
+	/// Separated by spaces or commas in the form. Empty to remove them all.
+	pub tags: Vec<String>,
+}
+
+impl OodleTags {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let filename = query.get_first_value("filename")?;
+		let tags =
+			try_parse_tags(&query.get_first_value("tags")?).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+		Ok(Self { filename, tags })
+	}
+}
+
+/// Ending one of your sessions, by its [id](crate::database::Session::id).
+pub struct SessionRevoke {
+	pub id: String,
+}
+
+impl SessionRevoke {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let id = query.get_first_value("id")?;
+
+		Ok(Self { id })
+	}
+}
+
+/// A form naming a single oodle, for the endpoints that only need to know
+/// which one to act on.
+pub struct OodleFile {
+	pub filename: String,
+}
+
+impl OodleFile {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let filename = query.get_first_value("filename")?;
+
+		Ok(Self { filename })
+	}
+}
+
 #[derive(Debug, Deserialize)]
 pub struct MessageCreate {
 	pub filename: String,
@@ -63,23 +215,145 @@ pub struct MessageModify {
 	pub filename: String,
 	pub id: usize,
 	pub content: String,
+	/// Attachments to set the alt text and caption of
+	#[serde(default)]
+	pub attachments: Vec<Attachment>,
+	/// The message's new tags, if they're changing
+	#[serde(default)]
+	pub tags: Option<Vec<String>>,
 }
 
 impl MessageModify {
+	/// Attachments are numbered from 1 in the form, as `attachment-1` with
+	/// `alt-1` and `caption-1`, and read until a number is missing. Tags are
+	/// left alone unless there's a `tags` field, even an empty one.
 	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
 		let query = QueryWrapper::from_post_body(req).await?;
 		let filename = query.get_first_value("filename")?;
 		let id = query.parse_first_value("id")?;
 		let content = query.get_first_value("content")?;
 
+		let mut attachments = vec![];
+		for number in 1.. {
+			let name = match query.get_optional_value(format!("attachment-{}", number)) {
+				Some(name) => name,
+				None => break,
+			};
+
+			attachments.push(Attachment {
+				name,
+				alt: query.get_optional_value(format!("alt-{}", number)),
+				caption: query.get_optional_value(format!("caption-{}", number)),
+			});
+		}
+
+		let tags = match query.get_first_value("tags") {
+			Ok(tags) => Some(try_parse_tags(&tags).map_err(|_| StatusCode::BAD_REQUEST)?),
+			Err(_) => None,
+		};
+
 		Ok(Self {
 			filename,
 			id,
 			content,
+			attachments,
+			tags,
+		})
+	}
+
+	/// Refuse tags that have nothing left once they're normalized, like
+	/// [try_parse_tags] does for the form. JSON has to be checked after it's
+	/// read.
+	pub fn check_tags(&self) -> Result<(), StatusCode> {
+		self.tags
+			.iter()
+			.flatten()
+			.try_for_each(|tag| try_parse_tags(tag).map(drop))
+			.map_err(|_| StatusCode::BAD_REQUEST)
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageInsert {
+	pub filename: String,
+	/// The id of the message to insert after
+	pub after: usize,
+	pub content: String,
+}
+
+impl MessageInsert {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let filename = query.get_first_value("filename")?;
+		let after = query.parse_first_value("after")?;
+		let content = query.get_first_value("content")?;
+
+		Ok(Self {
+			filename,
+			after,
+			content,
 		})
 	}
 }
 
+pub struct MessageMove {
+	pub from: String,
+	pub id: usize,
+	pub to: String,
+}
+
+impl MessageMove {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let from = query.get_first_value("from")?;
+		let id = query.parse_first_value("id")?;
+		let to = query.get_first_value("to")?;
+
+		Ok(Self { from, id, to })
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageDelete {
+	pub filename: String,
+	pub id: usize,
+}
+
+impl MessageDelete {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let filename = query.get_first_value("filename")?;
+		let id = query.parse_first_value("id")?;
+
+		Ok(Self { filename, id })
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageRestore {
+	pub filename: String,
+	pub id: usize,
+	pub revision: usize,
+}
+
+impl MessageRestore {
+	pub async fn from_request(req: Request) -> Result<Self, StatusCode> {
+		let query = QueryWrapper::from_post_body(req).await?;
+		let filename = query.get_first_value("filename")?;
+		let id = query.parse_first_value("id")?;
+		let revision = query.parse_first_value("revision")?;
+
+		Ok(Self {
+			filename,
+			id,
+			revision,
+		})
+	}
+}
+
+/// The field forms send the session's CSRF token in.
+pub const CSRF_FIELD: &str = "csrf";
+
 pub struct QueryWrapper(Query);
 
 impl QueryWrapper {
@@ -107,6 +381,20 @@ impl QueryWrapper {
 			.map(<_>::to_owned)
 	}
 
+	/// Like [QueryWrapper::get_first_value], but a missing or empty value is
+	/// None rather than an error.
+	pub fn get_optional_value<S: AsRef<str>>(&self, key: S) -> Option<String> {
+		self.0
+			.get_first_value(key)
+			.filter(|value| !value.trim().is_empty())
+			.map(<_>::to_owned)
+	}
+
+	/// The CSRF token sent in the form, see [CSRF_FIELD].
+	pub fn csrf_token(&self) -> Option<String> {
+		self.get_optional_value(CSRF_FIELD)
+	}
+
 	pub fn parse_first_value<T: FromStr, S: AsRef<str>>(&self, key: S) -> Result<T, StatusCode> {
 		self.0
 			.parse_first_value(key)
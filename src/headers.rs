@@ -0,0 +1,168 @@
+use std::fmt;
+
+use serde::{ser::SerializeMap, Serialize};
+
+/// The `Key: value` lines between a message's dateline and its body, kept in
+/// the order they were written. Keys are compared case-insensitively.
+///
+/// In the file, a message's dateline ends with a `+` when it has headers, and
+/// they're ended by a blank line:
+/// ```text
+/// 2022-06-01 13:45:00-0500 +
+/// Content-Warning: spiders
+/// Reply-To: 3
+///
+/// The body of the message.
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn get<K: AsRef<str>>(&self, key: K) -> Option<&str> {
+		self.0
+			.iter()
+			.find(|(k, _)| k.eq_ignore_ascii_case(key.as_ref()))
+			.map(|(_, v)| v.as_str())
+	}
+
+	/// Set a header, keeping its position if it was already present. Values
+	/// can't span lines, so newlines are replaced with spaces.
+	///
+	/// Fails if the key is not a letter followed by letters, digits, and dashes.
+	pub fn insert<K: Into<String>, V: Into<String>>(
+		&mut self,
+		key: K,
+		value: V,
+	) -> Result<Option<String>, BadHeaderKey> {
+		let key = key.into();
+
+		if !Self::valid_key(&key) {
+			return Err(BadHeaderKey(key));
+		}
+
+		Ok(self.set(key, value))
+	}
+
+	/// [Headers::insert] for keys we know are good, like our own constants and
+	/// ones we've just parsed.
+	pub(crate) fn set<K: Into<String>, V: Into<String>>(
+		&mut self,
+		key: K,
+		value: V,
+	) -> Option<String> {
+		let key = key.into();
+		let value = value.into().lines().collect::<Vec<_>>().join(" ");
+
+		debug_assert!(Self::valid_key(&key), "invalid header key {:?}", key);
+
+		match self
+			.0
+			.iter_mut()
+			.find(|(k, _)| k.eq_ignore_ascii_case(&key))
+		{
+			Some((_, v)) => Some(std::mem::replace(v, value)),
+			None => {
+				self.0.push((key, value));
+				None
+			}
+		}
+	}
+
+	pub fn remove<K: AsRef<str>>(&mut self, key: K) -> Option<String> {
+		let idx = self
+			.0
+			.iter()
+			.position(|(k, _)| k.eq_ignore_ascii_case(key.as_ref()))?;
+
+		Some(self.0.remove(idx).1)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+		self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+	}
+
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	pub fn valid_key(key: &str) -> bool {
+		let mut chars = key.chars();
+
+		match chars.next() {
+			Some(c) if c.is_ascii_alphabetic() => {
+				chars.all(|c| c.is_ascii_alphanumeric() || c == '-')
+			}
+			_ => false,
+		}
+	}
+
+	/// Parse a single `Key: value` line.
+	pub(crate) fn parse_line(line: &str) -> Option<(&str, &str)> {
+		let (key, value) = line.split_once(": ")?;
+
+		if Self::valid_key(key) {
+			Some((key, value.trim()))
+		} else {
+			None
+		}
+	}
+
+	/// Whether these lines, up to the first blank one, would be read as headers.
+	/// There has to be a blank line ending them.
+	///
+	/// Only used for an oodle's headers, after its title. A message marks its
+	/// headers on its dateline instead, see [Headers::DATELINE_MARK].
+	pub(crate) fn is_header_block<'a, I: IntoIterator<Item = &'a str>>(lines: I) -> bool {
+		let mut any = false;
+
+		for line in lines {
+			if line.trim().is_empty() {
+				return any;
+			} else if Self::parse_line(line).is_none() {
+				return false;
+			}
+
+			any = true;
+		}
+
+		false
+	}
+
+	/// Put at the end of a message's dateline when headers follow it. Files
+	/// from before headers never have it, so a body there that happens to look
+	/// like headers is still read as a body.
+	pub(crate) const DATELINE_MARK: char = '+';
+}
+
+impl Serialize for Headers {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: serde::Serializer,
+	{
+		let mut map = serializer.serialize_map(Some(self.0.len()))?;
+		for (key, value) in &self.0 {
+			map.serialize_entry(key, value)?;
+		}
+		map.end()
+	}
+}
+
+/// A header key that isn't a letter followed by letters, digits, and dashes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BadHeaderKey(pub String);
+
+impl fmt::Display for BadHeaderKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "invalid header key {:?}", self.0)
+	}
+}
+
+impl std::error::Error for BadHeaderKey {}
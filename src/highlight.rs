@@ -0,0 +1,102 @@
+use once_cell::sync::Lazy;
+use syntect::{
+	html::{ClassStyle, ClassedHTMLGenerator},
+	parsing::SyntaxSet,
+	util::LinesWithEndings,
+};
+
+use crate::Html;
+
+/// Put before the name of every CSS class given to highlighted code, so a
+/// theme can style `.hl-keyword` or `.hl-comment` without clashing with the
+/// rest of the page.
+pub const HIGHLIGHT_CLASS_PREFIX: &str = "hl-";
+
+static SYNTAXES: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+
+/// Render a fenced code block, highlighted if we know the language. The code
+/// is kept exactly as written, in a `<pre>`, so its whitespace survives.
+pub(crate) fn code_block(code: &str, language: Option<&str>) -> String {
+	let language = language.map(str::trim).filter(|lang| !lang.is_empty());
+	let syntax = language.and_then(|lang| SYNTAXES.find_syntax_by_token(lang));
+
+	let highlighted = match syntax {
+		None => None,
+		Some(syntax) => {
+			let mut generator = ClassedHTMLGenerator::new_with_class_style(
+				syntax,
+				&SYNTAXES,
+				ClassStyle::SpacedPrefixed {
+					prefix: HIGHLIGHT_CLASS_PREFIX,
+				},
+			);
+
+			LinesWithEndings::from(code)
+				.try_for_each(|line| generator.parse_html_for_line_which_includes_newline(line))
+				.ok()
+				.map(|_| generator.finalize())
+		}
+	};
+
+	let code = highlighted.unwrap_or_else(|| Html::escape(code).into_string());
+	match language {
+		Some(lang) => format!(
+			"<pre class=\"code\"><code class=\"language-{}\">{}</code></pre>",
+			Html::escape(lang),
+			code
+		),
+		None => format!("<pre class=\"code\"><code>{}</code></pre>", code),
+	}
+}
+
+/// A piece of a plain text message: either text, or a fenced code block with
+/// the language from its opening fence.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Block<'a> {
+	Text(&'a str),
+	Code(&'a str, Option<&'a str>),
+}
+
+/// Split plain text on its ```` ``` ```` fences. A fence that's never closed
+/// runs to the end of the text.
+pub(crate) fn fenced_blocks(content: &str) -> Vec<Block<'_>> {
+	let mut blocks = vec![];
+	let mut start = 0;
+	// Where the open fence's code starts, and its language
+	let mut fence: Option<(usize, Option<&str>)> = None;
+
+	let mut offset = 0;
+	for line in content.split_inclusive('\n') {
+		let line_start = offset;
+		offset += line.len();
+
+		let trimmed = line.trim_end();
+		if !trimmed.starts_with("```") {
+			continue;
+		}
+
+		match fence {
+			None => {
+				blocks.push(Block::Text(&content[start..line_start]));
+
+				let language = Some(trimmed[3..].trim()).filter(|lang| !lang.is_empty());
+				fence = Some((offset, language));
+			}
+			Some((code_start, language)) if trimmed == "```" => {
+				blocks.push(Block::Code(&content[code_start..line_start], language));
+
+				fence = None;
+				start = offset;
+			}
+			Some(_) => (),
+		}
+	}
+
+	match fence {
+		Some((code_start, language)) => blocks.push(Block::Code(&content[code_start..], language)),
+		None => blocks.push(Block::Text(&content[start..])),
+	}
+
+	blocks.retain(|block| !matches!(block, Block::Text(text) if text.trim().is_empty()));
+	blocks
+}
@@ -1,18 +1,65 @@
 use std::{
 	fmt,
+	io::SeekFrom,
 	path::{Path, PathBuf},
 	str::FromStr,
 };
 
 use serde::{ser::SerializeStruct, Serialize};
 use time::{format_description::FormatItem, macros::format_description, OffsetDateTime, UtcOffset};
-use tokio::{fs::File, io::AsyncWriteExt};
+use tokio::{
+	fs::{File, OpenOptions},
+	io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+mod attachment;
+mod error;
+mod filename;
+mod headers;
+mod highlight;
+mod render;
+mod revision;
+mod search;
+mod tag;
+#[cfg(test)]
+mod test_dir;
+
+pub use attachment::{
+	is_image, make_thumbnail, strip_metadata, thumbnail_name, Attachment, THUMBNAIL_DIRECTORY,
+	THUMBNAIL_SIZE,
+};
+pub use filename::{
+	filename_from_title, oodle_filename, sanitize_filename, slugify, valid_filename, valid_slug,
+	OODLE_EXTENSION,
+};
+pub use headers::{BadHeaderKey, Headers};
+pub use highlight::HIGHLIGHT_CLASS_PREFIX;
+pub use render::{Html, Renderer, UnknownRenderer};
+pub use revision::{Revision, REVISIONS_EXTENSION};
+pub use search::{Document, SearchHit, SearchIndex};
+pub use tag::{
+	normalize_tag, parse_tags, try_parse_tags, url_encode_tag, valid_tag, BadTag, TagIndex,
+};
+
+pub use error::{
+	OodleEditError, OodleParseError, OodleParseErrorKind, OodleReadError, OodleSaveError,
+	ParseWarning, ParseWarningKind, SaveStep,
+};
+
+/// `(old, new)` pairs of message ids, from when messages are renumbered.
+pub type Renumbered = Vec<(usize, usize)>;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Oodle {
 	pub name: String,
 	pub file: PathBuf,
+	/// `Key: value` lines after the title, like a message's [Headers]
+	pub headers: Headers,
 	pub messages: Vec<Message>,
+	/// Set when messages were skipped reading the file leniently. Saving would
+	/// lose them, and appending would number from around them, so both are
+	/// refused until the file is fixed by hand.
+	incomplete: bool,
 }
 
 impl Oodle {
@@ -24,10 +71,134 @@ impl Oodle {
 		Self {
 			name: name.into(),
 			file: file.into(),
+			headers: Headers::new(),
 			messages: vec![first_message],
+			incomplete: false,
+		}
+	}
+
+	/// Whether messages were skipped when this oodle was read, see
+	/// [ParseMode::Lenient]. An incomplete oodle can be read, but won't be
+	/// saved or appended to.
+	pub fn is_incomplete(&self) -> bool {
+		self.incomplete
+	}
+
+	/// The header marking an oodle as archived, hiding it from the index.
+	pub const ARCHIVED_HEADER: &'static str = "Archived";
+
+	pub fn is_archived(&self) -> bool {
+		self.headers.get(Self::ARCHIVED_HEADER).is_some()
+	}
+
+	pub fn set_archived(&mut self, when: Option<OffsetDateTime>) {
+		match when {
+			Some(when) => {
+				self.headers
+					.set(Self::ARCHIVED_HEADER, Message::format_date(when));
+			}
+			None => {
+				self.headers.remove(Self::ARCHIVED_HEADER);
+			}
 		}
 	}
 
+	/// The header holding the slug an oodle is found by in URLs.
+	pub const SLUG_HEADER: &'static str = "Slug";
+	/// The header holding slugs the oodle used to have, separated by spaces,
+	/// so old links can be sent to the current one.
+	pub const OLD_SLUGS_HEADER: &'static str = "Old-Slugs";
+
+	pub fn slug(&self) -> Option<&str> {
+		self.headers.get(Self::SLUG_HEADER)
+	}
+
+	pub fn old_slugs(&self) -> impl Iterator<Item = &str> {
+		self.headers
+			.get(Self::OLD_SLUGS_HEADER)
+			.unwrap_or_default()
+			.split_whitespace()
+	}
+
+	/// Whether the oodle is, or was, found at this slug.
+	pub fn has_slug(&self, slug: &str) -> bool {
+		self.slug() == Some(slug) || self.old_slugs().any(|old| old == slug)
+	}
+
+	/// Give the oodle a new slug, keeping the current one as an old slug.
+	pub fn set_slug<S: Into<String>>(&mut self, slug: S) {
+		let slug = slug.into();
+		let previous = self.headers.set(Self::SLUG_HEADER, slug.clone());
+
+		let mut old: Vec<String> = self
+			.old_slugs()
+			.filter(|&old| old != slug)
+			.map(<_>::to_owned)
+			.collect();
+
+		if let Some(previous) = previous.filter(|p| *p != slug && !old.contains(p)) {
+			old.push(previous);
+		}
+
+		self.set_old_slugs(old);
+	}
+
+	/// Keep these slugs as ones the oodle used to have, along with its own.
+	pub fn add_old_slugs<I: IntoIterator<Item = String>>(&mut self, slugs: I) {
+		let mut old: Vec<String> = self.old_slugs().map(<_>::to_owned).collect();
+		for slug in slugs {
+			if !old.contains(&slug) && self.slug() != Some(&slug) {
+				old.push(slug);
+			}
+		}
+
+		self.set_old_slugs(old);
+	}
+
+	fn set_old_slugs(&mut self, old: Vec<String>) {
+		if old.is_empty() {
+			self.headers.remove(Self::OLD_SLUGS_HEADER);
+		} else {
+			self.headers.set(Self::OLD_SLUGS_HEADER, old.join(" "));
+		}
+	}
+
+	/// The header choosing how this oodle's messages are rendered, overriding
+	/// the default.
+	pub const RENDERER_HEADER: &'static str = "Renderer";
+
+	/// The [Renderer] chosen for this oodle, if there is one we know.
+	pub fn renderer(&self) -> Option<Renderer> {
+		self.headers.get(Self::RENDERER_HEADER)?.parse().ok()
+	}
+
+	pub fn set_renderer(&mut self, renderer: Option<Renderer>) {
+		match renderer {
+			Some(renderer) => {
+				self.headers
+					.set(Self::RENDERER_HEADER, renderer.to_string());
+			}
+			None => {
+				self.headers.remove(Self::RENDERER_HEADER);
+			}
+		}
+	}
+
+	/// The header holding an oodle's tags, separated by spaces. Messages have
+	/// their own, see [Message::tags].
+	pub const TAGS_HEADER: &'static str = "Tags";
+
+	/// The oodle's tags, [normalized](normalize_tag) however they were written.
+	pub fn tags(&self) -> Vec<String> {
+		tag::header_tags(&self.headers, Self::TAGS_HEADER)
+	}
+
+	/// Replace the oodle's tags. They're [normalized](normalize_tag), and if
+	/// there are none left the header is removed.
+	pub fn set_tags<S: AsRef<str>>(&mut self, tags: &[S]) {
+		tag::set_header_tags(&mut self.headers, Self::TAGS_HEADER, tags);
+	}
+
 	pub fn push_message(&mut self, mut msg: Message) -> usize {
 		let idx = self.messages.last().map(|m| m.id + 1).unwrap_or(0);
 
@@ -48,6 +219,92 @@ impl Oodle {
 		id
 	}
 
+	/// Push a message and write it to the end of the oodle's file, rather than
+	/// rewriting the whole thing with [Oodle::save]. Edits still need a save.
+	///
+	/// If the append fails the message is removed again, so the oodle in memory
+	/// doesn't get ahead of the one on disk. A file that doesn't end with a
+	/// message's `.` line, like one edited by hand, is saved whole instead so
+	/// the new block can't run into whatever is at the end. Like saving, it's
+	/// refused for an [incomplete](Oodle::is_incomplete) oodle.
+	pub async fn append_message(&mut self, msg: Message) -> Result<usize, OodleSaveError> {
+		self.check_complete()?;
+		let id = self.push_message(msg);
+
+		let written = match Self::ends_with_terminator(&self.file).await {
+			Ok(true) => {
+				let block = {
+					let (msg, rest) = self.messages.split_last().unwrap();
+					MessageBlock {
+						msg,
+						previous: rest.last(),
+					}
+					.to_string()
+				};
+
+				Self::append_to(&self.file, block.as_bytes(), false).await
+			}
+			Ok(false) => self.save().await,
+			Err(e) => Err(e),
+		};
+
+		if let Err(e) = written {
+			self.messages.pop();
+			return Err(e);
+		}
+
+		Ok(id)
+	}
+
+	/// Whether the file ends with the `.` line closing a message, and a newline.
+	async fn ends_with_terminator(path: &Path) -> Result<bool, OodleSaveError> {
+		let mut file = File::open(path)
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::Open, e))?;
+
+		// Too short to seek back that far is as good as not ending right
+		if file.seek(SeekFrom::End(-3)).await.is_err() {
+			return Ok(false);
+		}
+
+		let mut tail = [0; 3];
+		match file.read_exact(&mut tail).await {
+			Ok(_) => Ok(&tail == b"\n.\n"),
+			Err(_) => Ok(false),
+		}
+	}
+
+	/// Write data to the end of a file. If that fails partway, the file is cut
+	/// back to the length it had so it isn't left with half a block.
+	async fn append_to(path: &Path, data: &[u8], create: bool) -> Result<(), OodleSaveError> {
+		let mut file = OpenOptions::new()
+			.append(true)
+			.create(create)
+			.open(path)
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::Open, e))?;
+
+		let length = file
+			.metadata()
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::Open, e))?
+			.len();
+
+		let written = match file.write_all(data).await {
+			Ok(()) => file
+				.sync_all()
+				.await
+				.map_err(|e| OodleSaveError::new(SaveStep::SyncFile, e)),
+			Err(e) => Err(OodleSaveError::new(SaveStep::Write, e)),
+		};
+
+		if written.is_err() {
+			let _ = file.set_len(length).await;
+		}
+
+		written
+	}
+
 	pub fn message(&self, index: usize) -> Option<&Message> {
 		self.messages.iter().find(|msg| msg.id == index)
 	}
@@ -56,99 +313,690 @@ impl Oodle {
 		self.messages.iter_mut().find(|msg| msg.id == index)
 	}
 
-	pub async fn save(&self) -> Result<(), std::io::Error> {
-		let mut file = File::create(&self.file).await?;
-		file.write(format!("{}", self).as_bytes()).await.map(|_| ())
+	/// Take a message out of the oodle. Unlike [Oodle::delete_message], this
+	/// only changes the oodle in memory and leaves nothing behind.
+	pub fn remove_message(&mut self, id: usize) -> Option<Message> {
+		let position = self.messages.iter().position(|msg| msg.id == id)?;
+		Some(self.messages.remove(position))
 	}
 
-	pub async fn read<P: AsRef<Path>>(path: P) -> Result<Oodle, std::io::Error> {
-		let mut oodle: Oodle = std::fs::read_to_string(path.as_ref())?.parse().unwrap();
-		oodle.file = path.as_ref().to_owned();
-		Ok(oodle)
+	/// Split the messages from the id `at` onward off into a new oodle, where
+	/// they're numbered from 0. Returns the new oodle along with the `(old, new)`
+	/// id of every message that moved.
+	///
+	/// Returns None if there's no message `at`, if it's the first message and
+	/// this oodle would be left empty, or if every message from `at` onward is
+	/// deleted and the new oodle would be.
+	pub fn split_off<N: Into<String>, P: Into<PathBuf>>(
+		&mut self,
+		at: usize,
+		name: N,
+		file: P,
+	) -> Option<(Oodle, Vec<(usize, usize)>)> {
+		let position = self.messages.iter().position(|msg| msg.id == at)?;
+		if position == 0 || self.messages[position..].iter().all(Message::is_deleted) {
+			return None;
+		}
+
+		let mut moved = vec![];
+		let messages = self
+			.messages
+			.split_off(position)
+			.into_iter()
+			.filter(|msg| !msg.is_deleted())
+			.enumerate()
+			.map(|(id, mut msg)| {
+				moved.push((msg.id, id));
+				msg.id = id;
+				msg
+			})
+			.collect();
+
+		let oodle = Oodle {
+			name: name.into(),
+			file: file.into(),
+			headers: Headers::new(),
+			messages,
+			incomplete: false,
+		};
+
+		Some((oodle, moved))
 	}
 
-	pub fn date(&self) -> Option<OffsetDateTime> {
-		self.messages.first().map(|m| m.date)
+	/// Merge another oodle's messages into this one, ordered by date. Every
+	/// message is renumbered from 0 and deleted messages are dropped. Returns the
+	/// `(old, new)` ids of this oodle's messages and then the other's.
+	pub fn merge(&mut self, other: Oodle) -> (Renumbered, Renumbered) {
+		let mut messages: Vec<(bool, Message)> = std::mem::take(&mut self.messages)
+			.into_iter()
+			.map(|msg| (false, msg))
+			.chain(other.messages.into_iter().map(|msg| (true, msg)))
+			.filter(|(_, msg)| !msg.is_deleted())
+			.collect();
+
+		// Stable, so messages at the same time keep the order they were in
+		messages.sort_by_key(|(_, msg)| msg.date);
+
+		let mut ours = vec![];
+		let mut theirs = vec![];
+		for (id, (from_other, mut msg)) in messages.into_iter().enumerate() {
+			if from_other {
+				theirs.push((msg.id, id));
+			} else {
+				ours.push((msg.id, id));
+			}
+
+			msg.id = id;
+			self.messages.push(msg);
+		}
+
+		(ours, theirs)
 	}
 
-	fn extract_title(s: &str) -> Option<String> {
-		if let Some(s) = s.strip_prefix("-=") {
-			if let Some(title) = s.strip_suffix("=-") {
-				return Some(title.trim().to_owned());
+	/// Replace a message's content and mark it as edited at `now`. The old
+	/// content is kept as a [Revision] in the file at [Oodle::revisions_path].
+	///
+	/// The alt text and captions of the given attachments are set too, see
+	/// [Message::set_attachment_text]. Attachments that aren't listed keep
+	/// theirs. The message's tags are replaced if `tags` is given. If the
+	/// content is the same, only those are set, without a revision or marking
+	/// the message edited.
+	pub async fn edit_message(
+		&mut self,
+		id: usize,
+		content: String,
+		attachments: &[Attachment],
+		tags: Option<&[String]>,
+		now: OffsetDateTime,
+	) -> Result<&Message, OodleEditError> {
+		let msg = self
+			.message(id)
+			.filter(|msg| !msg.is_deleted())
+			.ok_or(OodleEditError::NoSuchMessage(id))?;
+
+		if let Some(missing) = attachments
+			.iter()
+			.find(|attachment| !msg.attachments().any(|name| name == attachment.name))
+		{
+			return Err(OodleEditError::NoSuchAttachment(missing.name.clone()));
+		}
+
+		let edited = msg.content != content;
+		if edited {
+			let revision = Revision::of(msg).to_block();
+			Self::append_to(&self.revisions_path(), revision.as_bytes(), true).await?;
+		}
+
+		let msg = self.message_mut(id).unwrap();
+		let previous = msg.clone();
+		if edited {
+			msg.content = content;
+			msg.set_edited(now);
+		}
+
+		for attachment in attachments {
+			msg.set_attachment_text(
+				&attachment.name,
+				attachment.alt.as_deref(),
+				attachment.caption.as_deref(),
+			);
+		}
+
+		if let Some(tags) = tags {
+			msg.set_tags(tags);
+		}
+
+		if let Err(e) = self.save().await {
+			*self.message_mut(id).unwrap() = previous;
+			return Err(e.into());
+		}
+
+		Ok(self.message(id).unwrap())
+	}
+
+	/// Delete a message. Its content is kept as a [Revision].
+	///
+	/// Messages after it keep their ids, they're written with an explicit
+	/// index. The last message is left as a tombstone instead, so that its id
+	/// isn't handed out again to the next message.
+	pub async fn delete_message(
+		&mut self,
+		id: usize,
+		now: OffsetDateTime,
+	) -> Result<(), OodleEditError> {
+		let position = self
+			.messages
+			.iter()
+			.position(|msg| msg.id == id && !msg.is_deleted())
+			.ok_or(OodleEditError::NoSuchMessage(id))?;
+
+		let revision = Revision::of(&self.messages[position]).to_block();
+		Self::append_to(&self.revisions_path(), revision.as_bytes(), true).await?;
+
+		let previous = self.messages.clone();
+		if position == self.messages.len() - 1 {
+			let msg = &mut self.messages[position];
+			msg.content.clear();
+			msg.headers
+				.set(Message::DELETED_HEADER, Message::format_date(now));
+		} else {
+			self.messages.remove(position);
+		}
+
+		if let Err(e) = self.save().await {
+			self.messages = previous;
+			return Err(e.into());
+		}
+
+		Ok(())
+	}
+
+	/// Insert a message right after the message with id `after`, for things
+	/// that were forgotten mid-stream. Returns the new message's id.
+	///
+	/// The message takes the id after `after` if it's free. If not, the
+	/// messages following are moved up by one until there's a gap, and their
+	/// revisions move with them. An id is only free if no deleted message left
+	/// revisions under it, so the new message doesn't pick them up. The
+	/// message's date is clamped between its
+	/// neighbours' so messages stay in order. When that changes the date, the
+	/// real one is kept in the [Message::INSERTED_HEADER].
+	pub async fn insert_message_after(
+		&mut self,
+		after: usize,
+		mut msg: Message,
+	) -> Result<usize, OodleEditError> {
+		let position = self
+			.messages
+			.iter()
+			.position(|m| m.id == after)
+			.ok_or(OodleEditError::NoSuchMessage(after))?;
+		let id = after + 1;
+
+		// The run of ids directly after `after` that are taken, either by a
+		// message or by the revisions of one that was deleted
+		let revised: Vec<usize> = self
+			.all_revisions()
+			.await?
+			.into_iter()
+			.map(|rev| rev.message_id)
+			.collect();
+		let run = (id..)
+			.take_while(|id| revised.contains(id) || self.message(*id).is_some())
+			.count();
+
+		let written = msg.date;
+		msg.id = id;
+		msg.date = msg.date.max(self.messages[position].date);
+		if let Some(next) = self.messages.get(position + 1) {
+			msg.date = msg.date.min(next.date);
+		}
+
+		if msg.date != written {
+			msg.headers
+				.set(Message::INSERTED_HEADER, Message::format_date(written));
+		}
+
+		let moved: Vec<(usize, usize)> = (id..id + run).map(|id| (id, id + 1)).collect();
+		self.renumber_revisions(&moved).await?;
+
+		let previous = self.messages.clone();
+		for m in &mut self.messages[position + 1..] {
+			if m.id < id + run {
+				m.id += 1;
 			}
 		}
+		self.messages.insert(position + 1, msg);
+
+		if let Err(e) = self.save().await {
+			self.messages = previous;
+			// Put the revisions back too. If this fails as well there's not much
+			// else we can do.
+			let back: Vec<(usize, usize)> = moved.iter().map(|(old, new)| (*new, *old)).collect();
+			let _ = self.renumber_revisions(&back).await;
+			return Err(e.into());
+		}
 
-		None
+		Ok(id)
 	}
-}
 
-impl fmt::Display for Oodle {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "-= {} =-\n", self.name)?;
+	/// Move revisions to different message ids after messages have been
+	/// renumbered. `changes` are `(old, new)` id pairs, all applied at once.
+	pub async fn renumber_revisions(
+		&self,
+		changes: &[(usize, usize)],
+	) -> Result<(), OodleEditError> {
+		let mut revisions = self.all_revisions().await?;
+		if revisions.is_empty() || changes.is_empty() {
+			return Ok(());
+		}
 
-		let mut idx = 0;
-		for msg in &self.messages {
-			// Weird indexes are fixed on write, so we don't have to check low/high here.
-			write!(f, "\n")?;
-			if idx != msg.id {
-				idx = msg.id;
-				msg.fmt_with_idx(f)?;
-			} else {
-				write!(f, "{}", msg)?;
+		for revision in &mut revisions {
+			if let Some((_, new)) = changes.iter().find(|(old, _)| *old == revision.message_id) {
+				revision.message_id = *new;
 			}
-			write!(f, ".\n")?;
+		}
+
+		self.save_revisions(&revisions).await?;
+		Ok(())
+	}
+
+	/// Replace every revision in this oodle's revisions file, like when putting
+	/// it back after something failed.
+	pub async fn save_revisions(&self, revisions: &[Revision]) -> Result<(), OodleSaveError> {
+		// Don't leave an empty file behind if there wasn't one
+		if revisions.is_empty() && tokio::fs::metadata(self.revisions_path()).await.is_err() {
+			return Ok(());
+		}
+
+		let data: String = revisions.iter().map(Revision::to_block).collect();
+		Self::write_atomic(&self.revisions_path(), data.as_bytes()).await
+	}
+
+	/// Add revisions to this oodle's revisions file as belonging to the message
+	/// `id`, like when a message is moved here from another oodle.
+	pub async fn add_revisions(
+		&self,
+		id: usize,
+		revisions: Vec<Revision>,
+	) -> Result<(), OodleSaveError> {
+		if revisions.is_empty() {
+			return Ok(());
+		}
 
-			idx += 1;
+		let data: String = revisions
+			.into_iter()
+			.map(|rev| Revision {
+				message_id: id,
+				..rev
+			})
+			.map(|rev| rev.to_block())
+			.collect();
+
+		Self::append_to(&self.revisions_path(), data.as_bytes(), true).await
+	}
+
+	/// Every previous version of a message, oldest first. A revision's number
+	/// is its position in this list.
+	pub async fn revisions(&self, id: usize) -> Result<Vec<Revision>, OodleReadError> {
+		Ok(self
+			.all_revisions()
+			.await?
+			.into_iter()
+			.filter(|rev| rev.message_id == id)
+			.collect())
+	}
+
+	/// The revisions of every message, oldest first.
+	pub async fn all_revisions(&self) -> Result<Vec<Revision>, OodleReadError> {
+		match tokio::fs::read_to_string(self.revisions_path()).await {
+			Ok(string) => Ok(Revision::parse_all(&string)?),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Put a message back the way it was in one of its [Oodle::revisions],
+	/// with the alt text and captions its attachments had then. Ones attached
+	/// since keep theirs. This is an edit like any other, so the current
+	/// content becomes a revision.
+	pub async fn restore_revision(
+		&mut self,
+		id: usize,
+		revision: usize,
+		now: OffsetDateTime,
+	) -> Result<&Message, OodleEditError> {
+		let msg = self.message(id).ok_or(OodleEditError::NoSuchMessage(id))?;
+
+		let revision = self
+			.revisions(id)
+			.await?
+			.into_iter()
+			.nth(revision)
+			.ok_or(OodleEditError::NoSuchRevision(revision))?;
+
+		let attachments: Vec<Attachment> = revision
+			.attachments
+			.into_iter()
+			.filter(|attachment| msg.attachments().any(|name| name == attachment.name))
+			.collect();
+
+		self.edit_message(id, revision.content, &attachments, None, now)
+			.await
+	}
+
+	/// The file previous versions of edited messages are kept in.
+	pub fn revisions_path(&self) -> PathBuf {
+		let mut name = self.file.file_name().unwrap_or_default().to_owned();
+		name.push(".");
+		name.push(REVISIONS_EXTENSION);
+
+		self.file.with_file_name(name)
+	}
+
+	/// Write the oodle to disk. The oodle is written to a temporary file in the
+	/// same directory and renamed over the old one, so a crash partway through
+	/// leaves either the old file or the new one, never a truncated one.
+	pub async fn save(&self) -> Result<(), OodleSaveError> {
+		self.check_complete()?;
+		Self::write_atomic(&self.file, self.to_string().as_bytes()).await
+	}
+
+	/// Refuse to write an [incomplete](Oodle::is_incomplete) oodle.
+	fn check_complete(&self) -> Result<(), OodleSaveError> {
+		if self.incomplete {
+			return Err(OodleSaveError::new(
+				SaveStep::Check,
+				std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					"messages were skipped reading it, fix them by hand first",
+				),
+			));
 		}
 
 		Ok(())
 	}
-}
 
-impl FromStr for Oodle {
-	//TODO: gen- real error
-	type Err = ();
-
-	fn from_str(mut s: &str) -> Result<Self, Self::Err> {
-		let title = match s.find("\n\n") {
-			Some(idx) => match Self::extract_title(&s[..idx]) {
-				Some(title) => {
-					s = &s[idx + 2..];
-					title
+	/// Replace the file at `path` with `data` the way [Oodle::save] does.
+	async fn write_atomic(path: &Path, data: &[u8]) -> Result<(), OodleSaveError> {
+		let temp = Self::temp_path(path);
+
+		if let Err(e) = Self::write_temp(&temp, data).await {
+			// We're already failing, the temp file is just litter now.
+			let _ = tokio::fs::remove_file(&temp).await;
+			return Err(e);
+		}
+
+		tokio::fs::rename(&temp, path)
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::Rename, e))?;
+
+		Self::sync_directory(path).await
+	}
+
+	async fn write_temp(temp: &Path, data: &[u8]) -> Result<(), OodleSaveError> {
+		let mut file = File::create(temp)
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::CreateTemp, e))?;
+
+		file.write_all(data)
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::Write, e))?;
+
+		file.sync_all()
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::SyncFile, e))
+	}
+
+	/// The temporary file used by [Oodle::write_atomic]. It's hidden so that a
+	/// leftover one is never loaded as an oodle.
+	fn temp_path(path: &Path) -> PathBuf {
+		let mut name = std::ffi::OsString::from(".");
+		name.push(path.file_name().unwrap_or_default());
+		name.push(".tmp");
+
+		path.with_file_name(name)
+	}
+
+	/// Sync the directory containing `file` so that a rename into it survives
+	/// a crash.
+	#[cfg(unix)]
+	async fn sync_directory(file: &Path) -> Result<(), OodleSaveError> {
+		let directory = match file.parent() {
+			Some(parent) if !parent.as_os_str().is_empty() => parent,
+			_ => Path::new("."),
+		};
+
+		File::open(directory)
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::SyncDirectory, e))?
+			.sync_all()
+			.await
+			.map_err(|e| OodleSaveError::new(SaveStep::SyncDirectory, e))
+	}
+
+	/// Directories can't be opened, let alone synced, on other platforms.
+	#[cfg(not(unix))]
+	async fn sync_directory(_file: &Path) -> Result<(), OodleSaveError> {
+		Ok(())
+	}
+
+	pub async fn read<P: AsRef<Path>>(path: P) -> Result<Oodle, OodleReadError> {
+		Self::read_with(path, ParseMode::Strict)
+			.await
+			.map(|(oodle, _)| oodle)
+	}
+
+	/// Read an oodle from disk, parsing it with the given [ParseMode]. Any
+	/// problems recovered from in [ParseMode::Lenient] are returned alongside it.
+	pub async fn read_with<P: AsRef<Path>>(
+		path: P,
+		mode: ParseMode,
+	) -> Result<(Oodle, Vec<ParseWarning>), OodleReadError> {
+		let string = std::fs::read_to_string(path.as_ref())?;
+		let (mut oodle, warnings) = Self::parse_with(&string, mode)?;
+		oodle.file = path.as_ref().to_owned();
+		Ok((oodle, warnings))
+	}
+
+	/// Parse an oodle with the given [ParseMode]. [FromStr] parses strictly.
+	///
+	/// In lenient mode a leading byte order mark, CRLF line endings, trailing
+	/// whitespace on the title and terminator lines, and a missing blank line
+	/// after the title are normalized. Messages that fail to parse are skipped.
+	/// Each of these is reported as a [ParseWarning].
+	pub fn parse_with(
+		mut s: &str,
+		mode: ParseMode,
+	) -> Result<(Self, Vec<ParseWarning>), OodleParseError> {
+		let lenient = mode == ParseMode::Lenient;
+		let mut warnings = vec![];
+
+		if lenient {
+			if let Some(stripped) = s.strip_prefix('\u{feff}') {
+				warnings.push(ParseWarning::new(1, ParseWarningKind::ByteOrderMark));
+				s = stripped;
+			}
+		}
+
+		let mut saw_carriage_return = false;
+		let mut lines = s
+			.split('\n')
+			.enumerate()
+			.map(|(idx, line)| (idx + 1, line))
+			.map(|(number, line)| match line.strip_suffix('\r') {
+				Some(stripped) if lenient => {
+					if !saw_carriage_return {
+						saw_carriage_return = true;
+						warnings.push(ParseWarning::new(number, ParseWarningKind::CarriageReturn));
+					}
+					(number, stripped)
 				}
-				None => {
-					//TODO:gen- title malformed
-					todo!()
+				_ => (number, line),
+			})
+			.collect::<Vec<_>>()
+			.into_iter();
+
+		let title = match lines.next() {
+			Some((number, mut line)) if line.starts_with("-=") => {
+				if lenient && line.trim_end() != line {
+					warnings.push(ParseWarning::new(
+						number,
+						ParseWarningKind::TrailingWhitespace,
+					));
+					line = line.trim_end();
 				}
-			},
-			None => {
-				//TODO:gen- No title was present.
-				todo!()
+
+				Self::extract_title(line).ok_or_else(|| {
+					OodleParseError::new(number, 1, OodleParseErrorKind::MalformedTitle, line)
+				})?
 			}
+			Some((number, line)) => {
+				return Err(OodleParseError::new(
+					number,
+					1,
+					OodleParseErrorKind::MissingTitle,
+					line,
+				))
+			}
+			None => unreachable!("split always yields at least one item"),
 		};
 
+		let rest: Vec<(usize, &str)> = lines.collect();
+		let mut start = 0;
+
+		let mut headers = Headers::new();
+		if Headers::is_header_block(rest.iter().map(|(_, line)| *line)) {
+			while let Some((key, value)) = Headers::parse_line(rest[start].1) {
+				headers.set(key, value);
+				start += 1;
+			}
+		}
+
+		// The title, and its headers, must be followed by a blank line
+		match rest.get(start) {
+			Some((_, line)) if line.trim().is_empty() => start += 1,
+			Some(&(number, _)) if lenient => {
+				warnings.push(ParseWarning::new(
+					number,
+					ParseWarningKind::MissingBlankLine,
+				));
+			}
+			Some(&(number, line)) => {
+				return Err(OodleParseError::new(
+					number,
+					1,
+					OodleParseErrorKind::MalformedTitle,
+					line,
+				));
+			}
+			None => (),
+		}
+
 		let mut oodles = Self {
 			name: title,
 			file: PathBuf::from("/tmp"),
+			headers,
 			messages: vec![],
+			incomplete: false,
 		};
 
-		loop {
-			match s.find("\n.\n") {
-				Some(string_idx) => {
-					let message: Message = s[..string_idx].trim().parse()?;
-					oodles.push_message(message);
-					s = &s[string_idx + 3..];
+		// Split into message blocks, each paired with the line it ended on
+		let mut blocks = vec![];
+		let mut block = vec![];
+		for &(number, line) in &rest[start..] {
+			if line == "." {
+				blocks.push((std::mem::take(&mut block), number));
+			} else if lenient && line.trim_end() == "." {
+				warnings.push(ParseWarning::new(
+					number,
+					ParseWarningKind::TrailingWhitespace,
+				));
+				blocks.push((std::mem::take(&mut block), number));
+			} else {
+				block.push((number, line));
+			}
+		}
+
+		if block.iter().any(|(_, line)| !line.trim().is_empty()) {
+			let end = block.last().map(|(number, _)| *number).unwrap_or(0);
+			blocks.push((block, end));
+		}
+
+		// The id the next message gets if it doesn't say. A skipped message
+		// still takes its id, so the ones after it keep theirs.
+		let mut next_id = 0;
+		for (block, end) in blocks {
+			match Message::parse_lines(&block, end) {
+				Ok(mut message) => {
+					message.id = message.id.max(next_id);
+					next_id = oodles.push_message(message) + 1;
+				}
+				Err(e) if lenient => {
+					next_id = Message::declared_id(&block).unwrap_or(next_id).max(next_id) + 1;
+					oodles.incomplete = true;
+					warnings.push(ParseWarning::new(
+						e.line,
+						ParseWarningKind::SkippedMessage(e),
+					));
 				}
-				None => break,
+				Err(e) => return Err(e),
+			}
+		}
+
+		Ok((oodles, warnings))
+	}
+
+	pub fn date(&self) -> Option<OffsetDateTime> {
+		self.messages.first().map(|m| m.date)
+	}
+
+	fn extract_title(s: &str) -> Option<String> {
+		if let Some(s) = s.strip_prefix("-=") {
+			if let Some(title) = s.strip_suffix("=-") {
+				return Some(title.trim().to_owned());
 			}
 		}
 
-		if !s.trim().is_empty() {
-			oodles.push_message(s.trim().parse()?);
+		None
+	}
+}
+
+impl fmt::Display for Oodle {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "-= {} =-\n", self.name)?;
+
+		for (key, value) in self.headers.iter() {
+			write!(f, "{}: {}\n", key, value)?;
+		}
+
+		let mut previous = None;
+		for msg in &self.messages {
+			write!(f, "{}", MessageBlock { msg, previous })?;
+			previous = Some(msg);
+		}
+
+		Ok(())
+	}
+}
+
+/// A message as it appears in an oodle file: preceded by a blank line and
+/// followed by the terminator.
+struct MessageBlock<'a> {
+	msg: &'a Message,
+	previous: Option<&'a Message>,
+}
+
+impl fmt::Display for MessageBlock<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		// Weird indexes are fixed on write, so we don't have to check low/high here.
+		let idx = self.previous.map(|m| m.id + 1).unwrap_or(0);
+
+		write!(f, "\n")?;
+		if idx != self.msg.id {
+			self.msg.fmt_with_idx(f)?;
+		} else {
+			write!(f, "{}", self.msg)?;
 		}
+		write!(f, ".\n")
+	}
+}
+
+/// How strictly to parse an oodle. See [Oodle::parse_with].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+	/// Fail on the first problem
+	#[default]
+	Strict,
+	/// Normalize what we can, skip what we can't, and warn about both
+	Lenient,
+}
+
+impl FromStr for Oodle {
+	type Err = OodleParseError;
 
-		Ok(oodles)
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::parse_with(s, ParseMode::Strict).map(|(oodle, _)| oodle)
 	}
 }
 
@@ -156,6 +1004,7 @@ impl FromStr for Oodle {
 pub struct Message {
 	pub id: usize,
 	pub date: OffsetDateTime,
+	pub headers: Headers,
 	pub content: String,
 }
 
@@ -166,13 +1015,180 @@ impl Message {
 		Self {
 			id: 0,
 			date: OffsetDateTime::now_utc().to_offset(offset),
+			headers: Headers::new(),
 			content: message.into(),
 		}
 	}
 
+	/// The header holding when a message was last edited.
+	pub const EDITED_HEADER: &'static str = "Edited";
+
+	/// When the message was last edited, if it ever was.
+	pub fn edited(&self) -> Option<OffsetDateTime> {
+		self.headers
+			.get(Self::EDITED_HEADER)
+			.and_then(|edited| OffsetDateTime::parse(edited, Self::TIME_FORMAT).ok())
+	}
+
+	pub fn set_edited(&mut self, when: OffsetDateTime) {
+		self.headers
+			.set(Self::EDITED_HEADER, Self::format_date(when));
+	}
+
+	/// The header holding when a message was really written, if it was
+	/// inserted with [Oodle::insert_message_after] and had to have its date
+	/// changed to fit between its neighbours.
+	pub const INSERTED_HEADER: &'static str = "Inserted";
+
+	/// The header marking a message as deleted, left on the tombstone of the
+	/// last message in an oodle. See [Oodle::delete_message].
+	pub const DELETED_HEADER: &'static str = "Deleted";
+
+	pub fn is_deleted(&self) -> bool {
+		self.headers.get(Self::DELETED_HEADER).is_some()
+	}
+
+	/// The header listing the files attached to a message, separated by spaces.
+	pub const ATTACHMENTS_HEADER: &'static str = "Attachments";
+
+	/// The names of the files attached to this message, in the order they were
+	/// attached.
+	pub fn attachments(&self) -> impl Iterator<Item = &str> {
+		self.headers
+			.get(Self::ATTACHMENTS_HEADER)
+			.unwrap_or_default()
+			.split_whitespace()
+	}
+
+	/// Attach a file to the message by name. Names can't have whitespace in
+	/// them, as it separates them in the header.
+	///
+	/// # Panics
+	/// If the name is empty or has whitespace in it.
+	pub fn add_attachment<S: AsRef<str>>(&mut self, name: S) {
+		let name = name.as_ref();
+		assert!(
+			!name.is_empty() && !name.contains(char::is_whitespace),
+			"invalid attachment name {:?}",
+			name
+		);
+
+		let mut attachments: Vec<&str> = self.attachments().collect();
+		if !attachments.contains(&name) {
+			attachments.push(name);
+		}
+
+		let attachments = attachments.join(" ");
+		self.headers.set(Self::ATTACHMENTS_HEADER, attachments);
+	}
+
+	/// The header holding a message's tags, separated by spaces, like
+	/// [Oodle::TAGS_HEADER].
+	pub const TAGS_HEADER: &'static str = "Tags";
+
+	/// The message's tags, [normalized](normalize_tag) however they were
+	/// written.
+	pub fn tags(&self) -> Vec<String> {
+		tag::header_tags(&self.headers, Self::TAGS_HEADER)
+	}
+
+	/// Replace the message's tags. See [Oodle::set_tags].
+	pub fn set_tags<S: AsRef<str>>(&mut self, tags: &[S]) {
+		tag::set_header_tags(&mut self.headers, Self::TAGS_HEADER, tags);
+	}
+
+	/// The header holding the alt text of an attachment, followed by a dash
+	/// and the attachment's position in [Message::ATTACHMENTS_HEADER],
+	/// counting from 1. Like `Alt-1: A cat asleep on a keyboard`. Go through
+	/// [Message::set_attachments] to remove or reorder attachments, so these
+	/// are numbered again to match.
+	pub const ALT_HEADER: &'static str = "Alt";
+
+	/// The header holding the caption of an attachment, numbered like
+	/// [Message::ALT_HEADER].
+	pub const CAPTION_HEADER: &'static str = "Caption";
+
+	/// The files attached to this message along with their alt text and
+	/// captions, in the order they were attached.
+	pub fn attachment_details(&self) -> Vec<Attachment> {
+		self.attachments()
+			.enumerate()
+			.map(|(idx, name)| {
+				let text = |header| {
+					self.headers
+						.get(format!("{}-{}", header, idx + 1))
+						.map(<_>::to_owned)
+				};
+
+				Attachment {
+					name: name.to_owned(),
+					alt: text(Self::ALT_HEADER),
+					caption: text(Self::CAPTION_HEADER),
+				}
+			})
+			.collect()
+	}
+
+	/// Replace the files attached to this message, and their alt text and
+	/// captions, in the order given.
+	///
+	/// # Panics
+	/// If a name is empty or has whitespace in it, like
+	/// [Message::add_attachment].
+	pub fn set_attachments(&mut self, attachments: &[Attachment]) {
+		for number in 1..=self.attachments().count() {
+			self.headers
+				.remove(format!("{}-{}", Self::ALT_HEADER, number));
+			self.headers
+				.remove(format!("{}-{}", Self::CAPTION_HEADER, number));
+		}
+		self.headers.remove(Self::ATTACHMENTS_HEADER);
+
+		for attachment in attachments {
+			self.add_attachment(&attachment.name);
+			self.set_attachment_text(
+				&attachment.name,
+				attachment.alt.as_deref(),
+				attachment.caption.as_deref(),
+			);
+		}
+	}
+
+	/// Set the alt text and caption of an attached file. A missing or blank
+	/// one is removed. Returns false if there's no attachment by that name.
+	pub fn set_attachment_text(
+		&mut self,
+		name: &str,
+		alt: Option<&str>,
+		caption: Option<&str>,
+	) -> bool {
+		let number = match self.attachments().position(|attached| attached == name) {
+			Some(idx) => idx + 1,
+			None => return false,
+		};
+
+		for (header, text) in [(Self::ALT_HEADER, alt), (Self::CAPTION_HEADER, caption)] {
+			let key = format!("{}-{}", header, number);
+
+			match text.map(str::trim).filter(|text| !text.is_empty()) {
+				Some(text) => {
+					self.headers.set(key, text);
+				}
+				None => {
+					self.headers.remove(key);
+				}
+			}
+		}
+
+		true
+	}
+
 	pub fn formatted_date(&self) -> String {
-		self.date
-			.format(Self::TIME_FORMAT)
+		Self::format_date(self.date)
+	}
+
+	fn format_date(date: OffsetDateTime) -> String {
+		date.format(Self::TIME_FORMAT)
 			.expect("Failed to format date. Why?")
 	}
 
@@ -184,43 +1200,156 @@ impl Message {
 	fn fmt_write_dateline(&self, f: &mut fmt::Formatter<'_>, print_index: bool) -> fmt::Result {
 		write!(f, "{}", self.formatted_date())?;
 
-		if print_index {
-			write!(f, " ({})", self.id)?;
+		if print_index {
+			write!(f, " ({})", self.id)?;
+		}
+
+		if self.headers.is_empty() {
+			return write!(f, "\n");
+		}
+
+		write!(f, " {}\n", Headers::DATELINE_MARK)?;
+
+		for (key, value) in self.headers.iter() {
+			write!(f, "{}: {}\n", key, value)?;
+		}
+
+		write!(f, "\n")
+	}
+
+	fn fmt_write_body(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for line in self.content.lines() {
+			if line == "." {
+				write!(f, "..\n")?;
+			} else {
+				write!(f, "{}\n", line)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Parse a message from numbered lines. `end` is the line number of the
+	/// message terminator, used when reporting an empty message.
+	fn parse_lines(lines: &[(usize, &str)], end: usize) -> Result<Self, OodleParseError> {
+		let mut lines = lines.iter().skip_while(|(_, line)| line.trim().is_empty());
+
+		let (idx, date, marked) = match lines.next() {
+			Some((number, dateline)) => Self::parse_dateline(*number, dateline)?,
+			None => {
+				return Err(OodleParseError::new(
+					end,
+					1,
+					OodleParseErrorKind::EmptyMessage,
+					"",
+				))
+			}
+		};
+
+		let rest: Vec<(usize, &str)> = lines.copied().collect();
+
+		// Headers only come after a marked dateline, and go until a blank line
+		let mut headers = Headers::new();
+		let mut body = &rest[..];
+		if marked {
+			while let Some((&(number, line), after)) = body.split_first() {
+				body = after;
+
+				if line.trim().is_empty() {
+					break;
+				}
+
+				match Headers::parse_line(line) {
+					Some((key, value)) => {
+						headers.set(key, value);
+					}
+					None => {
+						return Err(OodleParseError::new(
+							number,
+							1,
+							OodleParseErrorKind::BadHeader,
+							line,
+						))
+					}
+				}
+			}
 		}
 
-		write!(f, "\n")
-	}
-
-	fn fmt_write_body(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		for line in self.content.lines() {
-			if line == "." {
-				write!(f, "..\n")?;
+		let mut content = String::new();
+		for (_, line) in body {
+			if *line == ".." {
+				content.push_str(".\n");
 			} else {
-				write!(f, "{}\n", line)?;
+				content.push_str(line);
+				content.push('\n');
 			}
 		}
 
-		Ok(())
+		Ok(Self {
+			id: idx.unwrap_or(0),
+			date,
+			headers,
+			content: content.trim().to_owned(),
+		})
+	}
+
+	/// Parse a dateline into its index, if it has one, the date, and whether
+	/// it's marked as having headers after it.
+	/// The id a message block's dateline gives, if it gives one we can read,
+	/// even if the rest of the message can't be.
+	fn declared_id(lines: &[(usize, &str)]) -> Option<usize> {
+		let (_, dateline) = lines.iter().find(|(_, line)| !line.trim().is_empty())?;
+		let dateline = dateline.trim();
+		let dateline = dateline
+			.strip_suffix(Headers::DATELINE_MARK)
+			.map(str::trim_end)
+			.unwrap_or(dateline);
+
+		let (_, idx) = dateline.strip_suffix(')')?.rsplit_once(" (")?;
+		idx.parse().ok()
 	}
 
-	fn parse_dateline(line: &str) -> Result<(Option<usize>, OffsetDateTime), ()> {
-		let (idx, dateline) = if line.ends_with(')') {
-			match line.rsplit_once(" ") {
+	fn parse_dateline(
+		number: usize,
+		line: &str,
+	) -> Result<(Option<usize>, OffsetDateTime, bool), OodleParseError> {
+		let indent = line.len() - line.trim_start().len();
+		let line = line.trim();
+
+		let (line, marked) = match line.strip_suffix(Headers::DATELINE_MARK) {
+			Some(rest) => (rest.trim_end(), true),
+			None => (line, false),
+		};
+
+		let (idx, dateline) = match line.strip_suffix(')') {
+			Some(rest) => match rest.rsplit_once(" (") {
 				Some((date, idx)) => {
-					let idx = &idx[1..idx.len() - 1];
-					let idx = usize::from_str_radix(idx, 10).unwrap();
+					let column = indent + date.chars().count() + 3;
+					let idx = idx.parse().map_err(|_| {
+						OodleParseError::new(number, column, OodleParseErrorKind::BadIndex, idx)
+					})?;
 					(Some(idx), date)
 				}
-				None => panic!("malformed dateline"),
-			}
-		} else {
-			(None, line)
+				None => {
+					return Err(OodleParseError::new(
+						number,
+						indent + 1,
+						OodleParseErrorKind::BadDateline,
+						line,
+					))
+				}
+			},
+			None => (None, line),
 		};
 
-		//TODO: gen- return an error rather than panic
 		match OffsetDateTime::parse(dateline, Self::TIME_FORMAT) {
-			Ok(dt) => return Ok((idx, dt)),
-			Err(e) => panic!("{}", e),
+			Ok(dt) => Ok((idx, dt, marked)),
+			Err(_) => Err(OodleParseError::new(
+				number,
+				indent + 1,
+				OodleParseErrorKind::BadDateline,
+				dateline,
+			)),
 		}
 	}
 }
@@ -233,34 +1362,16 @@ impl fmt::Display for Message {
 }
 
 impl FromStr for Message {
-	//TODO: gen- a more descriptive error
-	type Err = ();
+	type Err = OodleParseError;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
-		let mut lines = s.lines();
+		let lines: Vec<(usize, &str)> = s
+			.split('\n')
+			.enumerate()
+			.map(|(idx, line)| (idx + 1, line))
+			.collect();
 
-		let (idx, date) = if let Some(dateline) = lines.next() {
-			Self::parse_dateline(dateline)?
-		} else {
-			// No datetime present! *(/nothing/ present)
-			return Err(());
-		};
-
-		let mut content = String::new();
-		for line in lines {
-			if line == ".." {
-				content.push_str(".\n");
-			} else {
-				content.push_str(line);
-				content.push('\n');
-			}
-		}
-
-		Ok(Self {
-			id: idx.unwrap_or(0),
-			date,
-			content: content.trim().to_owned(),
-		})
+		Self::parse_lines(&lines, lines.len())
 	}
 }
 
@@ -271,27 +1382,41 @@ impl Serialize for Message {
 	{
 		let epoch = self.date - OffsetDateTime::UNIX_EPOCH;
 
-		let mut state = serializer.serialize_struct("Message", 3)?;
+		let attachments = self.attachment_details();
+		let tags = self.tags();
+
+		let mut state = serializer.serialize_struct("Message", 6)?;
 		state.serialize_field("id", &self.id)?;
 		state.serialize_field("date", &epoch.whole_seconds())?;
+		state.serialize_field("headers", &self.headers)?;
 		state.serialize_field("content", &self.content)?;
+		state.serialize_field("attachments", &attachments)?;
+		state.serialize_field("tags", &tags)?;
 		state.end()
 	}
 }
 
 #[cfg(test)]
 mod test {
-	use std::str::FromStr;
+	use std::{path::PathBuf, str::FromStr};
 
 	use time::macros::datetime;
 
-	use crate::{Message, Oodle};
+	use crate::{
+		filename_from_title, make_thumbnail, normalize_tag, oodle_filename, parse_tags,
+		sanitize_filename, strip_metadata, try_parse_tags, url_encode_tag, Attachment, BadTag,
+		Document, Headers, Html, Message, Oodle, OodleParseError, OodleParseErrorKind, ParseMode,
+		ParseWarningKind, SearchIndex, TagIndex,
+	};
+
+	use crate::test_dir::TestDir;
 
 	#[test]
 	fn message_formats_correctly() {
 		let message = Message {
 			id: 0,
 			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
 			content: String::from("Line one!\nLine tw- oh no is that a\n.\nIt was!"),
 		};
 
@@ -306,6 +1431,7 @@ mod test {
 		let message = Message {
 			id: 0,
 			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
 			content: String::from("Line one!\nLine tw- oh no is that a\n.\nIt was!"),
 		};
 
@@ -319,12 +1445,14 @@ mod test {
 		let message = Message {
 			id: 0,
 			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
 			content: String::from("Line one!\nLine tw- oh no is that a\n.\nIt was!"),
 		};
 
 		let message2 = Message {
 			id: 1,
 			date: datetime!(2022-06-01 14:15 -5),
+			headers: Headers::new(),
 			content: String::from("Looky here another message!"),
 		};
 
@@ -342,12 +1470,14 @@ mod test {
 		let message = Message {
 			id: 0,
 			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
 			content: String::from("Line one!\nLine tw- oh no is that a\n.\nIt was!"),
 		};
 
 		let message2 = Message {
 			id: 2,
 			date: datetime!(2022-06-01 14:15 -5),
+			headers: Headers::new(),
 			content: String::from("Looky here another message!"),
 		};
 
@@ -365,12 +1495,14 @@ mod test {
 		let message = Message {
 			id: 0,
 			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
 			content: String::from("Line one!\nLine tw- oh no is that a\n.\nIt was!"),
 		};
 
 		let message2 = Message {
 			id: 1,
 			date: datetime!(2022-06-01 14:15 -5),
+			headers: Headers::new(),
 			content: String::from("Looky here another message!"),
 		};
 
@@ -382,4 +1514,1089 @@ mod test {
 
 		assert_eq!(Oodle::from_str(expected), Ok(ood))
 	}
+
+	#[test]
+	fn oodle_without_title_errors() {
+		let oodle = "2022-06-01 13:45:00-0500\nNo title here\n.\n";
+
+		assert_eq!(
+			Oodle::from_str(oodle),
+			Err(OodleParseError {
+				line: 1,
+				column: 1,
+				kind: OodleParseErrorKind::MissingTitle,
+				text: String::from("2022-06-01 13:45:00-0500"),
+			})
+		)
+	}
+
+	#[test]
+	fn oodle_malformed_title_errors() {
+		let oodle = "-= Unclosed\n\n2022-06-01 13:45:00-0500\nHello\n.\n";
+
+		assert_eq!(
+			Oodle::from_str(oodle).unwrap_err().kind,
+			OodleParseErrorKind::MalformedTitle
+		)
+	}
+
+	#[test]
+	fn oodle_bad_dateline_errors() {
+		let oodle = "-= Title =-\n\n2022-06-01 13:45:00-0500\nFine\n.\n\nJune first\nNot fine\n.\n";
+
+		assert_eq!(
+			Oodle::from_str(oodle),
+			Err(OodleParseError {
+				line: 7,
+				column: 1,
+				kind: OodleParseErrorKind::BadDateline,
+				text: String::from("June first"),
+			})
+		)
+	}
+
+	#[test]
+	fn oodle_bad_index_errors() {
+		let oodle = "-= Title =-\n\n2022-06-01 13:45:00-0500 (two)\nHello\n.\n";
+
+		assert_eq!(
+			Oodle::from_str(oodle),
+			Err(OodleParseError {
+				line: 3,
+				column: 27,
+				kind: OodleParseErrorKind::BadIndex,
+				text: String::from("two"),
+			})
+		)
+	}
+
+	#[test]
+	fn oodle_empty_message_errors() {
+		let oodle = "-= Title =-\n\n2022-06-01 13:45:00-0500\nHello\n.\n\n.\n";
+
+		assert_eq!(
+			Oodle::from_str(oodle),
+			Err(OodleParseError {
+				line: 7,
+				column: 1,
+				kind: OodleParseErrorKind::EmptyMessage,
+				text: String::new(),
+			})
+		)
+	}
+
+	#[test]
+	fn test_oodle_parses() {
+		let oodle = Oodle::from_str(include_str!("../tests/test.oodle")).unwrap();
+
+		assert_eq!(oodle.name, "Title!");
+		assert_eq!(oodle.messages.len(), 3);
+		assert!(oodle.messages[1].content.contains("\n.\nYup"));
+	}
+
+	#[test]
+	fn lenient_oodle_normalizes_damage() {
+		let damaged = "\u{feff}-= Title =- \r\n2022-06-01 13:45:00-0500\r\nHello\r\n. \r\n2022-06-01 14:15:00-0500\r\nAgain\r\n.\r\n";
+
+		assert!(Oodle::from_str(damaged).is_err());
+
+		let (oodle, warnings) = Oodle::parse_with(damaged, ParseMode::Lenient).unwrap();
+		let kinds: Vec<ParseWarningKind> = warnings.into_iter().map(|w| w.kind).collect();
+
+		assert_eq!(oodle.name, "Title");
+		assert_eq!(oodle.messages.len(), 2);
+		assert_eq!(oodle.messages[0].content, "Hello");
+		assert_eq!(oodle.messages[1].content, "Again");
+		assert_eq!(
+			kinds,
+			vec![
+				ParseWarningKind::ByteOrderMark,
+				ParseWarningKind::CarriageReturn,
+				ParseWarningKind::TrailingWhitespace,
+				ParseWarningKind::MissingBlankLine,
+				ParseWarningKind::TrailingWhitespace
+			]
+		);
+	}
+
+	#[test]
+	fn lenient_oodle_skips_bad_messages() {
+		let oodle = "-= Title =-\n\n2022-06-01 13:45:00-0500\nFine\n.\n\nJune first\nNot fine\n.\n\n2022-06-01 14:15:00-0500\nAlso fine\n.\n";
+
+		let (oodle, warnings) = Oodle::parse_with(oodle, ParseMode::Lenient).unwrap();
+
+		assert_eq!(oodle.messages.len(), 2);
+		assert_eq!(oodle.messages[1].content, "Also fine");
+		assert_eq!(oodle.messages[1].id, 2);
+		assert_eq!(warnings.len(), 1);
+		assert_eq!(warnings[0].line, 7);
+		assert!(matches!(
+			warnings[0].kind,
+			ParseWarningKind::SkippedMessage(OodleParseError {
+				kind: OodleParseErrorKind::BadDateline,
+				..
+			})
+		));
+		assert!(oodle.is_incomplete());
+	}
+
+	#[tokio::test]
+	async fn incomplete_oodles_are_not_rewritten() {
+		let dir = TestDir::new("incomplete");
+		let path = dir.join("incomplete.oodle");
+
+		let written =
+			"-= Title =-\n\n2022-06-01 13:45:00-0500\nFine\n.\n\nJune first\nNot fine\n.\n";
+		std::fs::write(&path, written).unwrap();
+
+		let (mut ood, _) = Oodle::read_with(&path, ParseMode::Lenient).await.unwrap();
+		let edited = ood
+			.edit_message(
+				0,
+				String::from("Changed"),
+				&[],
+				None,
+				datetime!(2022-06-01 14:00 -5),
+			)
+			.await;
+		assert!(edited.is_err());
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), written);
+	}
+
+	#[tokio::test]
+	async fn incomplete_oodles_keep_their_ids() {
+		let dir = TestDir::new("incomplete-ids");
+		let path = dir.join("incomplete.oodle");
+
+		let written = "-= Title =-\n\n2022-06-01 13:45:00-0500\nZero\n.\n\nJune first\nOne\n.\n\n2022-06-01 14:15:00-0500\nTwo\n.\n\n2022-06-01 14:30:00-0500 (7) +\nBad header\n\nSeven\n.\n\n2022-06-01 14:45:00-0500\nEight\n.\n";
+		std::fs::write(&path, written).unwrap();
+
+		let (mut ood, _) = Oodle::read_with(&path, ParseMode::Lenient).await.unwrap();
+		let ids: Vec<usize> = ood.messages.iter().map(|msg| msg.id).collect();
+		assert_eq!(ids, vec![0, 2, 8]);
+
+		// Appending would number the new message from what we have, which
+		// isn't everything, so it's refused like saving is
+		let later = Message {
+			id: 0,
+			date: datetime!(2022-06-01 15:00 -5),
+			headers: Headers::new(),
+			content: String::from("Later"),
+		};
+		assert!(ood.append_message(later).await.is_err());
+		assert_eq!(ood.messages.len(), 3);
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), written);
+
+		// Once it's fixed every message has the id it had
+		let fixed = written
+			.replace("June first", "2022-06-01 14:00:00-0500")
+			.replace("Bad header", "Key: value");
+		let fixed = Oodle::from_str(&fixed).unwrap();
+		for msg in &ood.messages {
+			assert_eq!(fixed.message(msg.id).unwrap().content, msg.content);
+		}
+	}
+
+	#[tokio::test]
+	async fn oodle_saves_and_reads_back() {
+		let dir = TestDir::new("save");
+		let path = dir.join("saved.oodle");
+
+		let message = Message {
+			id: 0,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
+			content: String::from("Saved!"),
+		};
+
+		let mut ood = Oodle::new("Saving", &path, message);
+		std::fs::write(&path, "old contents that are much longer than the new ones").unwrap();
+		ood.save().await.unwrap();
+
+		assert_eq!(Oodle::read(&path).await.unwrap(), ood);
+		assert!(!dir.join(".saved.oodle.tmp").exists());
+
+		ood.name = String::from("Saved again");
+		ood.save().await.unwrap();
+		assert_eq!(Oodle::read(&path).await.unwrap().name, "Saved again");
+	}
+
+	#[tokio::test]
+	async fn oodle_appends_messages() {
+		let dir = TestDir::new("append");
+		let path = dir.join("appended.oodle");
+
+		let message = Message {
+			id: 0,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
+			content: String::from("First!"),
+		};
+
+		let mut ood = Oodle::new("Appending", &path, message);
+		ood.save().await.unwrap();
+
+		let second = Message {
+			id: 0,
+			date: datetime!(2022-06-01 14:15 -5),
+			headers: Headers::new(),
+			content: String::from("Second"),
+		};
+		let jumped = Message {
+			id: 5,
+			date: datetime!(2022-06-01 14:30 -5),
+			headers: Headers::new(),
+			content: String::from("Jumped\n.\nahead"),
+		};
+
+		assert_eq!(ood.append_message(second).await.unwrap(), 1);
+		assert_eq!(ood.append_message(jumped).await.unwrap(), 5);
+
+		let on_disk = std::fs::read_to_string(&path).unwrap();
+		assert_eq!(on_disk, ood.to_string());
+		assert_eq!(Oodle::read(&path).await.unwrap(), ood);
+
+		// Without a trailing newline the block would run into the last dot, so
+		// the whole file is written instead
+		std::fs::write(&path, on_disk.trim_end()).unwrap();
+		let later = Message {
+			id: 0,
+			date: datetime!(2022-06-01 15:00 -5),
+			headers: Headers::new(),
+			content: String::from("Later"),
+		};
+
+		assert_eq!(ood.append_message(later).await.unwrap(), 6);
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), ood.to_string());
+		assert_eq!(Oodle::read(&path).await.unwrap(), ood);
+	}
+
+	#[test]
+	fn oodle_filenames_are_checked() {
+		assert_eq!(oodle_filename("first"), Some(String::from("first.oodle")));
+		assert_eq!(
+			oodle_filename("first.oodle"),
+			Some(String::from("first.oodle"))
+		);
+		assert_eq!(
+			oodle_filename("v1.2_notes"),
+			Some(String::from("v1.2_notes.oodle"))
+		);
+
+		assert_eq!(oodle_filename("../../etc/x"), None);
+		assert_eq!(oodle_filename("/etc/passwd"), None);
+		assert_eq!(oodle_filename(".hidden"), None);
+		assert_eq!(oodle_filename(".oodle"), None);
+		assert_eq!(oodle_filename("spaced out"), None);
+		assert_eq!(oodle_filename(""), None);
+	}
+
+	#[test]
+	fn oodle_filenames_from_titles() {
+		assert_eq!(
+			filename_from_title("Hey, I'm a title!", |_| false),
+			"hey-i-m-a-title.oodle"
+		);
+		assert_eq!(filename_from_title("!!!", |_| false), "oodle.oodle");
+		assert_eq!(
+			filename_from_title("Taken", |f| f == "taken.oodle" || f == "taken-2.oodle"),
+			"taken-3.oodle"
+		);
+	}
+
+	#[test]
+	fn oodle_keeps_old_slugs() {
+		let message = Message::new_now("Hi!", time::UtcOffset::UTC);
+		let mut ood = Oodle::new("Slugs", "/tmp/nothing.oodle", message);
+
+		ood.set_slug("first");
+		ood.set_slug("second");
+		ood.set_slug("third");
+		assert_eq!(ood.slug(), Some("third"));
+		assert_eq!(ood.old_slugs().collect::<Vec<_>>(), vec!["first", "second"]);
+
+		// Going back to an old slug takes it out of the old ones
+		ood.set_slug("first");
+		assert_eq!(ood.slug(), Some("first"));
+		assert_eq!(ood.old_slugs().collect::<Vec<_>>(), vec!["second", "third"]);
+		assert!(ood.has_slug("third"));
+		assert!(!ood.has_slug("fourth"));
+
+		let parsed = Oodle::from_str(&ood.to_string()).unwrap();
+		assert_eq!(parsed.headers, ood.headers);
+	}
+
+	#[test]
+	fn search_index_ranks_and_forgets() {
+		let message = Message::new_now("Cats are sleeping", time::UtcOffset::UTC);
+		let mut cats = Oodle::new("All about cats", "/tmp/cats.oodle", message);
+		let mut second = Message::new_now("Dogs, and a cat. A CAT!", time::UtcOffset::UTC);
+		second.id = 1;
+		cats.messages.push(second);
+
+		let message = Message::new_now("Dogs are barking", time::UtcOffset::UTC);
+		let dogs = Oodle::new("Dogs", "/tmp/dogs.oodle", message);
+
+		let mut index = SearchIndex::new();
+		index.index_oodle(&cats);
+		index.index_oodle(&dogs);
+
+		let found = |index: &SearchIndex, query| -> Vec<Document> {
+			index
+				.search(query)
+				.into_iter()
+				.map(|hit| hit.document)
+				.collect()
+		};
+		let document = |filename: &str, message| Document {
+			filename: filename.to_owned(),
+			message,
+		};
+
+		// The title counts for more than a message saying it twice
+		assert_eq!(found(&index, "cat"), vec![document("cats.oodle", Some(1))]);
+		assert_eq!(
+			found(&index, "cats"),
+			vec![
+				document("cats.oodle", None),
+				document("cats.oodle", Some(0))
+			]
+		);
+		// Every term has to be there
+		assert_eq!(
+			found(&index, "dogs cat"),
+			vec![document("cats.oodle", Some(1))]
+		);
+		assert_eq!(found(&index, "dogs sleeping"), vec![]);
+
+		cats.messages.pop();
+		index.index_oodle(&cats);
+		assert_eq!(found(&index, "cat"), vec![]);
+
+		index.remove_oodle("dogs.oodle");
+		assert_eq!(found(&index, "barking"), vec![]);
+	}
+
+	#[test]
+	fn html_snippet_marks_matches() {
+		assert_eq!(
+			Html::snippet("Fish & <chips>, with more fish", "FISH").as_str(),
+			"<mark>Fish</mark> &amp; &lt;chips&gt;, with more <mark>fish</mark>"
+		);
+
+		let long = format!("{} needle {}", "hay ".repeat(30), "hay ".repeat(60));
+		let snippet = Html::snippet(long, "needle");
+		assert!(snippet.as_str().starts_with("…"));
+		assert!(snippet.as_str().ends_with("…"));
+		assert!(snippet.as_str().contains("<mark>needle</mark>"));
+	}
+
+	#[test]
+	fn html_escapes_hostile_text() {
+		assert_eq!(
+			Html::escape("<script>alert(\"hi\")</script>").as_str(),
+			"&lt;script&gt;alert(&quot;hi&quot;)&lt;/script&gt;"
+		);
+
+		// Breaking out of a quoted attribute
+		assert_eq!(
+			Html::escape("x' onmouseover='alert(1)").as_str(),
+			"x&#39; onmouseover=&#39;alert(1)"
+		);
+
+		// Entities are shown as written, not decoded
+		assert_eq!(Html::escape("&lt;b&gt;").as_str(), "&amp;lt;b&amp;gt;");
+	}
+
+	#[test]
+	fn html_message_keeps_only_line_breaks() {
+		let content = "Look at this:\n<img src=x onerror=alert(1)>\n<br>";
+
+		assert_eq!(
+			Html::plain(content).as_str(),
+			"<p>Look at this:<br>&lt;img src=x onerror=alert(1)&gt;<br>&lt;br&gt;</p>"
+		);
+	}
+
+	#[test]
+	fn html_markdown_is_sanitized() {
+		let content = "Some *emphasis* and [a link](https://example.com).\n\n<script>alert(1)</script>\n\n[bad](javascript:alert(1)) <img src=x onerror=alert(1)>";
+		let html = Html::markdown(content);
+
+		assert!(html.as_str().contains("<em>emphasis</em>"));
+		assert!(html.as_str().contains("href=\"https://example.com\""));
+		assert!(!html.as_str().contains("<script"));
+		assert!(!html.as_str().contains("javascript:"));
+		assert!(!html.as_str().contains("onerror"));
+	}
+
+	#[test]
+	fn html_highlights_fenced_code() {
+		let content = "Before\n```rust\nfn main() {\n\tlet x = \"<b>\";\n}\n```\nAfter";
+
+		for html in [Html::plain(content), Html::markdown(content)] {
+			let html = html.as_str();
+
+			let start = html.find("<pre").unwrap();
+			let end = html.find("</pre>").unwrap();
+			let code = &html[start..end];
+
+			assert!(code.starts_with("<pre class=\"code\"><code class=\"language-rust\">"));
+			assert!(code.contains("<span class=\"hl-"));
+			assert!(code.contains("\n\t"));
+			assert!(code.contains("&lt;b&gt;"));
+			assert!(!code.contains("<br>"));
+
+			assert!(html.contains("Before"));
+			assert!(html[end..].contains("After"));
+		}
+	}
+
+	#[test]
+	fn message_attachments_round_trip() {
+		let mut message = Message::new_now("Look!", time::UtcOffset::UTC);
+		message.add_attachment(sanitize_filename("my screenshot.png"));
+		message.add_attachment(sanitize_filename("../notes.txt"));
+		message.add_attachment("my-screenshot.png");
+
+		assert_eq!(
+			message.attachments().collect::<Vec<_>>(),
+			vec!["my-screenshot.png", "-notes.txt"]
+		);
+
+		let parsed = Message::from_str(&message.to_string()).unwrap();
+		assert_eq!(
+			parsed.attachments().collect::<Vec<_>>(),
+			vec!["my-screenshot.png", "-notes.txt"]
+		);
+
+		let json = serde_json::to_value(&parsed).unwrap();
+		assert_eq!(
+			json["attachments"],
+			serde_json::json!([
+				{ "name": "my-screenshot.png", "alt": null, "caption": null },
+				{ "name": "-notes.txt", "alt": null, "caption": null }
+			])
+		);
+
+		assert_eq!(
+			Html::attachments(&parsed.attachment_details()).as_str(),
+			"<ul class=\"attachments\"><li><figure><a href=\"/attachments/my-screenshot.png\"><img src=\"/attachments/thumbnails/my-screenshot.png.png\" alt=\"my-screenshot.png\"></a></figure></li><li><figure><a href=\"/attachments/-notes.txt\">-notes.txt</a></figure></li></ul>"
+		);
+	}
+
+	#[test]
+	fn attachment_text_round_trips() {
+		let mut message = Message::new_now("Look!", time::UtcOffset::UTC);
+		message.add_attachment("cat.jpg");
+		message.add_attachment("notes.txt");
+
+		assert!(message.set_attachment_text(
+			"cat.jpg",
+			Some("A cat \"asleep\" on a keyboard"),
+			Some("Not\nagain")
+		));
+		assert!(message.set_attachment_text("notes.txt", Some("  "), Some("The notes")));
+		assert!(!message.set_attachment_text("dog.jpg", Some("A dog"), None));
+
+		let parsed = Message::from_str(&message.to_string()).unwrap();
+		assert_eq!(
+			parsed.headers.get("Alt-1"),
+			Some("A cat \"asleep\" on a keyboard")
+		);
+		assert_eq!(parsed.headers.get("Alt-2"), None);
+
+		let details = parsed.attachment_details();
+		assert_eq!(details[1].caption.as_deref(), Some("The notes"));
+
+		let json = serde_json::to_value(&parsed).unwrap();
+		assert_eq!(json["attachments"][0]["caption"], "Not again");
+
+		assert_eq!(
+			Html::attachments(&details).as_str(),
+			"<ul class=\"attachments\"><li><figure><a href=\"/attachments/cat.jpg\"><img src=\"/attachments/thumbnails/cat.jpg\" alt=\"A cat &quot;asleep&quot; on a keyboard\"></a><figcaption>Not again</figcaption></figure></li><li><figure><a href=\"/attachments/notes.txt\">notes.txt</a><figcaption>The notes</figcaption></figure></li></ul>"
+		);
+
+		let mut message = parsed;
+		assert!(message.set_attachment_text("cat.jpg", None, None));
+		assert_eq!(message.headers.get("Alt-1"), None);
+		assert_eq!(message.headers.get("Caption-1"), None);
+
+		// Taking the first away moves the second's text up with it
+		message.set_attachment_text("cat.jpg", Some("A cat"), None);
+		let mut details = message.attachment_details();
+		details.remove(0);
+		message.set_attachments(&details);
+		assert_eq!(message.attachments().collect::<Vec<_>>(), ["notes.txt"]);
+		assert_eq!(message.headers.get("Alt-1"), None);
+		assert_eq!(message.headers.get("Caption-1"), Some("The notes"));
+		assert_eq!(message.headers.get("Caption-2"), None);
+
+		message.set_attachments(&[]);
+		assert!(message.headers.is_empty());
+	}
+
+	#[test]
+	fn attachment_images_lose_exif() {
+		let image = image::DynamicImage::new_rgb8(1000, 500);
+
+		let mut jpeg = vec![];
+		image
+			.write_to(
+				&mut std::io::Cursor::new(&mut jpeg),
+				image::ImageOutputFormat::Jpeg(90),
+			)
+			.unwrap();
+
+		// An APP1 segment with EXIF in it, right after the start of image
+		let mut exif = vec![0xFF, 0xE1, 0x00, 0x10];
+		exif.extend_from_slice(b"Exif\0\0GPS-here");
+		let mut with_exif = jpeg[..2].to_vec();
+		with_exif.extend_from_slice(&exif);
+		with_exif.extend_from_slice(&jpeg[2..]);
+
+		// And XMP, and a comment
+		let mut xmp = vec![0xFF, 0xE1, 0x00, 0x23];
+		xmp.extend_from_slice(b"http://ns.adobe.com/xap/1.0/\0<x/>");
+		with_exif.splice(2..2, xmp);
+		with_exif.splice(2..2, *b"\xFF\xFE\x00\x04hi");
+
+		assert_eq!(strip_metadata(&with_exif).as_ref(), jpeg.as_slice());
+
+		let thumbnail = make_thumbnail("photo.jpg", &with_exif).unwrap();
+		let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+		assert_eq!((thumbnail.width(), thumbnail.height()), (800, 400));
+	}
+
+	#[test]
+	fn attachment_images_are_turned_upright() {
+		let image = image::DynamicImage::new_rgb8(1000, 500);
+
+		let mut jpeg = vec![];
+		image
+			.write_to(
+				&mut std::io::Cursor::new(&mut jpeg),
+				image::ImageOutputFormat::Jpeg(90),
+			)
+			.unwrap();
+
+		// Big-endian TIFF with one tag, orientation 6: turned a quarter clockwise
+		let mut exif = vec![0xFF, 0xE1, 0x00, 0x22];
+		exif.extend_from_slice(b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01");
+		exif.extend_from_slice(b"\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0");
+		let mut rotated = jpeg[..2].to_vec();
+		rotated.extend_from_slice(&exif);
+		rotated.extend_from_slice(&jpeg[2..]);
+
+		let upright = strip_metadata(&rotated);
+		assert!(!upright.windows(4).any(|w| w == b"Exif"));
+		let upright = image::load_from_memory(&upright).unwrap();
+		assert_eq!((upright.width(), upright.height()), (500, 1000));
+
+		let thumbnail = make_thumbnail("photo.jpg", &rotated).unwrap();
+		let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+		assert_eq!((thumbnail.width(), thumbnail.height()), (400, 800));
+	}
+
+	#[test]
+	fn attachment_webps_and_gifs_lose_metadata() {
+		fn chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+			let mut chunk = kind.to_vec();
+			chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+			chunk.extend_from_slice(data);
+			if data.len() % 2 == 1 {
+				chunk.push(0);
+			}
+			chunk
+		}
+
+		fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+			let chunks = chunks.concat();
+			let mut webp = b"RIFF".to_vec();
+			webp.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+			webp.extend_from_slice(b"WEBP");
+			webp.extend_from_slice(&chunks);
+			webp
+		}
+
+		// Flags say there's alpha, EXIF, and XMP; then a 1x1 canvas
+		let vp8x = |flags: u8| chunk(b"VP8X", &[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		let image = chunk(b"VP8L", b"pixel");
+
+		let webp = riff(&[
+			vp8x(0x10 | 0x08 | 0x04),
+			image.clone(),
+			chunk(b"EXIF", b"GPS-here"),
+			chunk(b"XMP ", b"<x/>!"),
+		]);
+		assert_eq!(
+			strip_metadata(&webp).as_ref(),
+			riff(&[vp8x(0x10), image]).as_slice()
+		);
+
+		let mut gif = vec![];
+		image::DynamicImage::new_rgb8(4, 4)
+			.write_to(
+				&mut std::io::Cursor::new(&mut gif),
+				image::ImageOutputFormat::Gif,
+			)
+			.unwrap();
+
+		// After the header, screen descriptor, and global color table
+		let start = match gif[10] {
+			packed if packed & 0x80 != 0 => 13 + (3 << ((packed & 0x07) + 1)),
+			_ => 13,
+		};
+		let mut with_comment = gif.clone();
+		with_comment.splice(start..start, *b"\x21\xFE\x03hi!\x00");
+		with_comment.splice(start..start, *b"\x21\xFF\x0BXMP DataXMP\x02<>\x00");
+
+		assert_eq!(strip_metadata(&with_comment).as_ref(), gif.as_slice());
+	}
+
+	#[test]
+	fn message_headers_round_trip() {
+		let mut headers = Headers::new();
+		headers.insert("Content-Warning", "spiders").unwrap();
+		headers.insert("Reply-To", "3").unwrap();
+		assert!(headers.insert("Not a key", "no").is_err());
+
+		let message = Message {
+			id: 0,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers,
+			content: String::from("There was a spider.\nIt was big."),
+		};
+
+		let expected = "2022-06-01 13:45:00-0500 +\nContent-Warning: spiders\nReply-To: 3\n\nThere was a spider.\nIt was big.\n";
+
+		assert_eq!(format!("{}", message), expected);
+		assert_eq!(Message::from_str(expected), Ok(message));
+	}
+
+	#[test]
+	fn oodle_archived_round_trips() {
+		let message = Message {
+			id: 0,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
+			content: String::from("Old news."),
+		};
+
+		let mut ood = Oodle::new("Archived!", "/tmp/nothing.oodle", message);
+		ood.set_archived(Some(datetime!(2022-07-01 09:00 -5)));
+
+		let expected = "-= Archived! =-\nArchived: 2022-07-01 09:00:00-0500\n\n2022-06-01 13:45:00-0500\nOld news.\n.\n";
+		assert_eq!(ood.to_string(), expected);
+
+		let mut parsed = Oodle::from_str(expected).unwrap();
+		assert!(parsed.is_archived());
+
+		parsed.set_archived(None);
+		assert!(!parsed.is_archived());
+		assert_eq!(
+			parsed.to_string(),
+			"-= Archived! =-\n\n2022-06-01 13:45:00-0500\nOld news.\n.\n"
+		);
+	}
+
+	#[test]
+	fn oodle_tags_parse_and_index() {
+		let written = "-= Trip =-\nTags: Travel, #Rust travel\n\n2022-06-01 13:45:00-0500 +\nTags: food\n\nLunch.\n.\n2022-06-01 14:00:00-0500\nNo tags.\n.\n";
+		let mut ood = Oodle::from_str(written).unwrap();
+		ood.file = PathBuf::from("/tmp/trip.oodle");
+
+		assert_eq!(ood.tags(), vec!["travel", "rust"]);
+		assert_eq!(ood.messages[0].tags(), vec!["food"]);
+		assert!(ood.messages[1].tags().is_empty());
+
+		assert_eq!(
+			parse_tags("#C++, C#, #c, Road_trips,,  ROAD-TRIPS"),
+			vec!["c++", "c#", "c", "road-trips"]
+		);
+		assert_eq!(normalize_tag("#!?"), None);
+		assert_eq!(
+			try_parse_tags("rust, #!?"),
+			Err(BadTag(String::from("#!?")))
+		);
+		assert_eq!(
+			try_parse_tags(" rust,, c++ "),
+			Ok(vec![String::from("rust"), String::from("c++")])
+		);
+		assert_eq!(url_encode_tag("c++"), "c%2B%2B");
+
+		let mut index = TagIndex::new();
+		index.index_oodle(&ood);
+		assert_eq!(
+			index.tags().collect::<Vec<_>>(),
+			vec![("food", 1), ("rust", 1), ("travel", 1)]
+		);
+		assert_eq!(
+			index.tagged("food").collect::<Vec<_>>(),
+			vec![&Document {
+				filename: String::from("trip.oodle"),
+				message: Some(0)
+			}]
+		);
+
+		ood.set_tags::<&str>(&[]);
+		ood.messages[1].set_tags(&["Dinner"]);
+		assert_eq!(
+			ood.to_string(),
+			"-= Trip =-\n\n2022-06-01 13:45:00-0500 +\nTags: food\n\nLunch.\n.\n\n2022-06-01 14:00:00-0500 +\nTags: dinner\n\nNo tags.\n.\n"
+		);
+
+		index.index_oodle(&ood);
+		assert_eq!(
+			index.tags().collect::<Vec<_>>(),
+			vec![("dinner", 1), ("food", 1)]
+		);
+	}
+
+	#[test]
+	fn message_body_like_headers_round_trips() {
+		let message = Message {
+			id: 0,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
+			content: String::from("Update: it works\n\nI didn't think it would."),
+		};
+
+		let formatted = format!("{}", message);
+
+		assert_eq!(
+			formatted,
+			"2022-06-01 13:45:00-0500\nUpdate: it works\n\nI didn't think it would.\n"
+		);
+		assert_eq!(Message::from_str(&formatted), Ok(message));
+	}
+
+	#[test]
+	fn message_headers_need_a_marked_dateline() {
+		// Written before there were headers, so it's all body
+		let old =
+			"-= Old =-\n\n2022-06-01 13:45:00-0500\nTodo: buy milk\nAlso: eggs\n\nAnd bread.\n.\n";
+		let ood = Oodle::from_str(old).unwrap();
+
+		assert!(ood.messages[0].headers.is_empty());
+		assert_eq!(
+			ood.messages[0].content,
+			"Todo: buy milk\nAlso: eggs\n\nAnd bread."
+		);
+		assert_eq!(ood.to_string(), old);
+
+		let bad =
+			Message::from_str("2022-06-01 13:45:00-0500 (2) +\nEdited: yes\nnot a header\n\nBody");
+		assert_eq!(bad.unwrap_err().kind, OodleParseErrorKind::BadHeader);
+	}
+
+	#[test]
+	fn message_body_with_colon_is_not_headers() {
+		let message =
+			Message::from_str("2022-06-01 13:45:00-0500\nNote: no blank line after\nso it's body")
+				.unwrap();
+
+		assert!(message.headers.is_empty());
+		assert_eq!(message.content, "Note: no blank line after\nso it's body");
+	}
+
+	#[tokio::test]
+	async fn oodle_edits_keep_revisions() {
+		let dir = TestDir::new("revisions");
+		let path = dir.join("edited.oodle");
+
+		let message = Message {
+			id: 0,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
+			content: String::from("Frist!"),
+		};
+
+		let mut ood = Oodle::new("Editing", &path, message);
+		ood.save().await.unwrap();
+
+		ood.edit_message(
+			0,
+			String::from("First!"),
+			&[],
+			None,
+			datetime!(2022-06-01 13:50 -5),
+		)
+		.await
+		.unwrap();
+		ood.edit_message(
+			0,
+			String::from("First?"),
+			&[],
+			None,
+			datetime!(2022-06-01 14:00 -5),
+		)
+		.await
+		.unwrap();
+
+		let msg = ood.message(0).unwrap();
+		assert_eq!(msg.content, "First?");
+		assert_eq!(msg.edited(), Some(datetime!(2022-06-01 14:00 -5)));
+		assert_eq!(Oodle::read(&path).await.unwrap(), ood);
+
+		let revisions = ood.revisions(0).await.unwrap();
+		assert_eq!(revisions.len(), 2);
+		assert_eq!(revisions[0].content, "Frist!");
+		assert_eq!(revisions[0].date, datetime!(2022-06-01 13:45 -5));
+		assert_eq!(revisions[1].content, "First!");
+		assert_eq!(revisions[1].date, datetime!(2022-06-01 13:50 -5));
+
+		ood.restore_revision(0, 1, datetime!(2022-06-01 14:10 -5))
+			.await
+			.unwrap();
+		assert_eq!(ood.message(0).unwrap().content, "First!");
+		assert_eq!(ood.revisions(0).await.unwrap().len(), 3);
+
+		assert!(ood
+			.edit_message(7, String::new(), &[], None, datetime!(2022-06-01 14:10 -5))
+			.await
+			.is_err());
+	}
+
+	#[tokio::test]
+	async fn attachment_text_edits_are_not_revisions() {
+		let dir = TestDir::new("alt-edits");
+		let path = dir.join("alt.oodle");
+
+		let mut message = Message {
+			id: 0,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
+			content: String::from("Look"),
+		};
+		message.add_attachment("cat.jpg");
+
+		let mut ood = Oodle::new("Alt text", &path, message);
+		ood.save().await.unwrap();
+
+		let alt = |text: &str| Attachment {
+			name: String::from("cat.jpg"),
+			alt: Some(String::from(text)),
+			caption: None,
+		};
+
+		ood.edit_message(
+			0,
+			String::from("Look"),
+			&[alt("A cat")],
+			Some(&[String::from("cats")]),
+			datetime!(2022-06-01 13:50 -5),
+		)
+		.await
+		.unwrap();
+
+		let msg = ood.message(0).unwrap();
+		assert_eq!(msg.attachment_details(), [alt("A cat")]);
+		assert_eq!(msg.tags(), ["cats"]);
+		assert_eq!(msg.edited(), None);
+		assert!(ood.revisions(0).await.unwrap().is_empty());
+
+		ood.edit_message(
+			0,
+			String::from("Look!"),
+			&[alt("A cat, asleep")],
+			None,
+			datetime!(2022-06-01 14:00 -5),
+		)
+		.await
+		.unwrap();
+
+		let revisions = ood.revisions(0).await.unwrap();
+		assert_eq!(revisions.len(), 1);
+		assert_eq!(revisions[0].attachments, [alt("A cat")]);
+
+		ood.restore_revision(0, 0, datetime!(2022-06-01 14:10 -5))
+			.await
+			.unwrap();
+		let msg = ood.message(0).unwrap();
+		assert_eq!(msg.content, "Look");
+		assert_eq!(msg.attachment_details(), [alt("A cat")]);
+		assert_eq!(
+			ood.revisions(0).await.unwrap()[1].attachments,
+			[alt("A cat, asleep")]
+		);
+	}
+
+	#[tokio::test]
+	async fn oodle_deletes_keep_ids() {
+		let dir = TestDir::new("delete");
+		let path = dir.join("deleted.oodle");
+
+		let message = |content: &str| Message {
+			id: 0,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
+			content: String::from(content),
+		};
+
+		let mut ood = Oodle::new("Deleting", &path, message("zero"));
+		ood.push_message(message("one"));
+		ood.push_message(message("two"));
+		ood.save().await.unwrap();
+
+		ood.delete_message(1, datetime!(2022-06-01 14:00 -5))
+			.await
+			.unwrap();
+
+		let ids: Vec<usize> = ood.messages.iter().map(|m| m.id).collect();
+		assert_eq!(ids, vec![0, 2]);
+		assert!(std::fs::read_to_string(&path)
+			.unwrap()
+			.contains("2022-06-01 13:45:00-0500 (2)\ntwo"));
+		assert_eq!(Oodle::read(&path).await.unwrap(), ood);
+
+		// The last message becomes a tombstone so its id isn't reused
+		ood.delete_message(2, datetime!(2022-06-01 14:00 -5))
+			.await
+			.unwrap();
+		assert!(ood.message(2).unwrap().is_deleted());
+		assert_eq!(Oodle::read(&path).await.unwrap(), ood);
+		assert_eq!(ood.push_message(message("three")), 3);
+
+		assert!(ood
+			.delete_message(2, datetime!(2022-06-01 14:00 -5))
+			.await
+			.is_err());
+		assert_eq!(ood.revisions(1).await.unwrap()[0].content, "one");
+	}
+
+	#[tokio::test]
+	async fn oodle_inserts_between_messages() {
+		let dir = TestDir::new("insert");
+		let path = dir.join("inserted.oodle");
+
+		let message = |id, date, content: &str| Message {
+			id,
+			date,
+			headers: Headers::new(),
+			content: String::from(content),
+		};
+
+		let mut ood = Oodle::new(
+			"Inserting",
+			&path,
+			message(0, datetime!(2022-06-01 13:00 -5), "zero"),
+		);
+		ood.push_message(message(1, datetime!(2022-06-01 14:00 -5), "one"));
+		ood.push_message(message(2, datetime!(2022-06-01 15:00 -5), "two"));
+		ood.push_message(message(4, datetime!(2022-06-01 16:00 -5), "four"));
+		ood.save().await.unwrap();
+		ood.edit_message(
+			1,
+			String::from("one!"),
+			&[],
+			None,
+			datetime!(2022-06-01 14:05 -5),
+		)
+		.await
+		.unwrap();
+
+		// No room after 0, so 1 and 2 move up into the gap at 3
+		let forgot = message(0, datetime!(2022-06-01 17:00 -5), "forgot");
+		assert_eq!(ood.insert_message_after(0, forgot).await.unwrap(), 1);
+
+		let ids: Vec<usize> = ood.messages.iter().map(|m| m.id).collect();
+		assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+		assert_eq!(ood.message(2).unwrap().content, "one!");
+		assert_eq!(ood.revisions(2).await.unwrap()[0].content, "one");
+		assert!(ood.revisions(1).await.unwrap().is_empty());
+
+		let inserted = ood.message(1).unwrap();
+		assert_eq!(inserted.date, datetime!(2022-06-01 14:00 -5));
+		assert_eq!(
+			inserted.headers.get(Message::INSERTED_HEADER),
+			Some("2022-06-01 17:00:00-0500")
+		);
+		assert_eq!(Oodle::read(&path).await.unwrap(), ood);
+
+		// There's a gap after 4 once 5 is skipped, so nothing moves
+		ood.push_message(message(6, datetime!(2022-06-01 18:00 -5), "six"));
+		let gap = message(0, datetime!(2022-06-01 17:00 -5), "five");
+		assert_eq!(ood.insert_message_after(4, gap).await.unwrap(), 5);
+		assert_eq!(ood.message(6).unwrap().content, "six");
+		assert!(ood.message(5).unwrap().headers.is_empty());
+
+		// Deleting 5 leaves its id empty but with a revision, which something
+		// put in its place mustn't take
+		ood.delete_message(5, datetime!(2022-06-01 19:00 -5))
+			.await
+			.unwrap();
+		let again = message(0, datetime!(2022-06-01 17:30 -5), "five again");
+		assert_eq!(ood.insert_message_after(4, again).await.unwrap(), 5);
+
+		assert!(ood.revisions(5).await.unwrap().is_empty());
+		assert_eq!(ood.revisions(6).await.unwrap()[0].content, "five");
+		assert_eq!(ood.message(7).unwrap().content, "six");
+		assert_eq!(Oodle::read(&path).await.unwrap(), ood);
+	}
+
+	#[test]
+	fn oodle_splits_off_messages() {
+		let message = |id, content: &str| Message {
+			id,
+			date: datetime!(2022-06-01 13:45 -5),
+			headers: Headers::new(),
+			content: String::from(content),
+		};
+
+		let mut ood = Oodle::new("Whole", "/tmp/whole.oodle", message(0, "zero"));
+		ood.push_message(message(1, "one"));
+		ood.push_message(message(3, "three"));
+
+		assert!(ood.split_off(0, "Nothing", "/tmp/nothing.oodle").is_none());
+		assert!(ood.split_off(2, "Nothing", "/tmp/nothing.oodle").is_none());
+
+		let (tangent, moved) = ood.split_off(1, "Tangent", "/tmp/tangent.oodle").unwrap();
+
+		assert_eq!(ood.messages.len(), 1);
+		assert_eq!(moved, vec![(1, 0), (3, 1)]);
+		assert_eq!(tangent.name, "Tangent");
+		assert_eq!(tangent.message(0).unwrap().content, "one");
+		assert_eq!(tangent.message(1).unwrap().content, "three");
+
+		// Nothing would be left to split off but a deleted message
+		let mut deleted = message(1, "");
+		deleted
+			.headers
+			.set(Message::DELETED_HEADER, "2022-06-01 14:00 -05:00");
+		ood.push_message(deleted);
+		assert!(ood.split_off(1, "Nothing", "/tmp/nothing.oodle").is_none());
+		assert_eq!(ood.messages.len(), 2);
+	}
+
+	#[test]
+	fn oodle_merges_by_date() {
+		let message = |id, date, content: &str| Message {
+			id,
+			date,
+			headers: Headers::new(),
+			content: String::from(content),
+		};
+
+		let mut ood = Oodle::new(
+			"Ours",
+			"/tmp/ours.oodle",
+			message(0, datetime!(2022-06-01 13:00 -5), "ours 0"),
+		);
+		ood.push_message(message(2, datetime!(2022-06-01 15:00 -5), "ours 2"));
+
+		let mut other = Oodle::new(
+			"Theirs",
+			"/tmp/theirs.oodle",
+			message(0, datetime!(2022-06-01 14:00 -5), "theirs 0"),
+		);
+		other.push_message(message(1, datetime!(2022-06-01 16:00 -5), "theirs 1"));
+
+		let (ours, theirs) = ood.merge(other);
+
+		let contents: Vec<&str> = ood.messages.iter().map(|m| m.content.as_str()).collect();
+		assert_eq!(contents, vec!["ours 0", "theirs 0", "ours 2", "theirs 1"]);
+		assert_eq!(ours, vec![(0, 0), (2, 2)]);
+		assert_eq!(theirs, vec![(0, 1), (1, 3)]);
+	}
 }
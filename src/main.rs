@@ -1,38 +1,54 @@
 use std::{
 	future::Future,
-	net::SocketAddr,
+	net::{IpAddr, SocketAddr},
+	path::PathBuf,
 	pin::Pin,
 	sync::Arc,
 	task::{Context, Poll},
+	time::{Duration, Instant},
 };
 
 use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
-use database::Session;
+use database::{OodlesError, Session};
 use form::QueryWrapper;
-use hyper::{header, service::Service, Body, Method, Response, Server, StatusCode};
+use hyper::{
+	header, server::conn::AddrStream, service::Service, Body, Method, Response, Server, StatusCode,
+};
 use mavourings::{
 	file_reply, file_string_reply,
 	query::{self, Query, QueryParseError},
 	template::Template,
 };
-use oodles::Message;
+use mime_guess::mime;
+use oodles::{normalize_tag, url_encode_tag, Html, Message, Oodle, OodleEditError, Renderer};
 use rand::rngs::OsRng;
 use serde::de::DeserializeOwned;
 use time::{
 	format_description::FormatItem,
 	macros::{format_description, offset},
+	OffsetDateTime,
 };
 
-use crate::database::Database;
+use crate::database::{Database, Users};
 
 mod config;
 mod database;
 mod form;
+#[cfg(test)]
+mod test_dir;
+mod throttle;
+mod totp;
 
 const DATETIME_FORMAT: &[FormatItem] = format_description!(
 	"[weekday repr:long], [month repr:long] [day padding:none] [year repr:full] [hour repr:24]:[minute padding:zero]"
 );
 
+/// The most that can be uploaded at once to attach to a message, 16MiB.
+const MAX_ATTACHMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The most results shown for a search.
+const MAX_SEARCH_RESULTS: usize = 50;
+
 #[tokio::main]
 async fn main() {
 	let command = std::env::args().nth(1);
@@ -43,6 +59,14 @@ async fn main() {
 
 	let config = config::Config::get();
 
+	if let Some("thumbnails") = command.as_deref() {
+		command_thumbnails(config).await
+	}
+
+	if let Some("totp") = command.as_deref() {
+		command_totp(config)
+	}
+
 	println!(
 		"Starting with Config:\n\t{}:{}\n\tCreds: {}\n\tData: {}",
 		config.address,
@@ -51,7 +75,12 @@ async fn main() {
 		config.data_directory.to_string_lossy()
 	);
 
-	let database = Arc::new(Database::get(config.credential_file, config.data_directory));
+	let database = Arc::new(Database::get(
+		config.credential_file,
+		config.data_directory,
+		config.renderer,
+		config.login_limits,
+	));
 	database.create_directories().await;
 	database.oodles_mut().await.load_oodles().await;
 
@@ -80,11 +109,244 @@ fn command_encrypt() -> ! {
 	}
 }
 
+/// Make the thumbnails for every image attachment again, like after
+/// [THUMBNAIL_SIZE](oodles::THUMBNAIL_SIZE) changes.
+async fn command_thumbnails(config: config::Config) -> ! {
+	let database = Database::get(
+		config.credential_file,
+		config.data_directory,
+		config.renderer,
+		config.login_limits,
+	);
+	database.create_directories().await;
+
+	let made = database.oodles().await.regenerate_thumbnails().await;
+	match made {
+		Ok(made) => {
+			println!("Made {} thumbnails", made);
+			std::process::exit(0)
+		}
+		Err(e) => {
+			eprintln!("Failed to read attachments: {}", e);
+			std::process::exit(1)
+		}
+	}
+}
+
+/// Turn on two-factor login for a user, or give them a new secret and recovery
+/// codes if they have them already. Prints the URI that adds the secret to an
+/// authenticator app, and the recovery codes, which can't be seen again.
+fn command_totp(config: config::Config) -> ! {
+	let username = match std::env::args().nth(2) {
+		Some(username) => username,
+		None => {
+			eprintln!("Usage: oodles totp USERNAME");
+			std::process::exit(1)
+		}
+	};
+
+	let mut users = Users::load_file(&config.credential_file);
+	match users.enroll_second_factor(&username) {
+		Ok((secret, codes)) => {
+			println!(
+				"Add this to an authenticator app:\n\t{}",
+				totp::otpauth_uri(&username, &secret)
+			);
+			println!("\nRecovery codes, each works once in place of a code:");
+			for code in codes {
+				println!("\t{}", code);
+			}
+			println!(
+				"\nRestart oodles if it's running, it only reads the credentials when it starts."
+			);
+			std::process::exit(0)
+		}
+		Err(e) => {
+			eprintln!("Failed to set up two-factor login for {}: {}", username, e);
+			std::process::exit(1)
+		}
+	}
+}
+
+/// Refuse a login because there have been too many failed ones, saying how
+/// long until they can try again.
+fn too_many_logins(wait: Duration) -> Response<Body> {
+	// Round up, so trying again when we say doesn't find it's a moment early
+	let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+
+	Response::builder()
+		.status(StatusCode::TOO_MANY_REQUESTS)
+		.header(header::RETRY_AFTER, seconds)
+		.body(Body::from(format!(
+			"Too many failed logins, try again in {} seconds",
+			seconds
+		)))
+		.unwrap()
+}
+
+/// Whether a request may go ahead. Anything that could change an oodle, which
+/// is anything but a GET or HEAD, needs someone logged in, and is refused with
+/// 401 Unauthorized otherwise. Logging in is the one exception. So do the
+/// [PRIVATE_READS].
+///
+/// This is the only place access is checked, so new routes are covered
+/// without having to remember to.
+fn authorize(method: &Method, path: &str, session: Option<&Session>) -> Result<(), StatusCode> {
+	let private = PRIVATE_READS.contains(&path);
+
+	if (!is_write(method) && !private) || logs_in(method, path) || session.is_some() {
+		Ok(())
+	} else {
+		Err(StatusCode::UNAUTHORIZED)
+	}
+}
+
+/// Whether a request is one of the steps of logging in.
+fn logs_in(method: &Method, path: &str) -> bool {
+	*method == Method::POST && matches!(path, "login" | "login/code")
+}
+
+/// Reads that show more than the pages do, like what a message said before
+/// it was edited, so they need a session too.
+const PRIVATE_READS: &[&str] = &["oodle/message/revisions"];
+
+/// Whether a request could change something, which is anything but a GET or
+/// HEAD.
+fn is_write(method: &Method) -> bool {
+	!matches!(*method, Method::GET | Method::HEAD)
+}
+
+/// The header fetches send the session's CSRF token in.
+const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Writes from someone logged in have to carry their session's CSRF token, so
+/// another site can't make their browser post for them. Fetches send it in the
+/// [CSRF_HEADER] and forms in a [form::CSRF_FIELD] field. It's never taken from
+/// the query, where it'd end up in logs and history. Refused with 403
+/// Forbidden.
+async fn check_csrf(req: &mut Request, session: Option<&Session>) -> Result<(), StatusCode> {
+	let session = match session {
+		Some(session) if is_write(req.method()) => session,
+		_ => return Ok(()),
+	};
+
+	match req.csrf_token().await {
+		Some(token) if session.check_csrf(&token) => Ok(()),
+		_ => Err(StatusCode::FORBIDDEN),
+	}
+}
+
+/// Logging in has no session to check a CSRF token against, so another site
+/// could log someone's browser in as the attacker. Browsers say which site a
+/// post came from in the Origin header, and logins from another host than ours
+/// are refused with 403 Forbidden. Clients that aren't browsers don't send one,
+/// and have no one to trick, so they're let through.
+fn check_login_origin(req: &Request, path: &str) -> Result<(), StatusCode> {
+	if !logs_in(req.method(), path) {
+		return Ok(());
+	}
+
+	let headers = req.inner.headers();
+	let origin = match headers.get(header::ORIGIN) {
+		Some(origin) => origin.to_str().ok(),
+		None => return Ok(()),
+	};
+
+	// An origin is the scheme, host, and port, without a path
+	let origin_host = origin.and_then(|origin| origin.split_once("://"));
+	let host = headers
+		.get(header::HOST)
+		.and_then(|host| host.to_str().ok());
+
+	match (origin_host, host) {
+		(Some((_, origin)), Some(host)) if origin.eq_ignore_ascii_case(host) => Ok(()),
+		_ => Err(StatusCode::FORBIDDEN),
+	}
+}
+
+/// A bare response for an error status, with the status as its body.
+fn status_reply(status: StatusCode) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.body(Body::from(status.as_str().to_owned()))
+		.unwrap()
+}
+
+//TODO: gen- Assocaite offset with user account.
+fn now() -> OffsetDateTime {
+	OffsetDateTime::now_utc().to_offset(offset!(-5))
+}
+
+fn edit_error_status(e: OodleEditError) -> StatusCode {
+	match e {
+		OodleEditError::NoSuchMessage(_) | OodleEditError::NoSuchRevision(_) => {
+			StatusCode::NOT_FOUND
+		}
+		OodleEditError::NoSuchAttachment(_) => StatusCode::BAD_REQUEST,
+		e => {
+			eprintln!("Failed to edit message: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		}
+	}
+}
+
+fn oodles_error_status(e: OodlesError) -> StatusCode {
+	match e {
+		OodlesError::NoSuchOodle(_) | OodlesError::NoSuchMessage(_) => StatusCode::NOT_FOUND,
+		OodlesError::AlreadyExists(_) | OodlesError::SlugTaken(_) => StatusCode::CONFLICT,
+		OodlesError::BadFilename(_)
+		| OodlesError::BadSlug(_)
+		| OodlesError::BadTitle(_)
+		| OodlesError::CannotSplit(_)
+		| OodlesError::SameOodle => StatusCode::BAD_REQUEST,
+		e => {
+			eprintln!("Failed to change oodles: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		}
+	}
+}
+
+/// Where an oodle can be found.
+fn oodle_location(oodle: &Oodle) -> String {
+	format!("/oodles/{}", oodle.slug().unwrap_or_default())
+}
+
+/// Like [oodles_error_status], but with the error in the body for the
+/// errors a person can fix by changing what they sent.
+fn oodles_error_reply(e: OodlesError) -> Response<Body> {
+	let message = match e {
+		OodlesError::AlreadyExists(_)
+		| OodlesError::BadFilename(_)
+		| OodlesError::BadSlug(_)
+		| OodlesError::BadTitle(_)
+		| OodlesError::SlugTaken(_) => Some(e.to_string()),
+		_ => None,
+	};
+	let status = oodles_error_status(e);
+
+	Response::builder()
+		.status(status)
+		.header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from(
+			message.unwrap_or_else(|| status.as_str().to_owned()),
+		))
+		.unwrap()
+}
+
+fn multipart_error_status(e: multer::Error) -> StatusCode {
+	match e {
+		multer::Error::StreamSizeExceeded { .. } | multer::Error::FieldSizeExceeded { .. } => {
+			StatusCode::PAYLOAD_TOO_LARGE
+		}
+		_ => StatusCode::BAD_REQUEST,
+	}
+}
+
 struct MakeSvc {
 	database: Arc<Database>,
 }
 
-impl<T> Service<T> for MakeSvc {
+impl Service<&AddrStream> for MakeSvc {
 	type Response = Svc;
 	type Error = &'static str;
 	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
@@ -93,15 +355,18 @@ impl<T> Service<T> for MakeSvc {
 		Poll::Ready(Ok(()))
 	}
 
-	fn call(&mut self, _: T) -> Self::Future {
+	fn call(&mut self, conn: &AddrStream) -> Self::Future {
 		let database = self.database.clone();
-		let fut = async move { Ok(Svc { database }) };
+		let remote = conn.remote_addr().ip();
+		let fut = async move { Ok(Svc { database, remote }) };
 		Box::pin(fut)
 	}
 }
 
 pub struct Request {
 	pub inner: hyper::Request<Body>,
+	/// The address the request came from, if we know it
+	pub remote: Option<IpAddr>,
 }
 
 impl Request {
@@ -130,16 +395,48 @@ impl Request {
 		let body = hyper::body::to_bytes(self.inner.body_mut()).await.unwrap();
 		serde_json::from_slice(&body)
 	}
+
+	/// The CSRF token sent with the request, from the [CSRF_HEADER] or a form
+	/// body. The body is read to find it and put back for whatever handles the
+	/// request.
+	pub async fn csrf_token(&mut self) -> Option<String> {
+		if let Some(token) = self.inner.headers().get(CSRF_HEADER) {
+			return token.to_str().ok().map(<_>::to_owned);
+		}
+
+		let form = self
+			.inner
+			.headers()
+			.get(header::CONTENT_TYPE)
+			.and_then(|ct| ct.to_str().ok())
+			.map(|ct| ct.starts_with("application/x-www-form-urlencoded"))
+			.unwrap_or(false);
+		if !form {
+			return None;
+		}
+
+		let body = hyper::body::to_bytes(self.inner.body_mut()).await.ok()?;
+		let query: Option<Query> = std::str::from_utf8(&body)
+			.ok()
+			.and_then(|body| body.parse().ok());
+		*self.inner.body_mut() = Body::from(body);
+
+		QueryWrapper::from(query?).csrf_token()
+	}
 }
 
 impl From<hyper::Request<Body>> for Request {
 	fn from(inner: hyper::Request<Body>) -> Self {
-		Self { inner }
+		Self {
+			inner,
+			remote: None,
+		}
 	}
 }
 
 struct Svc {
 	database: Arc<Database>,
+	remote: IpAddr,
 }
 
 impl Service<hyper::Request<Body>> for Svc {
@@ -153,12 +450,14 @@ impl Service<hyper::Request<Body>> for Svc {
 
 	fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
 		let db = self.database.clone();
-		Box::pin(async { Ok(Self::task(req.into(), db).await) })
+		let mut req = Request::from(req);
+		req.remote = Some(self.remote);
+		Box::pin(async { Ok(Self::task(req, db).await) })
 	}
 }
 
 impl Svc {
-	async fn task(req: Request, db: Arc<Database>) -> Response<Body> {
+	async fn task(mut req: Request, db: Arc<Database>) -> Response<Body> {
 		let path = req
 			.inner
 			.uri()
@@ -169,6 +468,18 @@ impl Svc {
 
 		let session = db.get_session(&req.inner).await;
 
+		if let Err(status) = authorize(req.method(), &path, session.as_ref()) {
+			return status_reply(status);
+		}
+
+		if let Err(status) = check_csrf(&mut req, session.as_ref()).await {
+			return status_reply(status);
+		}
+
+		if let Err(status) = check_login_origin(&req, &path) {
+			return status_reply(status);
+		}
+
 		let response = match (req.method(), path.as_str()) {
 			(&Method::GET, "") | (&Method::GET, "index.html") => {
 				Ok(Self::index(req, db, session).await)
@@ -193,19 +504,54 @@ impl Svc {
 			}),
 
 			(&Method::POST, "login") => Self::user_login(req, db).await,
-			(&Method::GET, "logout") => Self::user_logout(req, db, session).await,
-
-			(&Method::POST, "oodle/create") => Self::oodle_create(req, db, session).await,
+			(&Method::POST, "login/code") => Self::user_login_code(req, db).await,
+			(&Method::GET, "search") => Ok(Self::search(req, db).await),
+			(&Method::GET, "tags") => Ok(Self::tags(db).await),
+			(&Method::POST, "logout") => Self::user_logout(req, db, session).await,
+			(&Method::GET, "sessions") => Ok(Self::user_sessions(db, session).await),
+			(&Method::POST, "sessions/revoke") => Self::user_session_revoke(req, db, session).await,
+
+			(&Method::POST, "oodle/create") => Self::oodle_create(req, db).await,
+			(&Method::POST, "oodle/split") => Self::oodle_split(req, db).await,
+			(&Method::POST, "oodle/merge") => Self::oodle_merge(req, db).await,
+			(&Method::POST, "oodle/rename") => Self::oodle_rename(req, db).await,
+			(&Method::POST, "oodle/delete") => Self::oodle_delete(req, db).await,
+			(&Method::POST, "oodle/restore") => Self::oodle_restore(req, db).await,
+			(&Method::POST, "oodle/renderer") => Self::oodle_renderer(req, db).await,
+			(&Method::POST, "oodle/tags") => Self::oodle_tags(req, db).await,
+			(&Method::POST, "oodle/archive") => Self::oodle_archive(req, db, Some(now())).await,
+			(&Method::POST, "oodle/unarchive") => Self::oodle_archive(req, db, None).await,
+			(&Method::POST, "oodle/message/move") => Self::oodle_message_move(req, db).await,
 			(&Method::POST, "oodle/message/create") => Self::oodle_message(req, db, session).await,
 			(&Method::POST, "oodle/message/modify") => {
 				Self::oodle_message_modify(req, db, session).await
 			}
-			(&Method::GET, "oodle/message/get") => Self::oodle_message_get(req, db, session).await,
+			(&Method::GET, "oodle/message/get") => Self::oodle_message_get(req, db).await,
+			(&Method::POST, "oodle/message/insert") => {
+				Self::oodle_message_insert(req, db, session).await
+			}
+			(&Method::POST, "oodle/message/delete") => Self::oodle_message_delete(req, db).await,
+			(&Method::GET, "oodle/message/revisions") => {
+				Self::oodle_message_revisions(req, db).await
+			}
+			(&Method::POST, "oodle/message/restore") => {
+				Self::oodle_message_restore(req, db, session).await
+			}
+			(&Method::POST, "oodle/message/attach") => Self::oodle_message_attach(req, db).await,
 
 			(&Method::GET, _) => {
-				if let Some(name) = path.strip_prefix("oodles/") {
-					let name = query::Query::url_decode(name, false).unwrap();
-					Self::oodle_view(req, db, name, session).await
+				if let Some(slug) = path.strip_prefix("oodles/") {
+					let slug = query::Query::url_decode(slug, false).unwrap();
+					Self::oodle_view(req, db, slug, session).await
+				} else if let Some(tag) = path.strip_prefix("tags/") {
+					let tag = query::Query::url_decode(tag, false).unwrap();
+					Self::tag_view(db, tag).await
+				} else if let Some(name) = path.strip_prefix("attachments/thumbnails/") {
+					let path = db.oodles().await.thumbnail_path(name);
+					Self::attachment_get(path).await
+				} else if let Some(name) = path.strip_prefix("attachments/") {
+					let path = db.oodles().await.attachment_path(name);
+					Self::attachment_get(path).await
 				} else {
 					Err(StatusCode::NOT_FOUND)
 				}
@@ -216,28 +562,54 @@ impl Svc {
 
 		match response {
 			Ok(response) => response,
-			Err(status) => Response::builder()
-				.status(status)
-				.body(Body::from(format!("{}", status.as_str())))
-				.unwrap(),
+			Err(status) => status_reply(status),
 		}
 	}
 
-	async fn index(
-		mut _req: Request,
-		db: Arc<Database>,
-		session: Option<Session>,
-	) -> Response<Body> {
+	/// The list of oodles, only those with a tag if there's a `tag` query
+	/// parameter.
+	async fn index(req: Request, db: Arc<Database>, session: Option<Session>) -> Response<Body> {
+		let tag = QueryWrapper::from_uri_query(&req)
+			.ok()
+			.and_then(|query| query.get_optional_value("tag"))
+			.and_then(|tag| normalize_tag(&tag));
+
 		let mut tpl = Template::file("web/index.html").await;
+		if let Some(tag) = &tag {
+			tpl.set("tag", Html::escape(tag));
+		}
+
+		let oodles = db.oodles().await;
 
 		if let Some(sesh) = session {
-			tpl.set("username", sesh.username);
+			tpl.set("username", Html::escape(sesh.username));
+			tpl.set("csrf", Html::escape(&sesh.csrf_token));
+
+			for (title, slug, filename) in oodles.archived() {
+				let mut pattern = tpl.document.get_pattern("archived").unwrap();
+				pattern.set("name", Html::escape(title));
+				pattern.set("slug", Html::escape(slug));
+				pattern.set("filename", Html::escape(filename));
+				pattern.set("csrf", Html::escape(&sesh.csrf_token));
+				tpl.document.set_pattern("archived", pattern);
+			}
+
+			for filename in oodles.trashed() {
+				let mut pattern = tpl.document.get_pattern("trashed").unwrap();
+				pattern.set("filename", Html::escape(filename));
+				pattern.set("csrf", Html::escape(&sesh.csrf_token));
+				tpl.document.set_pattern("trashed", pattern);
+			}
 		}
 
-		for (title, datetime) in db.oodles().await.oodle_metedata().await {
+		for (title, slug, tags, datetime) in oodles.oodle_metedata(tag.as_deref()).await {
 			//TODO: gen- display dates, too
 			let mut pattern = tpl.document.get_pattern("oodle").unwrap();
-			pattern.set("name", title);
+			pattern.set("name", Html::escape(title));
+			pattern.set("slug", Html::escape(slug));
+			if !tags.is_empty() {
+				pattern.set("tags", Html::tags(&tags));
+			}
 			pattern.set(
 				"date",
 				datetime
@@ -251,21 +623,119 @@ impl Svc {
 		tpl.as_response().unwrap()
 	}
 
-	async fn oodle_create(
-		req: Request,
-		db: Arc<Database>,
-		session: Option<Session>,
-	) -> Result<Response<Body>, StatusCode> {
-		//FIXME: gen- check user has rights to make an oodle
+	/// Search every oodle for titles and messages with all the words in the
+	/// `q` query parameter.
+	async fn search(req: Request, db: Arc<Database>) -> Response<Body> {
+		let query = QueryWrapper::from_uri_query(&req)
+			.ok()
+			.and_then(|query| query.get_optional_value("q"))
+			.unwrap_or_default();
+
+		let mut tpl = Template::file("web/search.html").await;
+		tpl.set("query", Html::escape(&query));
+
+		if !query.is_empty() {
+			let oodles = db.oodles().await;
+			let results = oodles.search(&query);
+
+			if results.is_empty() {
+				tpl.set("no_results", "");
+			}
+
+			for (oodle, message) in results.into_iter().take(MAX_SEARCH_RESULTS) {
+				let mut pattern = tpl.document.get_pattern("result").unwrap();
+				pattern.set("name", Html::escape(&oodle.name));
+
+				match message {
+					Some(msg) => {
+						pattern.set(
+							"link",
+							format!("{}#message-{}", oodle_location(oodle), msg.id),
+						);
+						pattern.set("snippet", Html::snippet(&msg.content, &query));
+					}
+					None => {
+						pattern.set("link", oodle_location(oodle));
+						pattern.set("snippet", Html::snippet(&oodle.name, &query));
+					}
+				}
+
+				tpl.document.set_pattern("result", pattern);
+			}
+		}
+
+		tpl.as_response().unwrap()
+	}
+
+	/// Every tag in use, with how many oodles and messages have it.
+	async fn tags(db: Arc<Database>) -> Response<Body> {
+		let mut tpl = Template::file("web/tags.html").await;
+
+		for (tag, count) in db.oodles().await.tags() {
+			let mut pattern = tpl.document.get_pattern("tag").unwrap();
+			pattern.set("tag_url", Html::escape(url_encode_tag(&tag)));
+			pattern.set("tag", Html::escape(tag));
+			pattern.set("count", count);
+			tpl.document.set_pattern("tag", pattern);
+		}
+
+		tpl.as_response().unwrap()
+	}
+
+	/// The oodles and messages with a tag.
+	async fn tag_view(db: Arc<Database>, tag: String) -> Result<Response<Body>, StatusCode> {
+		let tag = normalize_tag(&tag).ok_or(StatusCode::NOT_FOUND)?;
+
+		let oodles = db.oodles().await;
+		let tagged = oodles.tagged(&tag);
+		if tagged.is_empty() {
+			return Err(StatusCode::NOT_FOUND);
+		}
+
+		let mut tpl = Template::file("web/tag.html").await;
+		tpl.set("tag_url", Html::escape(url_encode_tag(&tag)));
+		tpl.set("tag", Html::escape(&tag));
+
+		for (oodle, message) in tagged {
+			match message {
+				None => {
+					let mut pattern = tpl.document.get_pattern("oodle").unwrap();
+					pattern.set("name", Html::escape(&oodle.name));
+					pattern.set("link", oodle_location(oodle));
+					tpl.document.set_pattern("oodle", pattern);
+				}
+				Some(msg) => {
+					let mut pattern = tpl.document.get_pattern("message").unwrap();
+					pattern.set("name", Html::escape(&oodle.name));
+					pattern.set(
+						"link",
+						format!("{}#message-{}", oodle_location(oodle), msg.id),
+					);
+					pattern.set("date", msg.date.format(DATETIME_FORMAT).unwrap());
+					pattern.set("snippet", Html::snippet(&msg.content, ""));
+					tpl.document.set_pattern("message", pattern);
+				}
+			}
+		}
+
+		Ok(tpl.as_response().unwrap())
+	}
+
+	async fn oodle_create(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
 		let form = form::OodleCreate::from_request(req).await?;
 
 		//TODO: gen- Assocaite offset with user account.
 		let message = Message::new_now(form.content, offset!(-5));
-		db.oodles_mut()
+		let created = db
+			.oodles_mut()
 			.await
-			.new_oodle(form.title, form.filename, message)
+			.new_oodle(form.title, form.filename.as_deref(), message)
 			.await;
 
+		if let Err(e) = created {
+			return Ok(oodles_error_reply(e));
+		}
+
 		Ok(Response::builder()
 			.status(200)
 			.header(header::LOCATION, "/")
@@ -274,37 +744,288 @@ impl Svc {
 			.unwrap())
 	}
 
+	async fn oodle_split(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
+		let form = form::OodleSplit::from_request(req).await?;
+
+		let location = {
+			let mut oodles = db.oodles_mut().await;
+			let split = oodles
+				.split_oodle(&form.filename, form.at, form.title, &form.new_filename)
+				.await;
+
+			match split {
+				Ok(filename) => oodle_location(oodles.oodle_by_file(filename).unwrap()),
+				Err(e) => return Ok(oodles_error_reply(e)),
+			}
+		};
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, location)
+			.status(302)
+			.body(Body::from("Oodle split! Redirecting to the new one."))
+			.unwrap())
+	}
+
+	async fn oodle_merge(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
+		let form = form::OodleMerge::from_request(req).await?;
+
+		let location = {
+			let mut oodles = db.oodles_mut().await;
+			oodles
+				.merge_oodles(&form.from, &form.into)
+				.await
+				.map_err(oodles_error_status)?;
+
+			oodle_location(oodles.oodle_by_file(&form.into).unwrap())
+		};
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, location)
+			.status(302)
+			.body(Body::from("Oodles merged! Redirecting to the merged one."))
+			.unwrap())
+	}
+
+	async fn oodle_rename(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
+		let form = form::OodleRename::from_request(req).await?;
+
+		let location = {
+			let mut oodles = db.oodles_mut().await;
+			let renamed = oodles
+				.rename_oodle(
+					&form.filename,
+					form.title,
+					form.slug.as_deref(),
+					form.new_filename.as_deref(),
+				)
+				.await;
+
+			let filename = match renamed {
+				Ok(filename) => filename,
+				Err(e) => return Ok(oodles_error_reply(e)),
+			};
+
+			oodle_location(oodles.oodle_by_file(filename).unwrap())
+		};
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, location)
+			.status(302)
+			.body(Body::from("Oodle renamed! Redirecting to it."))
+			.unwrap())
+	}
+
+	async fn oodle_delete(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
+		let form = form::OodleFile::from_request(req).await?;
+
+		db.oodles_mut()
+			.await
+			.trash_oodle(&form.filename)
+			.await
+			.map_err(oodles_error_status)?;
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, "/")
+			.status(302)
+			.body(Body::from("Oodle moved to the trash! Redirecting to home."))
+			.unwrap())
+	}
+
+	async fn oodle_restore(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
+		let form = form::OodleFile::from_request(req).await?;
+
+		let location = {
+			let mut oodles = db.oodles_mut().await;
+			oodles
+				.restore_oodle(&form.filename)
+				.await
+				.map_err(oodles_error_status)?;
+
+			oodle_location(oodles.oodle_by_file(&form.filename).unwrap())
+		};
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, location)
+			.status(302)
+			.body(Body::from("Oodle restored! Redirecting to it."))
+			.unwrap())
+	}
+
+	async fn oodle_renderer(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
+		let form = form::OodleRenderer::from_request(req).await?;
+
+		let location = {
+			let mut oodles = db.oodles_mut().await;
+			oodles
+				.set_renderer(&form.filename, form.renderer)
+				.await
+				.map_err(oodles_error_status)?;
+
+			oodle_location(oodles.oodle_by_file(&form.filename).unwrap())
+		};
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, location)
+			.status(302)
+			.body(Body::from("Oodle updated! Redirecting to it."))
+			.unwrap())
+	}
+
+	async fn oodle_tags(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
+		let form = form::OodleTags::from_request(req).await?;
+
+		let location = {
+			let mut oodles = db.oodles_mut().await;
+			oodles
+				.set_tags(&form.filename, &form.tags)
+				.await
+				.map_err(oodles_error_status)?;
+
+			oodle_location(oodles.oodle_by_file(&form.filename).unwrap())
+		};
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, location)
+			.status(302)
+			.body(Body::from("Oodle updated! Redirecting to it."))
+			.unwrap())
+	}
+
+	/// Archive an oodle, or unarchive it if `when` is None.
+	async fn oodle_archive(
+		req: Request,
+		db: Arc<Database>,
+		when: Option<OffsetDateTime>,
+	) -> Result<Response<Body>, StatusCode> {
+		let form = form::OodleFile::from_request(req).await?;
+
+		let location = {
+			let mut oodles = db.oodles_mut().await;
+			oodles
+				.set_archived(&form.filename, when)
+				.await
+				.map_err(oodles_error_status)?;
+
+			oodle_location(oodles.oodle_by_file(&form.filename).unwrap())
+		};
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, location)
+			.status(302)
+			.body(Body::from("Oodle updated! Redirecting to it."))
+			.unwrap())
+	}
+
+	async fn oodle_message_move(
+		req: Request,
+		db: Arc<Database>,
+	) -> Result<Response<Body>, StatusCode> {
+		let form = form::MessageMove::from_request(req).await?;
+
+		let (location, id) = {
+			let mut oodles = db.oodles_mut().await;
+			let id = oodles
+				.move_message(&form.from, form.id, &form.to, now())
+				.await
+				.map_err(oodles_error_status)?;
+
+			(oodle_location(oodles.oodle_by_file(&form.to).unwrap()), id)
+		};
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::LOCATION, format!("{}#message-{}", location, id))
+			.status(302)
+			.body(Body::from("Message moved! Redirecting to where it went."))
+			.unwrap())
+	}
+
 	//TODO: gen- Error handling
 	async fn oodle_view(
 		req: Request,
 		db: Arc<Database>,
-		name: String,
+		slug: String,
 		session: Option<Session>,
 	) -> Result<Response<Body>, StatusCode> {
-		println!("Reqested oodle: {}", name);
+		println!("Reqested oodle: {}", slug);
 
 		let oodles = db.oodles().await;
-		let oodle = oodles.get_oodle_by_name(name).unwrap();
+		let oodle = match oodles.oodle_by_slug(&slug) {
+			Some(oodle) => oodle,
+			None => {
+				// Send links to an old slug, or from before slugs, to the current one
+				let moved = oodles
+					.oodle_by_old_slug(&slug)
+					.or_else(|| oodles.get_oodle_by_name(&slug))
+					.ok_or(StatusCode::NOT_FOUND)?;
+
+				return Ok(Response::builder()
+					.header(header::LOCATION, oodle_location(moved))
+					.status(StatusCode::MOVED_PERMANENTLY)
+					.body(Body::default())
+					.unwrap());
+			}
+		};
 
 		let mut tpl = Template::file("web/oodle.html").await;
-		tpl.set("name", oodle.name.clone());
+		tpl.set("name", Html::escape(&oodle.name));
+
+		let tags = oodle.tags();
+		if !tags.is_empty() {
+			tpl.set("tags", Html::tags(&tags));
+		}
 
 		if let Some(sesh) = session {
-			tpl.set("username", sesh.username);
+			tpl.set("username", Html::escape(sesh.username));
+			tpl.set("csrf", Html::escape(sesh.csrf_token));
 			tpl.set(
 				"filename",
-				oodle.file.file_name().unwrap().to_string_lossy(),
+				Html::escape(oodle.file.file_name().unwrap().to_string_lossy()),
 			);
+			tpl.set("slug", Html::escape(oodle.slug().unwrap_or_default()));
+
+			if oodle.is_archived() {
+				tpl.set("archived", "archived");
+			}
+
+			match oodle.renderer() {
+				Some(renderer) => tpl.set("renderer", renderer),
+				None => tpl.set("renderer", "default"),
+			}
+
+			tpl.set("tag_list", Html::escape(tags.join(" ")));
 		}
 
-		for msg in oodle.messages.iter() {
+		let renderer = oodle.renderer().unwrap_or(oodles.default_renderer());
+		for msg in oodle.messages.iter().filter(|msg| !msg.is_deleted()) {
 			let mut pattern = tpl.document.get_pattern("message").unwrap();
 
 			//TODO: gen- actually format the date
 			pattern.set("date", msg.date.format(DATETIME_FORMAT).unwrap());
-			pattern.set("message", msg.content.replace("\n", "<br>"));
+			pattern.set("message", Html::render(&msg.content, renderer));
 			pattern.set("message_id", format!("{}", msg.id));
 
+			if let Some(edited) = msg.edited() {
+				pattern.set("edited", edited.format(DATETIME_FORMAT).unwrap());
+			}
+
+			if msg.attachments().next().is_some() {
+				pattern.set("attachments", Html::attachments(&msg.attachment_details()));
+			}
+
+			let tags = msg.tags();
+			if !tags.is_empty() {
+				pattern.set("tags", Html::tags(&tags));
+			}
+
 			tpl.document.set_pattern("message", pattern);
 		}
 
@@ -323,29 +1044,30 @@ impl Svc {
 
 			let tpl = {
 				let mut oodles = db.oodles_mut().await;
+				let default_renderer = oodles.default_renderer();
 				let oodle = oodles
-					.oodle_by_file_mut(json.filename)
+					.oodle_by_file_mut(&json.filename)
 					.ok_or(StatusCode::NOT_FOUND)?;
+				let renderer = oodle.renderer().unwrap_or(default_renderer);
 
 				let message = Message::new_now(json.content, offset!(-5));
 
 				let mut tpl = Template::file("web/oodle_message.html").await;
 
 				if let Some(se) = session {
-					tpl.set("username", se.username);
+					tpl.set("username", Html::escape(se.username));
 				}
 
-				tpl.set("message", message.content.replace("\n", "<br>"));
+				tpl.set("message", Html::render(&message.content, renderer));
 				tpl.set("date", message.date.format(DATETIME_FORMAT).unwrap());
 
-				let id = oodle.push_message(message);
-
-				tpl.set("message_id", id);
-
-				oodle
-					.save()
+				let id = oodle
+					.append_message(message)
 					.await
 					.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+				oodles.reindex(&json.filename);
+
+				tpl.set("message_id", id);
 
 				tpl
 			};
@@ -353,36 +1075,88 @@ impl Svc {
 			tpl.as_response()
 				.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 		} else {
-			//TODO: gen- check the user actually has permission to add a message!
 			let form = form::MessageCreate::from_request(req).await?;
 
-			let name = {
+			let location = {
 				let mut oodles = db.oodles_mut().await;
 				let oodle = oodles
-					.oodle_by_file_mut(form.filename)
+					.oodle_by_file_mut(&form.filename)
 					.ok_or(StatusCode::NOT_FOUND)?;
 
-				oodle.push_message(Message::new_now(form.content, offset!(-5)));
 				oodle
-					.save()
+					.append_message(Message::new_now(form.content, offset!(-5)))
 					.await
 					.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-				oodle.name.clone()
+				let location = oodle_location(oodle);
+
+				oodles.reindex(&form.filename);
+				location
 			};
 
 			Ok(Response::builder()
 				.status(200)
-				.header(header::LOCATION, format!("/oodles/{}", name))
+				.header(header::LOCATION, location)
 				.status(302)
 				.body(Body::from("Oodle updated! Redirecting back to page"))
 				.unwrap())
 		}
 	}
 
-	async fn oodle_message_get(
+	async fn oodle_message_insert(
 		req: Request,
 		db: Arc<Database>,
 		session: Option<Session>,
+	) -> Result<Response<Body>, StatusCode> {
+		let json = matches!(req.query(), Some(Ok(q)) if q.has_bool("json"));
+
+		let form = if json {
+			req.json::<form::MessageInsert>()
+				.await
+				.map_err(|_| StatusCode::BAD_REQUEST)?
+		} else {
+			form::MessageInsert::from_request(req).await?
+		};
+
+		let mut oodles = db.oodles_mut().await;
+		let default_renderer = oodles.default_renderer();
+		let oodle = oodles
+			.oodle_by_file_mut(&form.filename)
+			.ok_or(StatusCode::NOT_FOUND)?;
+		let renderer = oodle.renderer().unwrap_or(default_renderer);
+
+		let id = oodle
+			.insert_message_after(form.after, Message::new_now(form.content, offset!(-5)))
+			.await
+			.map_err(edit_error_status)?;
+
+		oodles.reindex(&form.filename);
+		let oodle = oodles.oodle_by_file(&form.filename).unwrap();
+
+		if json {
+			let mut tpl = Self::render_message(oodle.message(id).unwrap(), renderer).await;
+
+			if let Some(se) = session {
+				tpl.set("username", Html::escape(se.username));
+			}
+
+			tpl.as_response()
+				.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+		} else {
+			Ok(Response::builder()
+				.status(200)
+				.header(
+					header::LOCATION,
+					format!("{}#message-{}", oodle_location(oodle), id),
+				)
+				.status(302)
+				.body(Body::from("Message inserted! Redirecting back to page"))
+				.unwrap())
+		}
+	}
+
+	async fn oodle_message_get(
+		req: Request,
+		db: Arc<Database>,
 	) -> Result<Response<Body>, StatusCode> {
 		//TODO: gen- check the user actually has permission to get this message!
 		let query = QueryWrapper::from_uri_query(&req)?;
@@ -393,7 +1167,10 @@ impl Svc {
 		let oodle = oodles
 			.oodle_by_file(filename)
 			.ok_or(StatusCode::NOT_FOUND)?;
-		let message = oodle.message(message_id).ok_or(StatusCode::NOT_FOUND)?;
+		let message = oodle
+			.message(message_id)
+			.filter(|msg| !msg.is_deleted())
+			.ok_or(StatusCode::NOT_FOUND)?;
 
 		Ok(Response::builder()
 			.status(200)
@@ -402,49 +1179,170 @@ impl Svc {
 			.unwrap())
 	}
 
-	async fn render_message(message: &Message) -> Template {
+	async fn render_message(message: &Message, renderer: Renderer) -> Template {
 		let mut tpl = Template::file("web/oodle_message.html").await;
 
-		tpl.set("message", message.content.replace("\n", "<br>"));
+		tpl.set("message", Html::render(&message.content, renderer));
 		tpl.set("date", message.date.format(DATETIME_FORMAT).unwrap());
 		tpl.set("message_id", message.id);
 
+		if let Some(edited) = message.edited() {
+			tpl.set("edited", edited.format(DATETIME_FORMAT).unwrap());
+		}
+
+		if message.attachments().next().is_some() {
+			tpl.set(
+				"attachments",
+				Html::attachments(&message.attachment_details()),
+			);
+		}
+
+		let tags = message.tags();
+		if !tags.is_empty() {
+			tpl.set("tags", Html::tags(&tags));
+		}
+
 		tpl
 	}
 
+	/// Attach the files in a multipart upload to a message. The oodle and
+	/// message are in the query, as `filename` and `id`.
+	async fn oodle_message_attach(
+		req: Request,
+		db: Arc<Database>,
+	) -> Result<Response<Body>, StatusCode> {
+		let query = QueryWrapper::from_uri_query(&req)?;
+		let filename = query.get_first_value("filename")?;
+		let id: usize = query.parse_first_value("id")?;
+		let json = matches!(req.query(), Some(Ok(q)) if q.has_bool("json"));
+
+		let boundary = req
+			.inner
+			.headers()
+			.get(header::CONTENT_TYPE)
+			.and_then(|ct| ct.to_str().ok())
+			.and_then(|ct| multer::parse_boundary(ct).ok())
+			.ok_or(StatusCode::BAD_REQUEST)?;
+
+		let constraints = multer::Constraints::new()
+			.size_limit(multer::SizeLimit::new().whole_stream(MAX_ATTACHMENT_SIZE));
+		let mut multipart =
+			multer::Multipart::with_constraints(req.inner.into_body(), boundary, constraints);
+
+		let mut attached = vec![];
+		loop {
+			let field = match multipart.next_field().await {
+				Ok(Some(field)) => field,
+				Ok(None) => break,
+				Err(e) => return Err(multipart_error_status(e)),
+			};
+
+			let original_name = match field.file_name() {
+				Some(name) if !name.is_empty() => name.to_owned(),
+				// Not a file, or no file was chosen
+				_ => continue,
+			};
+			let data = field.bytes().await.map_err(multipart_error_status)?;
+
+			let name = db
+				.oodles_mut()
+				.await
+				.attach_file(&filename, id, &original_name, &data)
+				.await
+				.map_err(oodles_error_status)?;
+			attached.push(name);
+		}
+
+		if json {
+			Ok(Response::builder()
+				.status(200)
+				.header("content-type", "application/json")
+				.body(Body::from(serde_json::to_string(&attached).unwrap()))
+				.unwrap())
+		} else {
+			let location = {
+				let oodles = db.oodles().await;
+				let oodle = oodles.oodle_by_file(&filename).unwrap();
+				format!("{}#message-{}", oodle_location(oodle), id)
+			};
+
+			Ok(Response::builder()
+				.status(200)
+				.header(header::LOCATION, location)
+				.status(302)
+				.body(Body::from("Attached! Redirecting back to the message."))
+				.unwrap())
+		}
+	}
+
+	async fn attachment_get(path: Option<PathBuf>) -> Result<Response<Body>, StatusCode> {
+		let path = path.ok_or(StatusCode::NOT_FOUND)?;
+
+		let data = tokio::fs::read(&path).await.map_err(|e| {
+			eprintln!(
+				"Failed to read attachment {}: {}",
+				path.to_string_lossy(),
+				e
+			);
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+		let mime = mime_guess::from_path(&path).first_or_octet_stream();
+		// Only show things inline that can't run script in our origin
+		let inline = match mime.type_() {
+			mime::IMAGE => mime.subtype() != mime::SVG,
+			mime::VIDEO | mime::AUDIO => true,
+			mime::TEXT => mime.subtype() == mime::PLAIN,
+			_ => mime == mime::APPLICATION_PDF,
+		};
+		let disposition = if inline { "inline" } else { "attachment" };
+
+		Ok(Response::builder()
+			.status(200)
+			.header(header::CONTENT_TYPE, mime.as_ref())
+			.header(header::CONTENT_DISPOSITION, disposition)
+			.header("X-Content-Type-Options", "nosniff")
+			.body(Body::from(data))
+			.unwrap())
+	}
+
 	async fn oodle_message_modify(
 		req: Request,
 		db: Arc<Database>,
 		session: Option<Session>,
 	) -> Result<Response<Body>, StatusCode> {
-		//TODO: gen- check the user actually has permission to add a message!
 		let query: Query = req.query().unwrap().unwrap();
 		if query.has_bool("json") {
 			let json: form::MessageModify =
 				req.json().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+			json.check_tags()?;
 
 			let mut tpl = {
 				let mut oodles = db.oodles_mut().await;
+				let default_renderer = oodles.default_renderer();
 				let oodle = oodles
-					.oodle_by_file_mut(json.filename)
+					.oodle_by_file_mut(&json.filename)
 					.ok_or(StatusCode::NOT_FOUND)?;
-
-				let tpl = {
-					let msg = oodle.message_mut(json.id).ok_or(StatusCode::NOT_FOUND)?;
-					msg.content = json.content;
-					Self::render_message(msg).await
-				};
+				let renderer = oodle.renderer().unwrap_or(default_renderer);
 
 				oodle
-					.save()
+					.edit_message(
+						json.id,
+						json.content,
+						&json.attachments,
+						json.tags.as_deref(),
+						now(),
+					)
 					.await
-					.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+					.map_err(edit_error_status)?;
+				oodles.reindex(&json.filename);
 
-				tpl
+				let oodle = oodles.oodle_by_file(&json.filename).unwrap();
+				Self::render_message(oodle.message(json.id).unwrap(), renderer).await
 			};
 
 			if let Some(se) = session {
-				tpl.set("username", se.username);
+				tpl.set("username", Html::escape(se.username));
 			}
 
 			tpl.as_response()
@@ -452,53 +1350,339 @@ impl Svc {
 		} else {
 			let form = form::MessageModify::from_request(req).await?;
 
-			let name = {
+			let location = {
+				let mut oodles = db.oodles_mut().await;
+				let oodle = oodles
+					.oodle_by_file_mut(&form.filename)
+					.ok_or(StatusCode::NOT_FOUND)?;
+
+				oodle
+					.edit_message(
+						form.id,
+						form.content,
+						&form.attachments,
+						form.tags.as_deref(),
+						now(),
+					)
+					.await
+					.map_err(edit_error_status)?;
+				let location = oodle_location(oodle);
+
+				oodles.reindex(&form.filename);
+				location
+			};
+
+			Ok(Response::builder()
+				.status(200)
+				.header(header::LOCATION, location)
+				.status(302)
+				.body(Body::from("Oodle updated! Redirecting back to page"))
+				.unwrap())
+		}
+	}
+
+	async fn oodle_message_delete(
+		req: Request,
+		db: Arc<Database>,
+	) -> Result<Response<Body>, StatusCode> {
+		let json = matches!(req.query(), Some(Ok(q)) if q.has_bool("json"));
+
+		let form = if json {
+			req.json::<form::MessageDelete>()
+				.await
+				.map_err(|_| StatusCode::BAD_REQUEST)?
+		} else {
+			form::MessageDelete::from_request(req).await?
+		};
+
+		let location = {
+			let mut oodles = db.oodles_mut().await;
+			let oodle = oodles
+				.oodle_by_file_mut(&form.filename)
+				.ok_or(StatusCode::NOT_FOUND)?;
+
+			oodle
+				.delete_message(form.id, now())
+				.await
+				.map_err(edit_error_status)?;
+			let location = oodle_location(oodle);
+
+			oodles.reindex(&form.filename);
+			location
+		};
+
+		if json {
+			Ok(Response::builder()
+				.status(200)
+				.body(Body::default())
+				.unwrap())
+		} else {
+			Ok(Response::builder()
+				.status(200)
+				.header(header::LOCATION, location)
+				.status(302)
+				.body(Body::from("Message deleted! Redirecting back to page"))
+				.unwrap())
+		}
+	}
+
+	async fn oodle_message_revisions(
+		req: Request,
+		db: Arc<Database>,
+	) -> Result<Response<Body>, StatusCode> {
+		let query = QueryWrapper::from_uri_query(&req)?;
+		let filename = query.get_first_value("filename")?;
+		let message_id: usize = query.parse_first_value("id")?;
+
+		let oodles = db.oodles().await;
+		let oodle = oodles
+			.oodle_by_file(filename)
+			.ok_or(StatusCode::NOT_FOUND)?;
+
+		// A deleted message's old versions go with it
+		match oodle.message(message_id) {
+			Some(msg) if !msg.is_deleted() => (),
+			_ => return Err(StatusCode::NOT_FOUND),
+		}
+
+		let revisions = oodle.revisions(message_id).await.map_err(|e| {
+			eprintln!("Failed to read revisions: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+		Ok(Response::builder()
+			.status(200)
+			.header("content-type", "application/json")
+			.body(Body::from(serde_json::to_string(&revisions).unwrap()))
+			.unwrap())
+	}
+
+	async fn oodle_message_restore(
+		req: Request,
+		db: Arc<Database>,
+		session: Option<Session>,
+	) -> Result<Response<Body>, StatusCode> {
+		let json = matches!(req.query(), Some(Ok(q)) if q.has_bool("json"));
+		if json {
+			let json: form::MessageRestore =
+				req.json().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+
+			let mut tpl = {
 				let mut oodles = db.oodles_mut().await;
+				let default_renderer = oodles.default_renderer();
 				let oodle = oodles
-					.oodle_by_file_mut(form.filename)
+					.oodle_by_file_mut(&json.filename)
 					.ok_or(StatusCode::NOT_FOUND)?;
+				let renderer = oodle.renderer().unwrap_or(default_renderer);
 
 				oodle
-					.message_mut(form.id)
-					.ok_or(StatusCode::NOT_FOUND)?
-					.content = form.content;
+					.restore_revision(json.id, json.revision, now())
+					.await
+					.map_err(edit_error_status)?;
+				oodles.reindex(&json.filename);
+
+				let oodle = oodles.oodle_by_file(&json.filename).unwrap();
+				Self::render_message(oodle.message(json.id).unwrap(), renderer).await
+			};
+
+			if let Some(se) = session {
+				tpl.set("username", Html::escape(se.username));
+			}
+
+			tpl.as_response()
+				.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+		} else {
+			let form = form::MessageRestore::from_request(req).await?;
+
+			let location = {
+				let mut oodles = db.oodles_mut().await;
+				let oodle = oodles
+					.oodle_by_file_mut(&form.filename)
+					.ok_or(StatusCode::NOT_FOUND)?;
 
 				oodle
-					.save()
+					.restore_revision(form.id, form.revision, now())
 					.await
-					.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+					.map_err(edit_error_status)?;
+				let location = oodle_location(oodle);
 
-				oodle.name.clone()
+				oodles.reindex(&form.filename);
+				location
 			};
 
 			Ok(Response::builder()
 				.status(200)
-				.header(header::LOCATION, format!("/oodles/{}", name))
+				.header(header::LOCATION, location)
 				.status(302)
-				.body(Body::from("Oodle updated! Redirecting back to page"))
+				.body(Body::from("Message restored! Redirecting back to page"))
 				.unwrap())
 		}
 	}
 
 	async fn user_login(req: Request, db: Arc<Database>) -> Result<Response<Body>, StatusCode> {
+		let remote = req.remote;
 		let form = form::Login::from_request(req).await?;
 
-		let builder = Response::builder().status(200);
+		if let Err(wait) = db.start_login(remote, &form.username, Instant::now()).await {
+			return Ok(too_many_logins(wait));
+		}
 
-		Ok(
-			if db.verify_user_login(&form.username, &form.password).await {
-				let session = db.new_user_session(form.username).await;
+		if !db
+			.verify_user_login(remote, &form.username, &form.password)
+			.await
+		{
+			return Ok(Response::builder()
+				.status(200)
+				.body(Body::from("INVALID username or password"))
+				.unwrap());
+		}
 
-				builder
-					.header(header::SET_COOKIE, session.get_set_cookie())
-					.header(header::LOCATION, "/")
+		if db.has_second_factor(&form.username).await {
+			db.login_refunded(remote, &form.username).await;
+			let challenge = db.new_login_challenge(&form.username).await;
+			return Ok(Self::login_code_page(&challenge, false).await);
+		}
+
+		db.login_succeeded(remote, &form.username).await;
+		Ok(Self::logged_in(&db, form.username).await)
+	}
+
+	/// The second step of logging in, for people with a second factor: the
+	/// code from their authenticator, or a recovery code.
+	async fn user_login_code(
+		req: Request,
+		db: Arc<Database>,
+	) -> Result<Response<Body>, StatusCode> {
+		let remote = req.remote;
+		let form = form::LoginCode::from_request(req).await?;
+
+		let username = match db.login_challenge(&form.challenge).await {
+			Some(username) => username,
+			None => {
+				return Ok(Response::builder()
+					.header(header::LOCATION, "/login")
 					.status(302)
-					.body(Body::from("Login success! Redirecting to home."))
-			} else {
-				builder.body(Body::from("INVALID username or password"))
+					.body(Body::from("Login expired! Redirecting to login."))
+					.unwrap())
 			}
-			.unwrap(),
-		)
+		};
+
+		if let Err(wait) = db.start_login(remote, &username, Instant::now()).await {
+			return Ok(too_many_logins(wait));
+		}
+
+		match db
+			.verify_login_code(remote, &form.challenge, &form.code)
+			.await
+		{
+			Some(username) => {
+				db.login_succeeded(remote, &username).await;
+				Ok(Self::logged_in(&db, username).await)
+			}
+			None => Ok(Self::login_code_page(&form.challenge, true).await),
+		}
+	}
+
+	/// Ask for the code that finishes logging in, for the challenge given
+	/// after getting the password right.
+	async fn login_code_page(challenge: &str, failed: bool) -> Response<Body> {
+		let mut tpl = Template::file("web/login_code.html").await;
+		tpl.set("challenge", Html::escape(challenge));
+		if failed {
+			tpl.set("failed", "");
+		}
+
+		tpl.as_response().unwrap()
+	}
+
+	/// Start a session for someone who's logged in and send them home.
+	async fn logged_in(db: &Database, username: String) -> Response<Body> {
+		let session = db.new_user_session(username).await;
+
+		Response::builder()
+			.header(header::SET_COOKIE, session.get_set_cookie())
+			.header(header::LOCATION, "/")
+			.status(302)
+			.body(Body::from("Login success! Redirecting to home."))
+			.unwrap()
+	}
+
+	/// The sessions you're logged in with, to end any you don't recognise or
+	/// don't use anymore.
+	async fn user_sessions(db: Arc<Database>, session: Option<Session>) -> Response<Body> {
+		let session = match session {
+			Some(session) => session,
+			None => {
+				return Response::builder()
+					.header(header::LOCATION, "/login")
+					.status(302)
+					.body(Body::from("Not logged in! Redirecting to login."))
+					.unwrap()
+			}
+		};
+
+		let mut tpl = Template::file("web/sessions.html").await;
+		tpl.set("username", Html::escape(&session.username));
+
+		for sesh in db.user_sessions(&session.username).await {
+			let mut pattern = tpl.document.get_pattern("session").unwrap();
+			pattern.set("id", Html::escape(&sesh.id));
+			pattern.set(
+				"created",
+				sesh.created
+					.to_offset(now().offset())
+					.format(DATETIME_FORMAT)
+					.unwrap(),
+			);
+			pattern.set(
+				"last_seen",
+				sesh.last_seen
+					.to_offset(now().offset())
+					.format(DATETIME_FORMAT)
+					.unwrap(),
+			);
+			pattern.set(
+				"current",
+				if sesh.id == session.id {
+					" (this one)"
+				} else {
+					""
+				},
+			);
+			pattern.set("csrf", Html::escape(&session.csrf_token));
+			tpl.document.set_pattern("session", pattern);
+		}
+
+		tpl.as_response().unwrap()
+	}
+
+	/// End one of your sessions. Ending the one you're using logs you out.
+	async fn user_session_revoke(
+		req: Request,
+		db: Arc<Database>,
+		session: Option<Session>,
+	) -> Result<Response<Body>, StatusCode> {
+		let session = session.ok_or(StatusCode::UNAUTHORIZED)?;
+		let form = form::SessionRevoke::from_request(req).await?;
+
+		if !db.revoke_session(&session.username, &form.id).await {
+			return Err(StatusCode::NOT_FOUND);
+		}
+
+		let builder = Response::builder().status(302);
+		Ok(if form.id == session.id {
+			builder
+				.header(header::SET_COOKIE, session.get_clear_cookie())
+				.header(header::LOCATION, "/")
+				.body(Body::from(
+					"Session ended, you're logged out. Redirecting home.",
+				))
+		} else {
+			builder
+				.header(header::LOCATION, "/sessions")
+				.body(Body::from("Session ended! Redirecting back to sessions."))
+		}
+		.unwrap())
 	}
 
 	async fn user_logout(
@@ -523,3 +1707,1120 @@ impl Svc {
 			.unwrap())
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use std::{
+		net::IpAddr,
+		sync::Arc,
+		time::{Duration, Instant},
+	};
+
+	use hyper::{header, Body, Method, StatusCode};
+	use oodles::{Message, Renderer};
+	use time::{OffsetDateTime, UtcOffset};
+
+	use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+	use rand::rngs::OsRng;
+
+	use crate::{
+		authorize,
+		database::{Database, OodlesError, Users},
+		test_dir::TestDir,
+		throttle::{LoginLimits, LoginThrottle},
+		totp, Session, Svc, CSRF_HEADER,
+	};
+
+	/// Every route that changes something.
+	const WRITES: &[&str] = &[
+		"oodle/create",
+		"oodle/split",
+		"oodle/merge",
+		"oodle/rename",
+		"oodle/delete",
+		"oodle/restore",
+		"oodle/renderer",
+		"oodle/tags",
+		"oodle/archive",
+		"oodle/unarchive",
+		"oodle/message/move",
+		"oodle/message/create",
+		"oodle/message/modify",
+		"oodle/message/insert",
+		"oodle/message/delete",
+		"oodle/message/restore",
+		"oodle/message/attach",
+		"sessions/revoke",
+		"logout",
+	];
+
+	/// A database in its own temporary directory, with no users and one oodle,
+	/// `untouched.oodle`. The directory goes away when the [TestDir] is dropped.
+	async fn database(name: &str) -> (Arc<Database>, TestDir) {
+		let directory = TestDir::new(name);
+
+		let credentials = directory.join("creds");
+		std::fs::write(&credentials, "").unwrap();
+
+		let database = Database::get(
+			credentials,
+			&*directory,
+			Renderer::Plain,
+			LoginLimits::default(),
+		);
+		database.create_directories().await;
+		database
+			.oodles_mut()
+			.await
+			.new_oodle(
+				"Untouched",
+				None,
+				Message::new_now("Hello!", UtcOffset::UTC),
+			)
+			.await
+			.unwrap();
+
+		(Arc::new(database), directory)
+	}
+
+	/// Try every write with the cookie and CSRF token given, expecting each to
+	/// be refused with the status.
+	async fn assert_writes_rejected(
+		db: &Arc<Database>,
+		cookie: Option<&str>,
+		csrf: Option<&str>,
+		status: StatusCode,
+	) {
+		for path in WRITES {
+			for json in [false, true] {
+				let mut request = hyper::Request::builder()
+					.method(Method::POST)
+					.uri(format!("/{}?filename=untouched.oodle&id=0", path));
+
+				let body = if json {
+					request = request.uri(format!("/{}?json", path));
+					r#"{"filename":"untouched.oodle","id":0,"content":"Changed","title":"Changed"}"#
+				} else {
+					"filename=untouched.oodle&id=0&content=Changed&title=Changed&tags=changed"
+				};
+
+				if let Some(cookie) = cookie {
+					request = request.header(header::COOKIE, cookie);
+				}
+				if let Some(csrf) = csrf {
+					request = request.header(CSRF_HEADER, csrf);
+				}
+
+				let response =
+					Svc::task(request.body(Body::from(body)).unwrap().into(), db.clone()).await;
+				assert_eq!(response.status(), status, "{}", path);
+			}
+		}
+
+		let oodles = db.oodles().await;
+		let oodle = oodles.oodle_by_file("untouched.oodle").unwrap();
+		assert_eq!(oodle.name, "Untouched");
+		assert_eq!(oodle.messages.len(), 1);
+		assert_eq!(oodle.messages[0].content, "Hello!");
+		assert!(oodle.tags().is_empty());
+	}
+
+	#[tokio::test]
+	async fn anonymous_writes_are_rejected() {
+		let (db, _dir) = database("anonymous").await;
+		assert_writes_rejected(&db, None, None, StatusCode::UNAUTHORIZED).await;
+	}
+
+	#[tokio::test]
+	async fn forged_sessions_are_rejected() {
+		let (db, _dir) = database("forged").await;
+		assert_writes_rejected(&db, Some("sid=forged"), None, StatusCode::UNAUTHORIZED).await;
+
+		// A real session, ended, doesn't work either
+		let session = db.new_user_session("someone").await;
+		db.delete_session(session.cookie.clone()).await;
+		assert_writes_rejected(
+			&db,
+			Some(&format!("sid={}", session.cookie)),
+			Some(&session.csrf_token),
+			StatusCode::UNAUTHORIZED,
+		)
+		.await;
+	}
+
+	#[tokio::test]
+	async fn writes_need_the_csrf_token() {
+		let (db, _dir) = database("csrf-missing").await;
+		let session = db.new_user_session("someone").await;
+		let cookie = format!("sid={}", session.cookie);
+
+		assert_writes_rejected(&db, Some(&cookie), None, StatusCode::FORBIDDEN).await;
+		assert_writes_rejected(&db, Some(&cookie), Some("forged"), StatusCode::FORBIDDEN).await;
+
+		// Another session's token is no good either
+		let other = db.new_user_session("someone").await;
+		assert_writes_rejected(
+			&db,
+			Some(&cookie),
+			Some(&other.csrf_token),
+			StatusCode::FORBIDDEN,
+		)
+		.await;
+	}
+
+	#[tokio::test]
+	async fn csrf_token_lets_writes_through() {
+		let (db, _dir) = database("csrf-sent").await;
+		let session = db.new_user_session("someone").await;
+
+		let tag = |body: String, csrf: Option<&str>| {
+			let mut request = hyper::Request::builder()
+				.method(Method::POST)
+				.uri("/oodle/tags")
+				.header(header::COOKIE, format!("sid={}", session.cookie))
+				.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+			if let Some(csrf) = csrf {
+				request = request.header(CSRF_HEADER, csrf);
+			}
+
+			Svc::task(request.body(Body::from(body)).unwrap().into(), db.clone())
+		};
+
+		// From a fetch, in the header
+		let response = tag(
+			String::from("filename=untouched.oodle&tags=header"),
+			Some(&session.csrf_token),
+		)
+		.await;
+		assert_eq!(response.status(), StatusCode::FOUND);
+		assert_eq!(
+			db.oodles()
+				.await
+				.oodle_by_file("untouched.oodle")
+				.unwrap()
+				.tags(),
+			vec!["header"]
+		);
+
+		// From a form, in the body, which is still there for the handler
+		let body = format!(
+			"filename=untouched.oodle&tags=form&csrf={}",
+			session.csrf_token
+		);
+		let response = tag(body, None).await;
+		assert_eq!(response.status(), StatusCode::FOUND);
+		assert_eq!(
+			db.oodles()
+				.await
+				.oodle_by_file("untouched.oodle")
+				.unwrap()
+				.tags(),
+			vec!["form"]
+		);
+	}
+
+	#[tokio::test]
+	async fn deleted_messages_have_no_revisions() {
+		let (db, _dir) = database("revisions-deleted").await;
+		let session = db.new_user_session("someone").await;
+
+		let revisions = |cookie: Option<&str>| {
+			let mut request = hyper::Request::builder()
+				.uri("/oodle/message/revisions?filename=untouched.oodle&id=0");
+			if let Some(cookie) = cookie {
+				request = request.header(header::COOKIE, format!("sid={}", cookie));
+			}
+
+			Svc::task(request.body(Body::empty()).unwrap().into(), db.clone())
+		};
+
+		assert_eq!(revisions(None).await.status(), StatusCode::UNAUTHORIZED);
+		assert_eq!(
+			revisions(Some(&session.cookie)).await.status(),
+			StatusCode::OK
+		);
+
+		// The only message stays behind as a tombstone when it's deleted
+		db.oodles_mut()
+			.await
+			.oodle_by_file_mut("untouched.oodle")
+			.unwrap()
+			.delete_message(0, OffsetDateTime::now_utc())
+			.await
+			.unwrap();
+
+		assert_eq!(
+			revisions(Some(&session.cookie)).await.status(),
+			StatusCode::NOT_FOUND
+		);
+	}
+
+	#[tokio::test]
+	async fn revisions_are_restored_from_forms() {
+		let (db, _dir) = database("restore-form").await;
+		let session = db.new_user_session("someone").await;
+
+		db.oodles_mut()
+			.await
+			.oodle_by_file_mut("untouched.oodle")
+			.unwrap()
+			.edit_message(
+				0,
+				String::from("Edited"),
+				&[],
+				None,
+				OffsetDateTime::now_utc(),
+			)
+			.await
+			.unwrap();
+
+		// A plain form post, with nothing in the query
+		let request = hyper::Request::builder()
+			.method(Method::POST)
+			.uri("/oodle/message/restore")
+			.header(header::COOKIE, format!("sid={}", session.cookie))
+			.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+			.body(Body::from(format!(
+				"filename=untouched.oodle&id=0&revision=0&csrf={}",
+				session.csrf_token
+			)))
+			.unwrap();
+
+		let response = Svc::task(request.into(), db.clone()).await;
+		assert_eq!(response.status(), StatusCode::FOUND);
+		assert_eq!(
+			db.oodles()
+				.await
+				.oodle_by_file("untouched.oodle")
+				.unwrap()
+				.message(0)
+				.unwrap()
+				.content,
+			"Hello!"
+		);
+	}
+
+	#[tokio::test]
+	async fn split_messages_take_their_revisions() {
+		let (db, directory) = database("split-revisions").await;
+		let directory = directory.join("oodles");
+		let mut oodles = db.oodles_mut().await;
+
+		let now = OffsetDateTime::now_utc();
+		let untouched = oodles.oodle_by_file_mut("untouched.oodle").unwrap();
+		untouched
+			.append_message(Message::new_now("One", UtcOffset::UTC))
+			.await
+			.unwrap();
+		untouched
+			.edit_message(1, String::from("Edited"), &[], None, now)
+			.await
+			.unwrap();
+
+		// When cutting the old oodle short fails its revisions are put back
+		let revisions = directory.join("untouched.oodle.revisions");
+		let before = std::fs::read_to_string(&revisions).unwrap();
+		std::fs::create_dir(directory.join(".untouched.oodle.tmp")).unwrap();
+		assert!(oodles
+			.split_oodle("untouched.oodle", 1, "Tangent", "tangent.oodle")
+			.await
+			.is_err());
+		assert_eq!(std::fs::read_to_string(&revisions).unwrap(), before);
+		std::fs::remove_dir(directory.join(".untouched.oodle.tmp")).unwrap();
+
+		oodles
+			.split_oodle("untouched.oodle", 1, "Tangent", "tangent.oodle")
+			.await
+			.unwrap();
+		let tangent = oodles.oodle_by_file("tangent.oodle").unwrap();
+		assert_eq!(tangent.revisions(0).await.unwrap()[0].content, "One");
+
+		// The next message gets the id the split one had, but not its history
+		let untouched = oodles.oodle_by_file_mut("untouched.oodle").unwrap();
+		let id = untouched
+			.append_message(Message::new_now("Another", UtcOffset::UTC))
+			.await
+			.unwrap();
+		assert_eq!(id, 1);
+		assert!(untouched.revisions(1).await.unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn failed_merges_and_moves_change_nothing() {
+		let (db, directory) = database("merge-failed").await;
+		let directory = directory.join("oodles");
+		let mut oodles = db.oodles_mut().await;
+
+		let now = OffsetDateTime::now_utc();
+		oodles
+			.new_oodle(
+				"Other",
+				Some("other.oodle"),
+				Message::new_now("Elsewhere", UtcOffset::UTC),
+			)
+			.await
+			.unwrap();
+		for file in ["untouched.oodle", "other.oodle"] {
+			oodles
+				.oodle_by_file_mut(file)
+				.unwrap()
+				.edit_message(0, String::from("Edited"), &[], None, now)
+				.await
+				.unwrap();
+		}
+
+		let on_disk = || {
+			[
+				"untouched.oodle",
+				"other.oodle",
+				"untouched.oodle.revisions",
+				"other.oodle.revisions",
+			]
+			.map(|file| std::fs::read_to_string(directory.join(file)).unwrap())
+		};
+		let before = on_disk();
+
+		// A directory where the temporary file goes makes saving an oodle fail.
+		// The merged revisions are written by then.
+		std::fs::create_dir(directory.join(".untouched.oodle.tmp")).unwrap();
+		assert!(oodles
+			.merge_oodles("other.oodle", "untouched.oodle")
+			.await
+			.is_err());
+		assert_eq!(on_disk(), before);
+		std::fs::remove_dir(directory.join(".untouched.oodle.tmp")).unwrap();
+
+		// Here it's deleting from the old oodle that fails, after the message
+		// has been put in the new one
+		std::fs::create_dir(directory.join(".other.oodle.tmp")).unwrap();
+		assert!(oodles
+			.move_message("other.oodle", 0, "untouched.oodle", now)
+			.await
+			.is_err());
+		assert_eq!(on_disk(), before);
+		std::fs::remove_dir(directory.join(".other.oodle.tmp")).unwrap();
+
+		let untouched = oodles.oodle_by_file("untouched.oodle").unwrap();
+		assert_eq!(untouched.messages.len(), 1);
+		assert_eq!(untouched.revisions(0).await.unwrap().len(), 1);
+
+		// With nothing in the way, it all goes through
+		oodles
+			.merge_oodles("other.oodle", "untouched.oodle")
+			.await
+			.unwrap();
+		let merged = oodles.oodle_by_file("untouched.oodle").unwrap();
+		assert_eq!(merged.messages.len(), 2);
+		assert_eq!(merged.revisions(1).await.unwrap()[0].content, "Elsewhere");
+		assert!(!directory.join("other.oodle").exists());
+	}
+
+	#[tokio::test]
+	async fn paths_are_not_filenames() {
+		let (db, _dir) = database("bad-filenames").await;
+
+		for filename in [
+			"",
+			"..",
+			"%2F",
+			"..%2Funtouched.oodle",
+			"x%2F..%2Funtouched.oodle",
+		] {
+			let request = hyper::Request::builder()
+				.uri(format!("/oodle/message/get?filename={}&id=0", filename))
+				.body(Body::empty())
+				.unwrap();
+
+			let response = Svc::task(request.into(), db.clone()).await;
+			assert_eq!(response.status(), StatusCode::NOT_FOUND, "{:?}", filename);
+		}
+	}
+
+	#[tokio::test]
+	async fn failed_renames_stay_where_they_were() {
+		let (db, directory) = database("rename-failed").await;
+		let directory = directory.join("oodles");
+		let mut oodles = db.oodles_mut().await;
+
+		oodles
+			.oodle_by_file_mut("untouched.oodle")
+			.unwrap()
+			.edit_message(
+				0,
+				String::from("Edited"),
+				&[],
+				None,
+				OffsetDateTime::now_utc(),
+			)
+			.await
+			.unwrap();
+
+		// A directory where the temporary file goes makes saving the new title
+		// fail, after the oodle has been moved
+		std::fs::create_dir(directory.join(".renamed.oodle.tmp")).unwrap();
+		assert!(oodles
+			.rename_oodle(
+				"untouched.oodle",
+				Some(String::from("Renamed")),
+				None,
+				Some("renamed.oodle")
+			)
+			.await
+			.is_err());
+		std::fs::remove_dir(directory.join(".renamed.oodle.tmp")).unwrap();
+
+		assert!(directory.join("untouched.oodle").exists());
+		assert!(directory.join("untouched.oodle.revisions").exists());
+		assert!(!directory.join("renamed.oodle").exists());
+		assert!(!directory.join("renamed.oodle.revisions").exists());
+
+		// and it can be tried again from where it is
+		let untouched = oodles.oodle_by_file("untouched.oodle").unwrap();
+		assert_eq!(untouched.name, "Untouched");
+		assert_eq!(untouched.revisions(0).await.unwrap().len(), 1);
+		assert_eq!(
+			oodles
+				.rename_oodle(
+					"untouched.oodle",
+					Some(String::from("Renamed")),
+					None,
+					Some("renamed.oodle")
+				)
+				.await
+				.unwrap(),
+			"renamed.oodle"
+		);
+		assert_eq!(
+			oodles.oodle_by_file("renamed.oodle").unwrap().name,
+			"Renamed"
+		);
+	}
+
+	#[tokio::test]
+	async fn titles_and_trashed_names_are_checked() {
+		let (db, directory) = database("titles").await;
+		let mut oodles = db.oodles_mut().await;
+
+		for title in ["Two\nlines", "Ends =- early"] {
+			let renamed = oodles
+				.rename_oodle("untouched.oodle", Some(title.into()), None, None)
+				.await;
+			assert!(
+				matches!(renamed, Err(OodlesError::BadTitle(_))),
+				"{:?}",
+				title
+			);
+		}
+		assert_eq!(
+			oodles.oodle_by_file("untouched.oodle").unwrap().name,
+			"Untouched"
+		);
+
+		let trashed = oodles.trash_oodle("../oodles/untouched.oodle").await;
+		assert!(matches!(trashed, Err(OodlesError::BadFilename(_))));
+
+		oodles.trash_oodle("untouched.oodle").await.unwrap();
+		assert!(directory.join("trash").join("untouched.oodle").exists());
+	}
+
+	#[tokio::test]
+	async fn loading_leaves_files_alone() {
+		let (db, directory) = database("load-untouched").await;
+		let path = directory.join("oodles").join("old.oodle");
+
+		// From before slugs, and saved on Windows
+		let written = "-= Old Times =-\r\n\r\n2022-06-01 13:45:00-0500\r\nHello\r\n.\r\n";
+		std::fs::write(&path, written).unwrap();
+
+		let mut oodles = db.oodles_mut().await;
+		oodles.load_oodles().await;
+
+		let old = oodles.oodle_by_file("old.oodle").unwrap();
+		assert_eq!(old.slug(), Some("old-times"));
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), written);
+	}
+
+	/// A request carrying a session's cookie.
+	fn with_cookie(session: &Session) -> hyper::Request<Body> {
+		hyper::Request::builder()
+			.header(header::COOKIE, format!("sid={}", session.cookie))
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn sessions_outlive_a_restart() {
+		let (db, directory) = database("sessions-kept").await;
+		let session = db.new_user_session("someone").await;
+		let revoked = db.new_user_session("someone").await;
+		assert_eq!(session.cookie.len(), 32);
+
+		let response = Svc::task(
+			hyper::Request::builder()
+				.method(Method::POST)
+				.uri("/sessions/revoke")
+				.header(header::COOKIE, format!("sid={}", session.cookie))
+				.header(CSRF_HEADER, &session.csrf_token)
+				.body(Body::from(format!("id={}", revoked.id)))
+				.unwrap()
+				.into(),
+			db.clone(),
+		)
+		.await;
+		assert_eq!(response.status(), StatusCode::FOUND);
+		assert_eq!(db.user_sessions("someone").await.len(), 1);
+
+		let restarted = Database::get(
+			directory.join("creds"),
+			&*directory,
+			Renderer::Plain,
+			LoginLimits::default(),
+		);
+
+		let kept = restarted.get_session(&with_cookie(&session)).await.unwrap();
+		assert_eq!(kept.id, session.id);
+		assert_eq!(kept.username, "someone");
+		assert_eq!(kept.csrf_token, session.csrf_token);
+		assert!(restarted
+			.get_session(&with_cookie(&revoked))
+			.await
+			.is_none());
+	}
+
+	#[test]
+	fn sessions_expire() {
+		let session = Session::new(String::from("sid"), String::from("someone"));
+		let now = session.created;
+
+		assert!(!session.expired(now));
+		assert!(!session.expired(now + Session::IDLE_TIMEOUT / 2));
+		assert!(session.expired(now + Session::IDLE_TIMEOUT));
+
+		// Staying busy doesn't keep it forever
+		let mut busy = session.clone();
+		busy.last_seen = now + Session::LIFETIME - Session::IDLE_TIMEOUT / 2;
+		assert!(!busy.expired(busy.last_seen));
+		assert!(busy.expired(now + Session::LIFETIME));
+	}
+
+	#[test]
+	fn sessions_are_only_written_when_they_change() {
+		let dir = TestDir::new("session-peek");
+		let credentials = dir.join("creds");
+		std::fs::write(&credentials, "").unwrap();
+
+		let mut users = Users::load_file(&credentials);
+		users.load_sessions(dir.join(Users::SESSION_FILE));
+		let session = users.new_session("someone").clone();
+		let created = users.session_snapshot().unwrap();
+
+		// Seen a moment ago, so there's nothing to change and reading is enough
+		let now = session.last_seen + Duration::from_secs(1);
+		let peeked = users.peek_session(&session.cookie, now).unwrap();
+		assert_eq!(peeked.map(|sesh| &sesh.id), Some(&session.id));
+		assert!(matches!(users.peek_session("nobody", now), Some(None)));
+		users.get_session(&session.cookie, now).unwrap();
+		assert_eq!(
+			users.session_snapshot().unwrap().generation,
+			created.generation
+		);
+
+		// Long enough ago that it's marked seen again, which is a change
+		let later = session.last_seen + Session::IDLE_TIMEOUT / 2;
+		assert!(users.peek_session(&session.cookie, later).is_none());
+		users.get_session(&session.cookie, later).unwrap();
+		assert!(users.session_snapshot().unwrap().generation > created.generation);
+
+		// As is one expiring, even when it's not the one asked for
+		let expired = session.last_seen + Session::LIFETIME;
+		assert!(users.peek_session("nobody", expired).is_none());
+	}
+
+	#[test]
+	fn failed_logins_back_off_then_lock_out() {
+		let limits = LoginLimits {
+			free_attempts: 2,
+			backoff: Duration::from_secs(10),
+			lockout_attempts: 5,
+			lockout: Duration::from_secs(600),
+		};
+		let mut throttle = LoginThrottle::new(limits);
+		let address = Some(IpAddr::from([192, 0, 2, 1]));
+		let start = Instant::now();
+
+		let fail = |throttle: &mut LoginThrottle, count: u64| {
+			throttle.failed(address, "someone", start + Duration::from_secs(count));
+			throttle.check(address, "someone", start + Duration::from_secs(count))
+		};
+
+		assert_eq!(fail(&mut throttle, 1), Ok(()));
+		assert_eq!(fail(&mut throttle, 2), Ok(()));
+		assert_eq!(fail(&mut throttle, 3), Err(Duration::from_secs(10)));
+		assert_eq!(fail(&mut throttle, 4), Err(Duration::from_secs(20)));
+		assert_eq!(fail(&mut throttle, 5), Err(Duration::from_secs(600)));
+
+		// The address is locked out whichever username it tries, and the
+		// username wherever it's tried from
+		let later = start + Duration::from_secs(60);
+		assert!(throttle.check(address, "someone-else", later).is_err());
+		assert!(throttle.check(None, "someone", later).is_err());
+		assert_eq!(throttle.check(None, "someone-else", later), Ok(()));
+
+		// It runs out, and then counting starts over
+		let after = start + Duration::from_secs(605);
+		assert_eq!(throttle.check(address, "someone", after), Ok(()));
+		assert_eq!(fail(&mut throttle, 606), Ok(()));
+		assert_eq!(fail(&mut throttle, 607), Ok(()));
+		assert!(fail(&mut throttle, 608).is_err());
+
+		// Logging in forgets the failures
+		throttle.succeeded(address, "someone");
+		let now = start + Duration::from_secs(608);
+		assert_eq!(throttle.check(address, "someone", now), Ok(()));
+
+		// Attempts are counted before they're made, and can be taken back
+		assert_eq!(throttle.attempt(address, "someone", now), Ok(()));
+		assert_eq!(throttle.attempt(address, "someone", now), Ok(()));
+		throttle.refund(address, "someone");
+		assert_eq!(throttle.attempt(address, "someone", now), Ok(()));
+		assert_eq!(throttle.attempt(address, "someone", now), Ok(()));
+		assert!(throttle.attempt(address, "someone", now).is_err());
+	}
+
+	#[tokio::test]
+	async fn guessing_passwords_is_throttled() {
+		let (db, _dir) = database("login-throttled").await;
+		let free_attempts = LoginLimits::default().free_attempts;
+
+		let login = |username: &str| {
+			let mut request: crate::Request = hyper::Request::builder()
+				.method(Method::POST)
+				.uri("/login")
+				.body(Body::from(format!("username={}&password=guess", username)))
+				.unwrap()
+				.into();
+			request.remote = Some(IpAddr::from([192, 0, 2, 1]));
+
+			Svc::task(request, db.clone())
+		};
+
+		for _ in 0..=free_attempts {
+			assert_eq!(login("someone").await.status(), StatusCode::OK);
+		}
+
+		let throttled = login("someone-else").await;
+		assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+		assert_eq!(throttled.headers()[header::RETRY_AFTER], "1");
+	}
+
+	#[tokio::test]
+	async fn logins_from_other_sites_are_refused() {
+		let (db, _dir) = database("login-origin").await;
+
+		let login = |origin: Option<&str>| {
+			let mut request = hyper::Request::builder()
+				.method(Method::POST)
+				.uri("/login")
+				.header(header::HOST, "oodles.example:8080");
+			if let Some(origin) = origin {
+				request = request.header(header::ORIGIN, origin);
+			}
+
+			let request = request
+				.body(Body::from("username=someone&password=guess"))
+				.unwrap();
+			Svc::task(request.into(), db.clone())
+		};
+
+		let refused = [Some("https://evil.example"), Some("null")];
+		for origin in refused {
+			assert_eq!(login(origin).await.status(), StatusCode::FORBIDDEN);
+		}
+
+		let allowed = [Some("http://oodles.example:8080"), None];
+		for origin in allowed {
+			assert_eq!(login(origin).await.status(), StatusCode::OK);
+		}
+	}
+
+	#[tokio::test]
+	async fn csrf_tokens_in_the_query_are_ignored() {
+		let (db, _dir) = database("csrf-query").await;
+		let session = db.new_user_session("someone").await;
+
+		let request = hyper::Request::builder()
+			.method(Method::POST)
+			.uri(format!(
+				"/oodle/message/attach?filename=untouched.oodle&id=0&csrf={}",
+				session.csrf_token
+			))
+			.header(header::COOKIE, format!("sid={}", session.cookie))
+			.body(Body::empty())
+			.unwrap();
+		let response = Svc::task(request.into(), db.clone()).await;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn guesses_at_once_are_all_counted() {
+		// Long enough that no wait runs out while the guesses are being hashed
+		let limits = LoginLimits {
+			backoff: Duration::from_secs(60 * 60),
+			..LoginLimits::default()
+		};
+		let (_, _, _, directory) = database_with_second_factor("login-at-once").await;
+		let db = Arc::new(Database::get(
+			directory.join("creds"),
+			&*directory,
+			Renderer::Plain,
+			limits,
+		));
+		let free_attempts = limits.free_attempts;
+
+		let guesses: Vec<_> = (0..free_attempts + 4)
+			.map(|_| {
+				let mut request: crate::Request = hyper::Request::builder()
+					.method(Method::POST)
+					.uri("/login")
+					.body(Body::from("username=someone&password=guess"))
+					.unwrap()
+					.into();
+				request.remote = Some(IpAddr::from([192, 0, 2, 1]));
+
+				tokio::spawn(Svc::task(request, db.clone()))
+			})
+			.collect();
+
+		let mut let_through = 0;
+		for guess in guesses {
+			if guess.await.unwrap().status() == StatusCode::OK {
+				let_through += 1;
+			}
+		}
+
+		// Only as many as could have been made one after another
+		assert_eq!(let_through, free_attempts + 1);
+	}
+
+	#[test]
+	fn login_codes_match_rfc_6238() {
+		// The SHA1 test vectors from the RFC, cut to six digits
+		let secret = b"12345678901234567890";
+		let vectors = [
+			(59, 287082),
+			(1111111109, 81804),
+			(1111111111, 50471),
+			(1234567890, 5924),
+			(2000000000, 279037),
+		];
+		for (time, code) in vectors {
+			assert_eq!(totp::code_at(secret, totp::step_at(time)), code);
+		}
+
+		// A step either side is fine, but not further, and not twice
+		let step = totp::step_at(1111111109);
+		assert_eq!(totp::verify(secret, "081804", 1111111109, None), Some(step));
+		assert_eq!(
+			totp::verify(secret, "081804", 1111111109 + totp::STEP, None),
+			Some(step)
+		);
+		assert_eq!(
+			totp::verify(secret, "081804", 1111111109 + 2 * totp::STEP, None),
+			None
+		);
+		assert_eq!(totp::verify(secret, "081804", 1111111109, Some(step)), None);
+		assert_eq!(totp::verify(secret, "81804", 1111111109, None), None);
+
+		let encoded = totp::encode_secret(secret);
+		assert_eq!(encoded, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+		assert_eq!(
+			totp::decode_secret(&encoded.to_lowercase()).unwrap(),
+			secret
+		);
+		assert_eq!(
+			totp::otpauth_uri("some one", secret),
+			"otpauth://totp/Oodles:some%20one?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+			&issuer=Oodles&algorithm=SHA1&digits=6&period=30"
+		);
+	}
+
+	/// Like [database], but with a user, `someone`, whose password is
+	/// `password` and who has a second factor. Their secret and recovery codes
+	/// are returned with it.
+	async fn database_with_second_factor(
+		name: &str,
+	) -> (Arc<Database>, Vec<u8>, Vec<String>, TestDir) {
+		let (_, directory) = database(name).await;
+		let credentials = directory.join("creds");
+
+		let salt = SaltString::generate(&mut OsRng);
+		let hash = Argon2::default()
+			.hash_password(b"password", &salt)
+			.unwrap()
+			.to_string();
+		std::fs::write(&credentials, format!("someone {}\n", hash)).unwrap();
+
+		let (secret, codes) = Users::load_file(&credentials)
+			.enroll_second_factor("someone")
+			.unwrap();
+
+		let db = Database::get(
+			credentials,
+			&*directory,
+			Renderer::Plain,
+			LoginLimits::default(),
+		);
+		(Arc::new(db), secret, codes, directory)
+	}
+
+	fn unix_now() -> u64 {
+		std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.unwrap()
+			.as_secs()
+	}
+
+	#[tokio::test]
+	async fn second_factor_is_needed_after_the_password() {
+		let (db, secret, _, _dir) = database_with_second_factor("second-factor").await;
+
+		let post = |path: &str, body: String| {
+			let request = hyper::Request::builder()
+				.method(Method::POST)
+				.uri(path)
+				.body(Body::from(body))
+				.unwrap();
+
+			Svc::task(request.into(), db.clone())
+		};
+
+		// The password alone gets you asked for a code, not logged in
+		let response = post("/login", String::from("username=someone&password=password")).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+		let page = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		let page = String::from_utf8_lossy(&page);
+		let (_, challenge) = page.split_once("name=\"challenge\" value=\"").unwrap();
+		let (challenge, _) = challenge.split_once('"').unwrap();
+
+		let now = totp::step_at(unix_now());
+		let right: Vec<String> = (now - 1..=now + 1)
+			.map(|step| format!("{:06}", totp::code_at(&secret, step)))
+			.collect();
+		let wrong = (0..)
+			.map(|code| format!("{:06}", code))
+			.find(|code| !right.contains(code))
+			.unwrap();
+
+		let code = |code: &str| format!("challenge={}&code={}", challenge, code);
+
+		let response = post("/login/code", code(&wrong)).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+		let response = post("/login/code", code(&right[1])).await;
+		assert_eq!(response.status(), StatusCode::FOUND);
+		assert_eq!(response.headers()[header::LOCATION], "/");
+		assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+		// The challenge is used up, and so is the code
+		let response = post("/login/code", code(&right[1])).await;
+		assert_eq!(response.headers()[header::LOCATION], "/login");
+		assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+		let challenge = db.new_login_challenge("someone").await;
+		assert_eq!(
+			db.verify_login_code(None, &challenge, &right[1]).await,
+			None
+		);
+	}
+
+	#[tokio::test]
+	async fn recovery_codes_work_once() {
+		let (db, _, codes, directory) = database_with_second_factor("recovery-codes").await;
+		assert_eq!(codes.len(), 10);
+
+		let challenge = db.new_login_challenge("someone").await;
+		assert_eq!(
+			db.verify_login_code(None, &challenge, &codes[0]).await,
+			Some(String::from("someone"))
+		);
+		let challenge = db.new_login_challenge("someone").await;
+		assert_eq!(
+			db.verify_login_code(None, &challenge, &codes[0]).await,
+			None
+		);
+
+		// It stays used after a restart, and the others still work, with or
+		// without their dash
+		let restarted = Database::get(
+			directory.join("creds"),
+			&*directory,
+			Renderer::Plain,
+			LoginLimits::default(),
+		);
+
+		let challenge = restarted.new_login_challenge("someone").await;
+		assert_eq!(
+			restarted
+				.verify_login_code(None, &challenge, &codes[0])
+				.await,
+			None
+		);
+		assert_eq!(
+			restarted
+				.verify_login_code(None, &challenge, &codes[1].replace('-', ""))
+				.await,
+			Some(String::from("someone"))
+		);
+	}
+
+	#[tokio::test]
+	async fn codes_stay_used_after_a_restart() {
+		let (db, secret, _, directory) = database_with_second_factor("used-steps").await;
+		let code = format!("{:06}", totp::code_at(&secret, totp::step_at(unix_now())));
+
+		let challenge = db.new_login_challenge("someone").await;
+		assert_eq!(
+			db.verify_login_code(None, &challenge, &code).await,
+			Some(String::from("someone"))
+		);
+
+		let restarted = Database::get(
+			directory.join("creds"),
+			&*directory,
+			Renderer::Plain,
+			LoginLimits::default(),
+		);
+		let challenge = restarted.new_login_challenge("someone").await;
+		assert_eq!(
+			restarted.verify_login_code(None, &challenge, &code).await,
+			None
+		);
+	}
+
+	#[tokio::test]
+	async fn enrolling_while_running_is_not_undone() {
+		let (db, _, codes, directory) = database_with_second_factor("enroll-running").await;
+		let credentials = directory.join("creds");
+
+		// Someone else enrolls with `oodles totp` after the server started
+		let mut file = std::fs::read_to_string(&credentials).unwrap();
+		file.push_str("other $argon2id$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$aGFzaA\n");
+		std::fs::write(&credentials, file).unwrap();
+		Users::load_file(&credentials)
+			.enroll_second_factor("other")
+			.unwrap();
+
+		// and then the server saves the credentials
+		let challenge = db.new_login_challenge("someone").await;
+		assert_eq!(
+			db.verify_login_code(None, &challenge, &codes[0]).await,
+			Some(String::from("someone"))
+		);
+
+		let file = std::fs::read_to_string(&credentials).unwrap();
+		let other = file
+			.lines()
+			.find(|line| line.starts_with("other "))
+			.unwrap();
+		assert_eq!(other.split(' ').count(), 13);
+		let someone = file
+			.lines()
+			.find(|line| line.starts_with("someone "))
+			.unwrap();
+		assert_eq!(someone.split(' ').count(), 12);
+	}
+
+	#[tokio::test]
+	async fn guesses_that_are_not_recovery_codes_are_refused() {
+		let (db, _, codes, _dir) = database_with_second_factor("recovery-format").await;
+
+		let challenge = db.new_login_challenge("someone").await;
+		for guess in [
+			"password",
+			"ABCDE-FGHI0",
+			&codes[0][1..],
+			&format!("{}1", codes[0]),
+		] {
+			assert_eq!(db.verify_login_code(None, &challenge, guess).await, None);
+		}
+
+		assert_eq!(
+			db.verify_login_code(None, &challenge, &format!(" {} ", codes[0]))
+				.await,
+			Some(String::from("someone"))
+		);
+	}
+
+	#[tokio::test]
+	async fn archived_oodles_are_not_searched() {
+		let (db, _dir) = database("archived-search").await;
+
+		assert_eq!(db.oodles().await.search("hello").len(), 1);
+
+		db.oodles_mut()
+			.await
+			.set_archived("untouched.oodle", Some(OffsetDateTime::now_utc()))
+			.await
+			.unwrap();
+		assert!(db.oodles().await.search("hello").is_empty());
+	}
+
+	#[tokio::test]
+	async fn archived_oodles_are_not_tagged() {
+		let (db, _dir) = database("archived-tags").await;
+
+		db.oodles_mut()
+			.await
+			.set_tags("untouched.oodle", &[String::from("c++")])
+			.await
+			.unwrap();
+		assert_eq!(db.oodles().await.tags(), [(String::from("c++"), 1)]);
+		assert_eq!(db.oodles().await.tagged("c++").len(), 1);
+
+		db.oodles_mut()
+			.await
+			.set_archived("untouched.oodle", Some(OffsetDateTime::now_utc()))
+			.await
+			.unwrap();
+		assert!(db.oodles().await.tags().is_empty());
+		assert!(db.oodles().await.tagged("c++").is_empty());
+	}
+
+	#[test]
+	fn session_cookies_stay_on_this_site() {
+		let session = Session::new(String::from("sid"), String::from("someone"));
+
+		assert!(session.get_set_cookie().ends_with("; SameSite=Lax"));
+		assert!(session.get_clear_cookie().ends_with("; SameSite=Lax"));
+	}
+
+	#[test]
+	fn only_writes_need_a_session() {
+		let session = Session::new(String::from("sid"), String::from("someone"));
+
+		for path in WRITES {
+			assert_eq!(authorize(&Method::POST, path, Some(&session)), Ok(()));
+			assert_eq!(
+				authorize(&Method::POST, path, None),
+				Err(StatusCode::UNAUTHORIZED)
+			);
+		}
+
+		assert_eq!(authorize(&Method::GET, "oodles/untouched", None), Ok(()));
+		assert_eq!(authorize(&Method::HEAD, "", None), Ok(()));
+		assert_eq!(authorize(&Method::POST, "login", None), Ok(()));
+		assert_eq!(authorize(&Method::POST, "login/code", None), Ok(()));
+		assert_eq!(
+			authorize(&Method::GET, "oodle/message/revisions", None),
+			Err(StatusCode::UNAUTHORIZED)
+		);
+		// Methods we don't route yet are covered too
+		assert_eq!(
+			authorize(&Method::DELETE, "oodle", None),
+			Err(StatusCode::UNAUTHORIZED)
+		);
+	}
+}
@@ -0,0 +1,279 @@
+use std::{fmt, str::FromStr};
+
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+
+use crate::{
+	attachment::{self, Attachment, THUMBNAIL_DIRECTORY},
+	highlight::{self, Block},
+	search,
+	tag::url_encode_tag,
+};
+
+/// How many characters of a message are shown in a [search snippet](Html::snippet).
+const SNIPPET_LENGTH: usize = 160;
+
+/// How many characters are shown before the first match in a snippet.
+const SNIPPET_LEAD: usize = 40;
+
+/// Markup that's safe to put in a page. The only ways to get one are by
+/// escaping text or by rendering a message, so anything a person wrote has
+/// been escaped on the way in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Html(String);
+
+impl Html {
+	/// Escape text so it shows as written, whether it's put in an element or in
+	/// a quoted attribute.
+	pub fn escape<S: AsRef<str>>(text: S) -> Self {
+		let text = text.as_ref();
+		let mut html = String::with_capacity(text.len());
+
+		for c in text.chars() {
+			match c {
+				'&' => html.push_str("&amp;"),
+				'<' => html.push_str("&lt;"),
+				'>' => html.push_str("&gt;"),
+				'"' => html.push_str("&quot;"),
+				'\'' => html.push_str("&#39;"),
+				c => html.push(c),
+			}
+		}
+
+		Self(html)
+	}
+
+	/// Render the content of a [Message](crate::Message) as plain text in
+	/// paragraphs, keeping its line breaks. Fenced code blocks are
+	/// [highlighted](crate::HIGHLIGHT_CLASS_PREFIX).
+	pub fn plain<S: AsRef<str>>(content: S) -> Self {
+		let mut html = String::new();
+
+		for block in highlight::fenced_blocks(content.as_ref()) {
+			match block {
+				Block::Text(text) => {
+					let Self(escaped) = Self::escape(text.trim_matches('\n'));
+					html.push_str(&format!("<p>{}</p>", escaped.replace('\n', "<br>")));
+				}
+				Block::Code(code, language) => {
+					html.push_str(&highlight::code_block(code, language))
+				}
+			}
+		}
+
+		Self(html)
+	}
+
+	/// Render the content of a [Message](crate::Message) as Markdown, with
+	/// fenced code blocks [highlighted](crate::HIGHLIGHT_CLASS_PREFIX). Any
+	/// HTML in the message, or made from it, that could run script or break
+	/// out of the message is removed.
+	pub fn markdown<S: AsRef<str>>(content: S) -> Self {
+		let mut options = Options::empty();
+		options.insert(Options::ENABLE_TABLES);
+		options.insert(Options::ENABLE_STRIKETHROUGH);
+
+		// The language and code of the fenced block we're in
+		let mut fenced: Option<(String, String)> = None;
+		let events = Parser::new_ext(content.as_ref(), options).filter_map(|event| {
+			match (event, &mut fenced) {
+				(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))), None) => {
+					fenced = Some((info.to_string(), String::new()));
+					None
+				}
+				(Event::Text(text), Some((_, code))) => {
+					code.push_str(&text);
+					None
+				}
+				(Event::End(Tag::CodeBlock(_)), Some(_)) => {
+					let (info, code) = fenced.take().unwrap();
+					let language = info.split_whitespace().next();
+					Some(Event::Html(highlight::code_block(&code, language).into()))
+				}
+				(event, _) => Some(event),
+			}
+		});
+
+		let mut html = String::new();
+		pulldown_cmark::html::push_html(&mut html, events);
+
+		let cleaned = ammonia::Builder::default()
+			.add_tag_attributes("pre", &["class"])
+			.add_tag_attributes("code", &["class"])
+			.add_tag_attributes("span", &["class"])
+			.clean(&html)
+			.to_string();
+
+		Self(cleaned)
+	}
+
+	/// Render a list of a message's attachments. Images are shown by their
+	/// thumbnail, linking to the original, with their alt text or, if they
+	/// don't have any, their name. Anything else is linked to. Captions go
+	/// under either.
+	pub fn attachments(attachments: &[Attachment]) -> Self {
+		let mut html = String::from("<ul class=\"attachments\">");
+
+		for Attachment { name, alt, caption } in attachments {
+			let Self(escaped) = Self::escape(name);
+
+			html.push_str("<li><figure>");
+			if attachment::is_image(name) {
+				let Self(thumbnail) = Self::escape(attachment::thumbnail_name(name));
+				let Self(alt) = Self::escape(alt.as_deref().unwrap_or(name));
+
+				html.push_str(&format!(
+					"<a href=\"/attachments/{}\"><img src=\"/attachments/{}/{}\" alt=\"{}\"></a>",
+					escaped, THUMBNAIL_DIRECTORY, thumbnail, alt
+				));
+			} else {
+				html.push_str(&format!("<a href=\"/attachments/{0}\">{0}</a>", escaped));
+			}
+
+			if let Some(caption) = caption {
+				let Self(caption) = Self::escape(caption);
+				html.push_str(&format!("<figcaption>{}</figcaption>", caption));
+			}
+			html.push_str("</figure></li>");
+		}
+
+		html.push_str("</ul>");
+		Self(html)
+	}
+
+	/// Render a list of tags, each linking to the page of everything with it.
+	pub fn tags<S: AsRef<str>>(tags: &[S]) -> Self {
+		let mut html = String::from("<ul class=\"tags\">");
+
+		for tag in tags {
+			let Self(url) = Self::escape(url_encode_tag(tag.as_ref()));
+			let Self(tag) = Self::escape(tag);
+			html.push_str(&format!("<li><a href=\"/tags/{}\">#{}</a></li>", url, tag));
+		}
+
+		html.push_str("</ul>");
+		Self(html)
+	}
+
+	/// A short piece of text around the first place it matches a search
+	/// query, with every term of the query that it has in `<mark>`. Text is cut
+	/// at the start or end is marked with an ellipsis.
+	pub fn snippet<S: AsRef<str>>(text: S, query: &str) -> Self {
+		let text = text.as_ref();
+		let query: Vec<String> = search::terms(query).collect();
+		let matches: Vec<_> = search::term_spans(text)
+			.filter(|span| query.contains(&text[span.clone()].to_lowercase()))
+			.collect();
+
+		let first = matches.first().map(|span| span.start).unwrap_or(0);
+		let start = text[..first]
+			.char_indices()
+			.rev()
+			.nth(SNIPPET_LEAD - 1)
+			.map(|(idx, _)| idx)
+			.unwrap_or(0);
+		let mut end = text[start..]
+			.char_indices()
+			.nth(SNIPPET_LENGTH)
+			.map(|(idx, _)| start + idx)
+			.unwrap_or(text.len());
+
+		// Don't cut a match in half
+		if let Some(span) = matches.iter().find(|span| span.contains(&end)) {
+			end = span.end;
+		}
+
+		let mut html = String::new();
+		if start > 0 {
+			html.push('…');
+		}
+
+		let mut idx = start;
+		for span in matches
+			.iter()
+			.filter(|span| span.start >= start && span.end <= end)
+		{
+			let Self(before) = Self::escape(&text[idx..span.start]);
+			let Self(matched) = Self::escape(&text[span.clone()]);
+			html.push_str(&format!("{}<mark>{}</mark>", before, matched));
+			idx = span.end;
+		}
+
+		let Self(rest) = Self::escape(&text[idx..end]);
+		html.push_str(&rest);
+		if end < text.len() {
+			html.push('…');
+		}
+
+		Self(html)
+	}
+
+	/// Render the content of a [Message](crate::Message) with the given [Renderer].
+	pub fn render<S: AsRef<str>>(content: S, renderer: Renderer) -> Self {
+		match renderer {
+			Renderer::Plain => Self::plain(content),
+			Renderer::Markdown => Self::markdown(content),
+		}
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	pub fn into_string(self) -> String {
+		self.0
+	}
+}
+
+impl fmt::Display for Html {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+/// How the content of a [Message](crate::Message) is turned into [Html]. The
+/// content is kept as written either way.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Renderer {
+	/// Text, shown as written with its line breaks kept
+	#[default]
+	Plain,
+	/// Markdown, with anything unsafe taken out
+	Markdown,
+}
+
+impl fmt::Display for Renderer {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Plain => write!(f, "plain"),
+			Self::Markdown => write!(f, "markdown"),
+		}
+	}
+}
+
+impl FromStr for Renderer {
+	type Err = UnknownRenderer;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"plain" => Ok(Self::Plain),
+			"markdown" => Ok(Self::Markdown),
+			_ => Err(UnknownRenderer(s.to_owned())),
+		}
+	}
+}
+
+/// A [Renderer] was asked for by a name we don't know.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownRenderer(pub String);
+
+impl fmt::Display for UnknownRenderer {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"unknown renderer {:?}, expected plain or markdown",
+			self.0
+		)
+	}
+}
+
+impl std::error::Error for UnknownRenderer {}
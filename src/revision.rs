@@ -0,0 +1,90 @@
+use serde::{ser::SerializeStruct, Serialize};
+use time::OffsetDateTime;
+
+use crate::{Attachment, Headers, Message, MessageBlock, OodleParseError};
+
+/// Tacked on to the end of an oodle's filename to get the file its
+/// [Revision]s are kept in.
+pub const REVISIONS_EXTENSION: &str = "revisions";
+
+/// A previous version of a message, kept when the message is edited.
+///
+/// Revisions are stored next to the oodle as message blocks, where the
+/// dateline is when that version was written and the index is the id of the
+/// message it belongs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revision {
+	pub message_id: usize,
+	/// When this version of the message was written
+	pub date: OffsetDateTime,
+	pub content: String,
+	/// The message's attachments then, with the alt text and captions they had
+	pub attachments: Vec<Attachment>,
+}
+
+impl Revision {
+	/// The message as it is now, to keep before it's changed.
+	pub(crate) fn of(msg: &Message) -> Self {
+		Self {
+			message_id: msg.id,
+			date: msg.edited().unwrap_or(msg.date),
+			content: msg.content.clone(),
+			attachments: msg.attachment_details(),
+		}
+	}
+
+	pub(crate) fn to_block(&self) -> String {
+		let mut msg = Message {
+			id: self.message_id,
+			date: self.date,
+			headers: Headers::new(),
+			content: self.content.clone(),
+		};
+		msg.set_attachments(&self.attachments);
+
+		MessageBlock {
+			msg: &msg,
+			previous: None,
+		}
+		.to_string()
+	}
+
+	/// Parse every revision in a revisions file, oldest first.
+	pub(crate) fn parse_all(s: &str) -> Result<Vec<Revision>, OodleParseError> {
+		let mut revisions = vec![];
+		let mut block = vec![];
+
+		for (number, line) in s.split('\n').enumerate().map(|(idx, l)| (idx + 1, l)) {
+			if line == "." {
+				let msg = Message::parse_lines(&block, number)?;
+				revisions.push(Revision {
+					message_id: msg.id,
+					date: msg.date,
+					attachments: msg.attachment_details(),
+					content: msg.content,
+				});
+				block.clear();
+			} else {
+				block.push((number, line));
+			}
+		}
+
+		Ok(revisions)
+	}
+}
+
+impl Serialize for Revision {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: serde::Serializer,
+	{
+		let epoch = self.date - OffsetDateTime::UNIX_EPOCH;
+
+		let mut state = serializer.serialize_struct("Revision", 4)?;
+		state.serialize_field("message_id", &self.message_id)?;
+		state.serialize_field("date", &epoch.whole_seconds())?;
+		state.serialize_field("content", &self.content)?;
+		state.serialize_field("attachments", &self.attachments)?;
+		state.end()
+	}
+}
@@ -0,0 +1,181 @@
+use std::{
+	collections::{HashMap, HashSet},
+	ops::Range,
+};
+
+use crate::Oodle;
+
+/// Matches in an oodle's title count for this many matches in a message, so
+/// an oodle named for what you searched comes first.
+const TITLE_WEIGHT: f64 = 2.0;
+
+/// Something that can be found: an oodle's title, or one of its messages.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Document {
+	/// The file name of the oodle
+	pub filename: String,
+	/// The id of the message, or None for the oodle's title
+	pub message: Option<usize>,
+}
+
+/// A [Document] that matched every term searched for, and how well.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit {
+	pub document: Document,
+	pub score: f64,
+}
+
+/// An inverted index over the titles and message content of oodles, from each
+/// term to the documents it's in and how many times.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+	terms: HashMap<String, HashMap<Document, usize>>,
+	/// The terms in each oodle, and how many documents it has, by file name,
+	/// so it can be taken out again
+	oodles: HashMap<String, (HashSet<String>, usize)>,
+	/// How many documents there are in all, for weighing rare terms
+	documents: usize,
+}
+
+impl SearchIndex {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add an oodle's title and messages, replacing whatever was indexed for
+	/// it before. Deleted messages are left out.
+	pub fn index_oodle(&mut self, oodle: &Oodle) {
+		let filename = oodle.file.file_name().unwrap().to_string_lossy();
+		self.remove_oodle(&filename);
+
+		let documents = std::iter::once((None, oodle.name.as_str())).chain(
+			oodle
+				.messages
+				.iter()
+				.filter(|msg| !msg.is_deleted())
+				.map(|msg| (Some(msg.id), msg.content.as_str())),
+		);
+
+		let mut oodle_terms = HashSet::new();
+		let mut count = 0;
+		for (message, text) in documents {
+			let document = Document {
+				filename: filename.clone().into_owned(),
+				message,
+			};
+
+			for term in terms(text) {
+				*self
+					.terms
+					.entry(term.clone())
+					.or_default()
+					.entry(document.clone())
+					.or_default() += 1;
+				oodle_terms.insert(term);
+			}
+
+			count += 1;
+		}
+
+		self.documents += count;
+		self.oodles
+			.insert(filename.into_owned(), (oodle_terms, count));
+	}
+
+	/// Take everything in an oodle out of the index.
+	pub fn remove_oodle(&mut self, filename: &str) {
+		let (terms, count) = match self.oodles.remove(filename) {
+			Some(indexed) => indexed,
+			None => return,
+		};
+
+		for term in terms {
+			if let Some(documents) = self.terms.get_mut(&term) {
+				documents.retain(|document, _| document.filename != filename);
+
+				if documents.is_empty() {
+					self.terms.remove(&term);
+				}
+			}
+		}
+
+		self.documents -= count;
+	}
+
+	/// Find the documents with every term in the query in them, best first.
+	/// A term that's rare across all the oodles counts for more than a common
+	/// one.
+	pub fn search(&self, query: &str) -> Vec<SearchHit> {
+		let mut query_terms: Vec<String> = terms(query).collect();
+		query_terms.sort();
+		query_terms.dedup();
+
+		let mut scores: Option<HashMap<&Document, f64>> = None;
+		for term in &query_terms {
+			let documents = match self.terms.get(term) {
+				Some(documents) => documents,
+				None => return vec![],
+			};
+
+			let idf = (1.0 + self.documents as f64 / documents.len() as f64).ln();
+			let matched = documents.iter().filter_map(|(document, &count)| {
+				let previous = match &scores {
+					Some(scores) => *scores.get(document)?,
+					None => 0.0,
+				};
+
+				let weight = match document.message {
+					None => TITLE_WEIGHT,
+					Some(_) => 1.0,
+				};
+
+				Some((document, previous + weight * count as f64 * idf))
+			});
+
+			scores = Some(matched.collect());
+		}
+
+		let mut hits: Vec<SearchHit> = scores
+			.unwrap_or_default()
+			.into_iter()
+			.map(|(document, score)| SearchHit {
+				document: document.clone(),
+				score,
+			})
+			.collect();
+
+		hits.sort_by(|a, b| {
+			b.score
+				.total_cmp(&a.score)
+				.then_with(|| a.document.filename.cmp(&b.document.filename))
+				.then_with(|| a.document.message.cmp(&b.document.message))
+		});
+		hits
+	}
+}
+
+/// The terms in some text, in order: its runs of letters and digits,
+/// lowercased.
+pub fn terms(text: &str) -> impl Iterator<Item = String> + '_ {
+	term_spans(text).map(move |span| text[span].to_lowercase())
+}
+
+/// Where each of the [terms] is in the text, as byte ranges.
+pub(crate) fn term_spans(text: &str) -> impl Iterator<Item = Range<usize>> + '_ {
+	let mut chars = text.char_indices().peekable();
+
+	std::iter::from_fn(move || {
+		let (start, _) = chars.find(|(_, c)| c.is_alphanumeric())?;
+
+		let mut end = text.len();
+		while let Some(&(idx, c)) = chars.peek() {
+			if !c.is_alphanumeric() {
+				end = idx;
+				break;
+			}
+			chars.next();
+		}
+
+		Some(start..end)
+	})
+}
@@ -0,0 +1,162 @@
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt,
+};
+
+use crate::{Document, Headers, Oodle};
+
+/// Make a tag out of what someone typed: lowercased, without a leading `#`,
+/// and with every run of anything that isn't a letter, digit, `+`, or `#` made
+/// a single `-`. So `C++` and `C#` stay apart from `C`. Returns None if
+/// there's nothing left.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+	let mut normal = String::with_capacity(tag.len());
+
+	for c in tag.trim_start_matches('#').chars() {
+		if c.is_alphanumeric() || c == '+' || c == '#' {
+			normal.extend(c.to_lowercase());
+		} else if !normal.is_empty() && !normal.ends_with('-') {
+			normal.push('-');
+		}
+	}
+
+	let normal = normal.trim_end_matches('-');
+	if normal.is_empty() {
+		None
+	} else {
+		Some(normal.to_owned())
+	}
+}
+
+/// Whether a tag is already [normalized](normalize_tag).
+pub fn valid_tag(tag: &str) -> bool {
+	normalize_tag(tag).as_deref() == Some(tag)
+}
+
+/// Read a list of tags separated by spaces or commas, like `rust, travel`.
+/// Each is [normalized](normalize_tag) and only the first of any repeats is
+/// kept.
+pub fn parse_tags(text: &str) -> Vec<String> {
+	let mut tags: Vec<String> = vec![];
+
+	for tag in split_tags(text) {
+		if let Some(tag) = normalize_tag(tag) {
+			if !tags.contains(&tag) {
+				tags.push(tag);
+			}
+		}
+	}
+
+	tags
+}
+
+/// [parse_tags] for what someone just typed, failing on a tag with nothing
+/// left once it's normalized, like `#!?`, instead of quietly dropping it.
+pub fn try_parse_tags(text: &str) -> Result<Vec<String>, BadTag> {
+	match split_tags(text).find(|tag| normalize_tag(tag).is_none()) {
+		Some(bad) => Err(BadTag(bad.to_owned())),
+		None => Ok(parse_tags(text)),
+	}
+}
+
+fn split_tags(text: &str) -> impl Iterator<Item = &str> {
+	text.split(|c: char| c == ',' || c.is_whitespace())
+		.filter(|tag| !tag.is_empty())
+}
+
+/// A tag made safe to put in a URL's path or query, where `+` and `#` would
+/// mean something else.
+pub fn url_encode_tag(tag: &str) -> String {
+	tag.replace('+', "%2B").replace('#', "%23")
+}
+
+/// The tags in a `Tags` header, see [Oodle::TAGS_HEADER].
+pub(crate) fn header_tags(headers: &Headers, key: &str) -> Vec<String> {
+	headers.get(key).map(parse_tags).unwrap_or_default()
+}
+
+/// Set the `Tags` header, or remove it if there are no tags.
+pub(crate) fn set_header_tags<S: AsRef<str>>(headers: &mut Headers, key: &str, tags: &[S]) {
+	let tags = parse_tags(
+		&tags
+			.iter()
+			.map(|tag| tag.as_ref())
+			.collect::<Vec<_>>()
+			.join(" "),
+	);
+
+	if tags.is_empty() {
+		headers.remove(key);
+	} else {
+		headers.set(key, tags.join(" "));
+	}
+}
+
+/// Every tag in use, and the oodles and messages that have it.
+#[derive(Debug, Default)]
+pub struct TagIndex {
+	tags: BTreeMap<String, BTreeSet<Document>>,
+}
+
+impl TagIndex {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add the tags of an oodle and its messages, replacing whatever was
+	/// indexed for it before. Deleted messages are left out.
+	pub fn index_oodle(&mut self, oodle: &Oodle) {
+		let filename = oodle.file.file_name().unwrap().to_string_lossy();
+		self.remove_oodle(&filename);
+
+		let tagged = std::iter::once((None, oodle.tags())).chain(
+			oodle
+				.messages
+				.iter()
+				.filter(|msg| !msg.is_deleted())
+				.map(|msg| (Some(msg.id), msg.tags())),
+		);
+
+		for (message, tags) in tagged {
+			for tag in tags {
+				self.tags.entry(tag).or_default().insert(Document {
+					filename: filename.clone().into_owned(),
+					message,
+				});
+			}
+		}
+	}
+
+	/// Take the tags of an oodle and its messages out of the index.
+	pub fn remove_oodle(&mut self, filename: &str) {
+		self.tags.retain(|_, documents| {
+			documents.retain(|document| document.filename != filename);
+			!documents.is_empty()
+		});
+	}
+
+	/// Every tag, in order, with how many oodles and messages have it.
+	pub fn tags(&self) -> impl Iterator<Item = (&str, usize)> {
+		self.tags
+			.iter()
+			.map(|(tag, documents)| (tag.as_str(), documents.len()))
+	}
+
+	/// The oodles and messages with a tag, ordered by the file name of the
+	/// oodle, with the oodle itself before its messages.
+	pub fn tagged(&self, tag: &str) -> impl Iterator<Item = &Document> {
+		self.tags.get(tag).into_iter().flatten()
+	}
+}
+
+/// A tag with nothing left of it once it's [normalized](normalize_tag).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BadTag(pub String);
+
+impl fmt::Display for BadTag {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:?} isn't a tag, it has no letters or digits", self.0)
+	}
+}
+
+impl std::error::Error for BadTag {}
@@ -0,0 +1,44 @@
+//! A scratch directory for tests, shared by the library and server tests.
+
+use std::{
+	ops::Deref,
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory in the system's temporary directory that's removed
+/// again when dropped. Every one gets its own path, even with the same name,
+/// so tests running at the same time don't trip over each other.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+	pub fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!(
+			"oodles-{}-{}-{}",
+			name,
+			std::process::id(),
+			COUNT.fetch_add(1, Ordering::Relaxed)
+		));
+
+		let _ = std::fs::remove_dir_all(&path);
+		std::fs::create_dir_all(&path).unwrap();
+
+		Self(path)
+	}
+}
+
+impl Deref for TestDir {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl Drop for TestDir {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.0);
+	}
+}
@@ -0,0 +1,165 @@
+use std::{
+	collections::HashMap,
+	net::IpAddr,
+	time::{Duration, Instant},
+};
+
+/// How many failed logins are allowed, and how long to wait after more.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LoginLimits {
+	/// Failures allowed before having to wait between attempts
+	pub free_attempts: u32,
+	/// The wait after the first failure past the free ones. It doubles with
+	/// each failure after that.
+	pub backoff: Duration,
+	/// Failures after which logging in is locked for the [lockout](Self::lockout)
+	pub lockout_attempts: u32,
+	/// How long a lockout lasts. Failures are forgotten after this long
+	/// without another.
+	pub lockout: Duration,
+}
+
+impl Default for LoginLimits {
+	fn default() -> Self {
+		Self {
+			free_attempts: 3,
+			backoff: Duration::from_secs(1),
+			lockout_attempts: 10,
+			lockout: Duration::from_secs(60 * 15),
+		}
+	}
+}
+
+/// Who a login attempt is counted against. Counting both means guessing at one
+/// account from many addresses, or at many accounts from one, is slowed down.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Attempter {
+	Address(IpAddr),
+	Username(String),
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Failures {
+	count: u32,
+	last: Instant,
+}
+
+/// Failed logins, by the address they came from and the username tried.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+	limits: LoginLimits,
+	failures: HashMap<Attempter, Failures>,
+}
+
+impl LoginThrottle {
+	pub fn new(limits: LoginLimits) -> Self {
+		Self {
+			limits,
+			failures: HashMap::new(),
+		}
+	}
+
+	/// Whether someone can try to log in now. If not, how long until they can.
+	pub fn check(
+		&self,
+		address: Option<IpAddr>,
+		username: &str,
+		now: Instant,
+	) -> Result<(), Duration> {
+		let wait = Self::attempters(address, username)
+			.filter_map(|attempter| self.failures.get(&attempter))
+			.filter_map(|failures| {
+				let until = failures.last + self.wait(failures.count);
+				until
+					.checked_duration_since(now)
+					.filter(|wait| !wait.is_zero())
+			})
+			.max();
+
+		match wait {
+			Some(wait) => Err(wait),
+			None => Ok(()),
+		}
+	}
+
+	/// [Check](LoginThrottle::check) whether someone can try to log in now,
+	/// and if they can, count it as a failure straight away. Doing both at
+	/// once means guesses made at the same time can't all get through before
+	/// any of them is counted. A login that goes right takes it back with
+	/// [LoginThrottle::succeeded] or [LoginThrottle::refund].
+	pub fn attempt(
+		&mut self,
+		address: Option<IpAddr>,
+		username: &str,
+		now: Instant,
+	) -> Result<(), Duration> {
+		self.check(address, username, now)?;
+		self.failed(address, username, now);
+		Ok(())
+	}
+
+	/// Take back one failure counted by [LoginThrottle::attempt], for a step
+	/// of logging in that went right but didn't finish it, like getting the
+	/// password right and still needing a code.
+	pub fn refund(&mut self, address: Option<IpAddr>, username: &str) {
+		for attempter in Self::attempters(address, username) {
+			if let Some(failures) = self.failures.get_mut(&attempter) {
+				failures.count = failures.count.saturating_sub(1);
+			}
+		}
+	}
+
+	/// Count a failed login against the address and username.
+	pub fn failed(&mut self, address: Option<IpAddr>, username: &str, now: Instant) {
+		self.forget_old(now);
+
+		for attempter in Self::attempters(address, username) {
+			let failures = self.failures.entry(attempter).or_insert(Failures {
+				count: 0,
+				last: now,
+			});
+			failures.count += 1;
+			failures.last = now;
+		}
+	}
+
+	/// Forget the failures of the address and username after someone logs in.
+	pub fn succeeded(&mut self, address: Option<IpAddr>, username: &str) {
+		for attempter in Self::attempters(address, username) {
+			self.failures.remove(&attempter);
+		}
+	}
+
+	/// How long to wait after a number of failures, from the last one.
+	fn wait(&self, count: u32) -> Duration {
+		let limits = &self.limits;
+
+		if count >= limits.lockout_attempts {
+			limits.lockout
+		} else if count > limits.free_attempts {
+			let doublings = count - limits.free_attempts - 1;
+			limits
+				.backoff
+				.checked_mul(2u32.saturating_pow(doublings))
+				.unwrap_or(limits.lockout)
+				.min(limits.lockout)
+		} else {
+			Duration::ZERO
+		}
+	}
+
+	/// Drop failures that are long enough ago to not count anymore, so we
+	/// don't keep every address and username ever tried.
+	fn forget_old(&mut self, now: Instant) {
+		let lockout = self.limits.lockout;
+		self.failures
+			.retain(|_, failures| now.saturating_duration_since(failures.last) < lockout);
+	}
+
+	fn attempters(address: Option<IpAddr>, username: &str) -> impl Iterator<Item = Attempter> {
+		address
+			.map(Attempter::Address)
+			.into_iter()
+			.chain(std::iter::once(Attempter::Username(username.to_owned())))
+	}
+}
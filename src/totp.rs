@@ -0,0 +1,106 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha1::Sha1;
+
+/// How many seconds each code is good for. This, [DIGITS], and HMAC-SHA1 are
+/// what authenticator apps expect if they aren't told otherwise.
+pub const STEP: u64 = 30;
+
+/// How many digits are in a code.
+pub const DIGITS: u32 = 6;
+
+/// How many steps either side of now a code is still accepted from, for clocks
+/// that are a little off.
+const SKEW: u64 = 1;
+
+/// How many bytes of randomness are in a secret. RFC 4226 recommends 160 bits.
+const SECRET_LENGTH: usize = 20;
+
+/// The name authenticator apps show the code under.
+const ISSUER: &str = "Oodles";
+
+/// A new random secret.
+pub fn generate_secret() -> Vec<u8> {
+	let mut secret = vec![0; SECRET_LENGTH];
+	OsRng.fill_bytes(&mut secret);
+	secret
+}
+
+/// A secret as it's kept in the credential file and typed into an
+/// authenticator app: unpadded base32.
+pub fn encode_secret(secret: &[u8]) -> String {
+	BASE32_NOPAD.encode(secret)
+}
+
+/// Read an [encoded](encode_secret) secret. Lowercase is allowed.
+pub fn decode_secret(encoded: &str) -> Option<Vec<u8>> {
+	BASE32_NOPAD
+		.decode(encoded.trim().to_ascii_uppercase().as_bytes())
+		.ok()
+}
+
+/// The step a Unix timestamp falls in.
+pub fn step_at(unix_time: u64) -> u64 {
+	unix_time / STEP
+}
+
+/// The code for a step, as in RFC 4226 and RFC 6238.
+pub fn code_at(secret: &[u8], step: u64) -> u32 {
+	let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+	mac.update(&step.to_be_bytes());
+	let hash = mac.finalize().into_bytes();
+
+	let offset = (hash[hash.len() - 1] & 0x0F) as usize;
+	let truncated = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7FFF_FFFF;
+
+	truncated % 10u32.pow(DIGITS)
+}
+
+/// Check a code someone typed against the steps around now. Returns the step
+/// it was for, if it was right and for a step after `used`, the last step a
+/// code was accepted for, so a code can't be used twice.
+pub fn verify(secret: &[u8], code: &str, unix_time: u64, used: Option<u64>) -> Option<u64> {
+	if !looks_like_code(code) {
+		return None;
+	}
+	let code: u32 = code.trim().parse().ok()?;
+
+	let now = step_at(unix_time);
+	(now.saturating_sub(SKEW)..=now + SKEW)
+		.filter(|&step| used.map(|used| step > used).unwrap_or(true))
+		.find(|&step| code_at(secret, step) == code)
+}
+
+/// Whether what someone typed looks like a code, rather than a recovery code.
+pub fn looks_like_code(code: &str) -> bool {
+	let code = code.trim();
+	code.len() == DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The `otpauth://` URI that adds a secret to an authenticator app, usually
+/// by being made into a QR code.
+pub fn otpauth_uri(username: &str, secret: &[u8]) -> String {
+	format!(
+		"otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+		issuer = ISSUER,
+		username = percent_encode(username),
+		secret = encode_secret(secret),
+		digits = DIGITS,
+		period = STEP,
+	)
+}
+
+fn percent_encode(text: &str) -> String {
+	let mut encoded = String::with_capacity(text.len());
+
+	for byte in text.bytes() {
+		if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+			encoded.push(byte as char);
+		} else {
+			encoded.push_str(&format!("%{:02X}", byte));
+		}
+	}
+
+	encoded
+}
@@ -1,12 +1,20 @@
 const postAction = "/oodle/message/create?json";
 const editAction = "/oodle/message/modify?json";
+const deleteAction = "/oodle/message/delete?json";
+const attachAction = "/oodle/message/attach?";
 
 const main = document.getElementsByTagName('main')[0];
 const messageForm = document.getElementById("message-form");
 const cancelEditLabel = document.getElementById('cancel-edit-label');
+const deleteButton = document.getElementById('delete-message');
+const attachForm = document.getElementById('attach-form');
 const contentTextarea = document.getElementById('content');
+const attachmentText = document.getElementById('attachment-text');
+const messageTagsLabel = document.getElementById('message-tags-label');
+const messageTags = document.getElementById('message-tags');
 const submitButton = document.getElementById("submit");
 const oodleFilename = document.getElementById("filename").value;
+const csrfToken = document.getElementById("csrf").value;
 
 /// Look for elements with the 'edit' class in the supplied node and add the
 /// proper event listener
@@ -19,12 +27,14 @@ function setupButtons(doc) {
 }
 setupButtons(document);
 document.getElementById('cancel-edit').addEventListener('click', function () { clearEdit(); });
+deleteButton.addEventListener('click', deleteMessage);
 
 let messageIdInput = undefined;
 let editingId = undefined;
 let savedPost = undefined;
 
 messageForm.addEventListener('submit', formSubmit);
+attachForm.addEventListener('submit', attachFiles);
 
 function editClicked(e) {
 	let messageId = e.target.getAttribute('message-id');
@@ -50,6 +60,8 @@ function setupEdit(messageId) {
 	})).then((response) => response.json()).then((data) => {
 		savedPost = contentTextarea.value;
 		contentTextarea.value = data.content;
+		showAttachmentText(data.attachments);
+		messageTags.value = data.tags.join(" ");
 	});
 
 	setForm(editAction, messageId);
@@ -61,6 +73,61 @@ function setupEdit(messageId) {
 let ghostedElement = undefined;
 let ghostElement = undefined;
 
+/// Add alt text and caption inputs for each of the attachments of the message
+/// being edited, numbered like the form on the server expects.
+function showAttachmentText(attachments) {
+	attachmentText.replaceChildren();
+
+	attachments.forEach((attachment, idx) => {
+		const number = idx + 1;
+		let fieldset = document.createElement('fieldset');
+		fieldset.className = "attachment";
+
+		let legend = document.createElement('legend');
+		legend.textContent = attachment.name;
+		fieldset.appendChild(legend);
+
+		let name = document.createElement('input');
+		name.type = "hidden";
+		name.name = `attachment-${number}`;
+		name.value = attachment.name;
+		fieldset.appendChild(name);
+
+		for (const [field, label] of [["alt", "Alt text"], ["caption", "Caption"]]) {
+			let input = document.createElement('input');
+			input.type = "text";
+			input.id = `${field}-${number}`;
+			input.name = `${field}-${number}`;
+			input.className = field;
+			input.value = attachment[field] ?? "";
+
+			let inputLabel = document.createElement('label');
+			inputLabel.htmlFor = input.id;
+			inputLabel.textContent = label;
+
+			fieldset.appendChild(inputLabel);
+			fieldset.appendChild(input);
+		}
+
+		attachmentText.appendChild(fieldset);
+	});
+}
+
+/// The alt text and captions from the inputs made by showAttachmentText
+function attachmentTextData() {
+	let attachments = [];
+
+	for (let fieldset of attachmentText.getElementsByClassName('attachment')) {
+		attachments.push({
+			'name': fieldset.querySelector('input[type=hidden]').value,
+			'alt': fieldset.querySelector('.alt').value,
+			'caption': fieldset.querySelector('.caption').value
+		});
+	}
+
+	return attachments;
+}
+
 function ghost(element) {
 	if (ghostedElement != undefined) {
 		ghostedElement.style.display = "";
@@ -86,6 +153,13 @@ function setForm(action, messageId) {
 	if (action == editAction) {
 		submitButton.value = "edit";
 		cancelEditLabel.style.display = "";
+		deleteButton.style.display = "";
+		attachForm.style.display = "";
+		messageTagsLabel.style.display = "";
+		attachForm.action = attachAction + new URLSearchParams({
+			filename: oodleFilename,
+			id: messageId
+		});
 
 		messageIdInput = document.createElement('input');
 		messageIdInput.type = "hidden";
@@ -95,6 +169,11 @@ function setForm(action, messageId) {
 	} else {
 		submitButton.value = "post";
 		cancelEditLabel.style.display = "none";
+		deleteButton.style.display = "none";
+		attachForm.style.display = "none";
+		attachmentText.replaceChildren();
+		messageTagsLabel.style.display = "none";
+		messageTags.value = "";
 		messageForm.removeChild(messageIdInput);
 		contentTextarea.value = savedPost;
 	}
@@ -117,7 +196,8 @@ function postMessage(event) {
 	fetch(postAction, {
 		method: 'POST',
 		headers: {
-			'Content-Type': "application/json"
+			'Content-Type': "application/json",
+			'X-CSRF-Token': csrfToken
 		},
 		body: JSON.stringify(jsonData)
 	})
@@ -138,12 +218,19 @@ function editMessage(event) {
 	event.stopPropagation();
 	event.preventDefault();
 
-	const jsonData = { 'filename': oodleFilename, 'content': document.getElementById('content').value, "id": parseInt(editingId, 10) };
+	const jsonData = {
+		'filename': oodleFilename,
+		'content': document.getElementById('content').value,
+		"id": parseInt(editingId, 10),
+		'attachments': attachmentTextData(),
+		'tags': messageTags.value.split(/[\s,]+/).filter((tag) => tag.length > 0)
+	};
 
 	fetch(editAction, {
 		method: 'POST',
 		headers: {
-			'Content-Type': "application/json"
+			'Content-Type': "application/json",
+			'X-CSRF-Token': csrfToken
 		},
 		body: JSON.stringify(jsonData)
 	})
@@ -161,4 +248,56 @@ function editMessage(event) {
 
 			clearEdit();
 		})
+}
+
+function deleteMessage(event) {
+	event.stopPropagation();
+	event.preventDefault();
+
+	if (!confirm("Delete this message?")) {
+		return;
+	}
+
+	const jsonData = { 'filename': oodleFilename, 'id': parseInt(editingId, 10) };
+
+	fetch(deleteAction, {
+		method: 'POST',
+		headers: {
+			'Content-Type': "application/json",
+			'X-CSRF-Token': csrfToken
+		},
+		body: JSON.stringify(jsonData)
+	})
+		.then((response) => {
+			if (!response.ok) {
+				return;
+			}
+
+			let deleted = ghostedElement;
+			clearEdit();
+			main.removeChild(deleted);
+		})
+}
+
+/// Upload through fetch so the CSRF token goes in a header, not the URL
+function attachFiles(event) {
+	event.stopPropagation();
+	event.preventDefault();
+
+	fetch(attachForm.action, {
+		method: 'POST',
+		headers: {
+			'X-CSRF-Token': csrfToken
+		},
+		body: new FormData(attachForm)
+	})
+		.then((response) => {
+			if (!response.ok) {
+				return;
+			}
+
+			// Show the message again, now with its attachments
+			window.location.hash = "message-" + editingId;
+			window.location.reload();
+		})
 }
\ No newline at end of file